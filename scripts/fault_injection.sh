@@ -0,0 +1,57 @@
+#!/usr/bin/env bash
+# kills the simulator part way through writing a batch at each fault point, runs it again so startup
+# recovery repairs the directory, and checks the partition holds every committed row exactly once.
+#
+# usage: scripts/fault_injection.sh [path to sensor_simulator binary]
+set -uo pipefail
+
+BIN="${1:-target/debug/sensor_simulator}"
+BATCH=2
+failures=0
+
+check() {
+    local fault="$1" expected_rows="$2"
+    local dir
+    dir="$(mktemp -d)"
+
+    # two batches: the first is committed normally, the fault fires while writing the second
+    ("$BIN" -i 1 -n $((BATCH * 2)) -f "$dir" --append-batch-size "$BATCH" --inject-fault "$fault" \
+        temperature -u celsius) >/dev/null 2>&1
+    if [ $? -eq 0 ]; then
+        echo "FAIL $fault: the process was expected to be killed"
+        failures=$((failures + 1))
+        return
+    fi
+
+    # found before recovering, as the recovery run writes a partition of its own
+    local partition
+    partition="$(ls "$dir"/*_output_0.csv)"
+
+    # any run pointed at the directory recovers it on startup
+    "$BIN" -i 1 -n 1 -f "$dir" temperature -u celsius >/dev/null 2>&1
+
+    local rows headers duplicates torn leftovers
+    rows=$(grep -vc '^id,' "$partition")
+    headers=$(grep -c '^id,' "$partition")
+    duplicates=$(grep -v '^id,' "$partition" | sort | uniq -d | wc -l)
+    torn=$(grep -v '^id,' "$partition" | awk -F, 'NF != 5' | wc -l)
+    leftovers=$(ls "$dir" | grep -c -e '\.journal' -e '\.tmp$')
+
+    if [ "$rows" -eq "$expected_rows" ] && [ "$headers" -eq 1 ] && [ "$duplicates" -eq 0 ] &&
+        [ "$torn" -eq 0 ] && [ "$leftovers" -eq 0 ]; then
+        echo "ok   $fault: $rows rows, no duplicates, no torn rows"
+    else
+        echo "FAIL $fault: rows=$rows (expected $expected_rows) headers=$headers duplicates=$duplicates torn=$torn leftover journal files=$leftovers"
+        failures=$((failures + 1))
+    fi
+
+    rm -rf "$dir"
+}
+
+# a crash before the journal is committed loses only the batch that was never acknowledged
+check mid-journal "$BATCH"
+# once the journal is committed the batch must survive, without being written twice
+check mid-append $((BATCH * 2))
+check before-cleanup $((BATCH * 2))
+
+exit $failures
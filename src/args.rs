@@ -1,3 +1,6 @@
+use crate::compression::validate_level;
+use crate::template::Template;
+use crate::timestamp::{TimestampFormat, Timezone};
 use clap::{Parser, Subcommand, ValueEnum};
 use serde::Serialize;
 
@@ -5,15 +8,54 @@ use serde::Serialize;
 #[derive(Parser, Debug, Clone, Serialize)]
 #[command(version, about, long_about = None)]
 pub struct Args {
-    /// type of sensor - dictates the type of data generated
+    /// type of sensor - dictates the type of data generated. or `serve` to run several behind an http server
     #[clap(subcommand)]
-    pub sensor_type: Sensor,
+    pub command: Command,
 
     #[clap(flatten)]
     pub timing_args: TimingArgs,
 
     #[clap(flatten)]
     pub output_args: OutputArgs,
+
+    #[clap(flatten, next_help_heading = "MQTT")]
+    pub mqtt_args: MqttArgs,
+
+    #[clap(flatten, next_help_heading = "Webhook")]
+    pub webhook_args: WebhookArgs,
+
+    #[clap(flatten, next_help_heading = "InfluxDB")]
+    pub influx_args: InfluxArgs,
+
+    #[clap(flatten, next_help_heading = "Sockets")]
+    pub socket_args: SocketArgs,
+
+    #[clap(flatten, next_help_heading = "Serial port emulation")]
+    pub pty_args: PtyArgs,
+
+    #[clap(flatten, next_help_heading = "Modbus")]
+    pub modbus_args: ModbusArgs,
+
+    #[clap(flatten, next_help_heading = "CoAP")]
+    pub coap_args: CoapArgs,
+
+    #[clap(flatten, next_help_heading = "Kafka")]
+    pub kafka_args: KafkaArgs,
+
+    #[clap(flatten, next_help_heading = "PostgreSQL")]
+    pub postgres_args: PostgresArgs,
+
+    #[clap(flatten, next_help_heading = "DuckDB")]
+    pub duckdb_args: DuckDbArgs,
+
+    #[clap(flatten, next_help_heading = "Arrow")]
+    pub arrow_args: ArrowArgs,
+
+    #[clap(flatten, next_help_heading = "Syslog")]
+    pub syslog_args: SyslogArgs,
+
+    #[clap(flatten, next_help_heading = "Named pipe")]
+    pub fifo_args: FifoArgs,
 }
 
 #[derive(Parser, Debug, Clone, Serialize)]
@@ -27,15 +69,452 @@ pub struct OutputArgs {
     )]
     pub to_file: String,
 
-    /// which file format to use. defaults to CSV
+    /// which file format to use. defaults to CSV. every reading of the run is written to `output.<format>`,
+    /// alongside the csv partitions the run is resumed from
     #[arg(short = 'o', long, default_value("csv"))]
     pub format: FileFormat,
 
+    /// how timestamps are written in the json, csv, msgpack and cbor output files. the partitions stay plain so
+    /// they can be resumed from
+    #[arg(long, default_value("plain"))]
+    pub file_timestamp: TimestampFormat,
+
+    /// how readings are printed to the console. everything else the simulator has to say goes to stderr, so
+    /// stdout can be piped straight into another tool
+    #[arg(long, default_value("human"), ignore_case = true)]
+    pub stdout_format: StdoutFormat,
+
+    /// the line printed for each reading with `--stdout-format template`, e.g.
+    /// `{timestamp_rfc3339} {id} {value:.3}{symbol}`
+    #[arg(long)]
+    pub stdout_template: Option<String>,
+
+    /// how timestamps are printed on the console. defaults to the time of day for human and plain for everything
+    /// else. line protocol is always in nanoseconds
+    #[arg(long)]
+    pub stdout_timestamp: Option<TimestampFormat>,
+
+    /// timezone the console shows timestamps in, as an iana name like `Europe/London` or an offset like `+05:30`.
+    /// files and every other sink stay in utc
+    #[arg(long, default_value("utc"), value_parser = Timezone::parse, allow_hyphen_values = true)]
+    pub timezone: Timezone,
+
+    /// only print readings and errors - no start up summary, progress notes or "process complete"
+    #[arg(short = 'q', long)]
+    pub quiet: bool,
+
+    /// compress files written to disk. partitions are compressed once they are closed, so the live file stays appendable
+    #[arg(short = 'c', long, default_value("none"), ignore_case = true)]
+    pub compress: Compression,
+
+    /// compression level. defaults to 6 for gzip (0-9) and 3 for zstd (1-22)
+    #[arg(long)]
+    pub compress_level: Option<i32>,
+
+    /// most readings held in memory at once. the buffer never grows past this, however long the run
+    #[arg(long, default_value_t = 250)]
+    pub buffer_capacity: usize,
+
+    /// what to do when the in-memory buffer is full: `flush` it to file, `drop-oldest` readings, or `block`
+    /// the sensor until the file write succeeds. defaults to flush when writing to file, drop-oldest otherwise
+    #[arg(long, ignore_case = true)]
+    pub overflow_policy: Option<OverflowPolicy>,
+
+    /// number of readings collected before they are appended to the current partition file
+    #[arg(long, default_value_t = 250, hide = true)]
+    pub append_batch_size: usize,
+
+    /// deliberately kill the process part way through writing the second batch, to check crash recovery
+    #[arg(long, hide = true)]
+    pub inject_fault: Option<FaultPoint>,
+
+    /// carry on from where the sensor with this id stopped, appending to its partitions in the output directory
+    #[arg(long)]
+    pub resume: Option<String>,
+
+    /// replace any files an earlier sensor with the same id left in the output directory
+    #[arg(long, conflicts_with = "resume")]
+    pub overwrite: bool,
+
+    /// save the sensor's full state to `<id>.checkpoint.json` this often, e.g. `90s`, `15m`, `6h`.
+    /// written to the output directory, or the working directory when not writing to file, and removed once
+    /// the run finishes cleanly
+    #[arg(long, value_parser = parse_duration)]
+    pub checkpoint_every: Option<std::time::Duration>,
+
+    /// carry on a run exactly where a checkpoint left it. the timing options are taken from the checkpoint
+    #[arg(long, conflicts_with_all = ["resume", "overwrite"])]
+    pub from_checkpoint: Option<String>,
+
     /// whether or not the output should be written to a database. Defaults to false.
     #[arg(short = 's', long, default_value("false"))]
     pub to_sql: BooleanArg,
 }
 
+#[derive(Parser, Debug, Clone, Serialize)]
+pub struct MqttArgs {
+    /// publish every reading to the MQTT broker on this host, e.g. `localhost`
+    #[arg(long)]
+    pub mqtt_host: Option<String>,
+
+    /// port the MQTT broker listens on
+    #[arg(long, default_value_t = 1883)]
+    pub mqtt_port: u16,
+
+    /// topic readings are published to. `{category}` and `{id}` are replaced with the sensor's own
+    #[arg(long, default_value("sensors/{category}/{id}"))]
+    pub mqtt_topic: String,
+
+    /// how each reading is encoded in the message body
+    #[arg(long, default_value("json"), ignore_case = true)]
+    pub mqtt_payload: PayloadFormat,
+
+    /// how timestamps are written in json, csv, msgpack and cbor payloads
+    #[arg(long, default_value("plain"))]
+    pub mqtt_timestamp: TimestampFormat,
+
+    /// MQTT quality of service level: 0, 1 or 2
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=2))]
+    pub mqtt_qos: u8,
+
+    /// ask the broker to keep the latest reading for subscribers that connect later
+    #[arg(long)]
+    pub mqtt_retain: bool,
+
+    /// MQTT protocol version to connect with
+    #[arg(long, default_value("3.1.1"))]
+    pub mqtt_version: MqttVersion,
+
+    /// client id to connect with. defaults to `sensor_simulator_<sensor id>`. under `serve` each sensor's id has
+    /// its type and position added, e.g. `rig_temperature_0`
+    #[arg(long)]
+    pub mqtt_client_id: Option<String>,
+
+    /// number of messages held while the broker is unreachable before readings start being dropped
+    #[arg(long, default_value_t = 1000)]
+    pub mqtt_buffer: usize,
+}
+
+#[derive(Parser, Debug, Clone, Serialize)]
+pub struct WebhookArgs {
+    /// POST readings as json to this url, e.g. `http://localhost:8000/ingest`
+    #[arg(long)]
+    pub webhook_url: Option<String>,
+
+    /// extra header sent with every request, as `Name: value`. can be given more than once
+    #[arg(long)]
+    pub webhook_header: Vec<String>,
+
+    /// name of an environment variable holding a token, sent as `Authorization: Bearer <token>`
+    #[arg(long)]
+    pub webhook_token_env: Option<String>,
+
+    /// number of readings sent in each request. 1 sends each reading as a single json object, more sends an array
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    pub webhook_batch_size: u16,
+
+    /// `json` objects like output.json's, or a `senml` json pack per request
+    #[arg(long, default_value("json"), ignore_case = true)]
+    pub webhook_format: WebhookFormat,
+
+    /// how long to wait for each request before giving up on it, e.g. `10s`
+    #[arg(long, default_value("10s"), value_parser = parse_duration)]
+    pub webhook_timeout: std::time::Duration,
+
+    /// how many times a failed request is retried before the batch is dead-lettered
+    #[arg(long, default_value_t = 5)]
+    pub webhook_retries: u32,
+
+    /// wait before the first retry. doubles after each failed attempt, up to a minute
+    #[arg(long, default_value("1s"), value_parser = parse_duration)]
+    pub webhook_backoff: std::time::Duration,
+
+    /// file that batches are appended to, one json line each, when every retry has failed
+    #[arg(long)]
+    pub webhook_dead_letter: Option<String>,
+
+    /// how timestamps are written in the request bodies
+    #[arg(long, default_value("plain"))]
+    pub webhook_timestamp: TimestampFormat,
+}
+
+#[derive(Parser, Debug, Clone, Serialize)]
+pub struct InfluxArgs {
+    /// write readings to the influxdb at this url, e.g. `http://localhost:8086`
+    #[arg(long)]
+    pub influx_url: Option<String>,
+
+    /// organisation that owns the bucket
+    #[arg(long, requires = "influx_url")]
+    pub influx_org: Option<String>,
+
+    /// bucket readings are written to
+    #[arg(long, requires = "influx_url")]
+    pub influx_bucket: Option<String>,
+
+    /// name of an environment variable holding an api token with write access to the bucket
+    #[arg(long, default_value("INFLUX_TOKEN"))]
+    pub influx_token_env: String,
+
+    /// number of readings written in each request
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    pub influx_batch_size: u16,
+}
+
+#[derive(Parser, Debug, Clone, Serialize)]
+pub struct SocketArgs {
+    /// connect to the tcp server at this address, e.g. `localhost:9000`, and stream readings to it one per line
+    #[arg(long)]
+    pub tcp_connect: Option<String>,
+
+    /// listen on this address, e.g. `0.0.0.0:9000`, and stream readings to every client that connects
+    #[arg(long)]
+    pub tcp_listen: Option<String>,
+
+    /// send each reading as a udp datagram to this address, e.g. `localhost:9000`
+    #[arg(long)]
+    pub udp: Option<String>,
+
+    /// connect to the unix domain socket at this path and stream readings to it one per line
+    #[arg(long)]
+    pub unix_connect: Option<String>,
+
+    /// create a unix domain socket at this path and stream readings to every client that connects
+    #[arg(long)]
+    pub unix_listen: Option<String>,
+
+    /// how each reading is encoded on the socket sinks
+    #[arg(long, default_value("json"), ignore_case = true)]
+    pub socket_format: PayloadFormat,
+
+    /// how timestamps are written in json, csv, msgpack and cbor readings
+    #[arg(long, default_value("plain"))]
+    pub socket_timestamp: TimestampFormat,
+
+    /// readings held while a connection is down, sent once it is back
+    #[arg(long, default_value_t = 1000)]
+    pub socket_buffer: usize,
+}
+
+#[derive(Parser, Debug, Clone, Serialize)]
+pub struct PtyArgs {
+    /// create a pseudo-terminal and write readings to it the way a sensor on a serial port would
+    #[arg(long)]
+    pub pty: bool,
+
+    /// what each line written to the pty looks like
+    #[arg(long, default_value("nmea"), ignore_case = true, requires = "pty")]
+    pub pty_format: PtyFormat,
+
+    /// line written for each reading with `--pty-format template`, e.g. `T={value:.1}{symbol}`. fields are
+    /// {id}, {value}, {symbol}, {unit}, {category} and {timestamp}. every line ends in \r\n
+    #[arg(long, requires = "pty")]
+    pub pty_template: Option<String>,
+
+    /// how {timestamp} is written in --pty-template lines. always in utc, like a device's clock
+    #[arg(long, default_value("plain"), requires = "pty")]
+    pub pty_timestamp: TimestampFormat,
+
+    /// also create a symlink to the pty at this path, e.g. `/tmp/ttySENSOR`, so it can be found by a fixed name.
+    /// under `serve` each sensor's link has its type and position added, e.g. `/tmp/ttySENSOR_temperature_0`
+    #[arg(long, requires = "pty")]
+    pub pty_link: Option<String>,
+}
+
+#[derive(Parser, Debug, Clone, Serialize)]
+pub struct ModbusArgs {
+    /// run a modbus tcp server on this address, e.g. `0.0.0.0:5020`, with each sensor's latest reading in its
+    /// registers. each sensor gets 4 registers, from 0 in the order the sensors are set up: the value in the
+    /// first two and a count of readings in the last two
+    #[arg(long)]
+    pub modbus_listen: Option<String>,
+
+    /// how the value is stored in its two registers
+    #[arg(
+        long,
+        default_value("float"),
+        ignore_case = true,
+        requires = "modbus_listen"
+    )]
+    pub modbus_encoding: RegisterEncoding,
+
+    /// with `--modbus-encoding scaled`, the value is multiplied by this and stored as a signed 32 bit integer
+    #[arg(long, default_value_t = 100.0, requires = "modbus_listen")]
+    pub modbus_scale: f32,
+
+    /// which of the two registers holds the high half of a 32 bit value
+    #[arg(
+        long,
+        default_value("big"),
+        ignore_case = true,
+        requires = "modbus_listen"
+    )]
+    pub modbus_word_order: WordOrder,
+}
+
+#[derive(Parser, Debug, Clone, Serialize)]
+pub struct CoapArgs {
+    /// run a coap server on this address, e.g. `0.0.0.0:5683`, where each sensor is an observable resource at
+    /// `/sensors/{id}`
+    #[arg(long)]
+    pub coap_listen: Option<String>,
+
+    /// payload format for clients that don't ask for one with the accept option
+    #[arg(
+        long,
+        default_value("json"),
+        ignore_case = true,
+        requires = "coap_listen"
+    )]
+    pub coap_format: CoapFormat,
+
+    /// how timestamps are written in the resources
+    #[arg(long, default_value("plain"), requires = "coap_listen")]
+    pub coap_timestamp: TimestampFormat,
+}
+
+#[derive(Parser, Debug, Clone, Serialize)]
+pub struct KafkaArgs {
+    /// produce readings to the kafka cluster reachable through these brokers, e.g. `localhost:9092`. several can
+    /// be given separated by commas
+    #[arg(long, value_delimiter = ',')]
+    pub kafka_brokers: Vec<String>,
+
+    /// topic readings are produced to. `{category}` and `{id}` are replaced with the sensor's own
+    #[arg(long, default_value("sensors"), requires = "kafka_brokers")]
+    pub kafka_topic: String,
+
+    /// key of each record, filled in like --kafka-topic. the default keeps each sensor's readings on one
+    /// partition, and an empty key spreads them across all of them instead
+    #[arg(long, default_value("{id}"), requires = "kafka_brokers")]
+    pub kafka_key: String,
+
+    /// how each reading is encoded in the record value
+    #[arg(
+        long,
+        default_value("json"),
+        ignore_case = true,
+        requires = "kafka_brokers"
+    )]
+    pub kafka_format: PayloadFormat,
+
+    /// how timestamps are written in json, csv, msgpack and cbor records
+    #[arg(long, default_value("plain"), requires = "kafka_brokers")]
+    pub kafka_timestamp: TimestampFormat,
+
+    /// most records sent to the broker in one request
+    #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u16).range(1..), requires = "kafka_brokers")]
+    pub kafka_batch_size: u16,
+
+    /// how long to wait for more readings to fill a batch before sending it, in milliseconds
+    #[arg(long, default_value_t = 100, requires = "kafka_brokers")]
+    pub kafka_linger_ms: u64,
+
+    /// which brokers must have a batch before it counts as delivered
+    #[arg(
+        long,
+        default_value("all"),
+        ignore_case = true,
+        requires = "kafka_brokers"
+    )]
+    pub kafka_acks: KafkaAcks,
+
+    /// any other librdkafka producer setting, as `name=value`, e.g. `compression.type=lz4`. can be given more
+    /// than once
+    #[arg(long, requires = "kafka_brokers")]
+    pub kafka_config: Vec<String>,
+}
+
+#[derive(Parser, Debug, Clone, Serialize)]
+pub struct PostgresArgs {
+    /// write readings to the postgres database at this url, e.g. `postgres://user@localhost/sensors`
+    #[arg(long, conflicts_with = "postgres_url_env")]
+    pub postgres_url: Option<String>,
+
+    /// name of an environment variable holding the url instead, e.g. `DATABASE_URL`, so a password in it doesn't
+    /// end up in shell history or process listings
+    #[arg(long)]
+    pub postgres_url_env: Option<String>,
+
+    /// make the readings table a timescaledb hypertable on its timestamp. the extension has to be installed
+    #[arg(long)]
+    pub postgres_hypertable: bool,
+
+    /// number of readings written to postgres in each COPY
+    #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u16).range(1..))]
+    pub postgres_batch_size: u16,
+}
+
+#[derive(Parser, Debug, Clone, Serialize)]
+pub struct DuckDbArgs {
+    /// write readings to this duckdb database file, e.g. `readings.duckdb`. it is created if it doesn't exist
+    #[arg(long)]
+    pub duckdb: Option<String>,
+
+    /// when the run finishes, have duckdb export every reading in the database to this parquet file
+    #[arg(long, requires = "duckdb")]
+    pub duckdb_parquet: Option<String>,
+}
+
+#[derive(Parser, Debug, Clone, Serialize)]
+pub struct ArrowArgs {
+    /// write readings as apache arrow ipc to this file, or `-` for stdout (which then only carries the arrow data)
+    #[arg(long)]
+    pub arrow: Option<String>,
+
+    /// `file` (feather v2) can only be read once the run finishes, `stream` can be read while it is still going.
+    /// defaults to stream on stdout and file otherwise
+    #[arg(long, ignore_case = true, requires = "arrow")]
+    pub arrow_format: Option<ArrowFormat>,
+
+    /// readings in each record batch. a batch is written as soon as it is full, and whatever is left when the
+    /// run finishes
+    #[arg(long, default_value_t = 250, requires = "arrow")]
+    pub arrow_batch_size: usize,
+}
+
+#[derive(Parser, Debug, Clone, Serialize)]
+pub struct SyslogArgs {
+    /// log each reading as an rfc 5424 syslog message to this udp address, e.g. `localhost:514`
+    #[arg(long, conflicts_with = "syslog_socket")]
+    pub syslog_udp: Option<String>,
+
+    /// log each reading as an rfc 5424 syslog message to this local datagram socket, e.g. `/dev/log`
+    #[arg(long)]
+    pub syslog_socket: Option<String>,
+
+    /// facility the messages are logged under
+    #[arg(long, default_value("local0"), ignore_case = true)]
+    pub syslog_facility: SyslogFacility,
+
+    /// app name in each message, for filtering on in the log pipeline
+    #[arg(long, default_value("sensor_simulator"))]
+    pub syslog_app_name: String,
+}
+
+#[derive(Parser, Debug, Clone, Serialize)]
+pub struct FifoArgs {
+    /// write readings into the named pipe at this path, one per line. it is made if it doesn't exist
+    #[arg(long)]
+    pub fifo: Option<String>,
+
+    /// how each reading is written to the fifo
+    #[arg(long, default_value("json"), ignore_case = true, requires = "fifo")]
+    pub fifo_format: PayloadFormat,
+
+    /// how timestamps are written in json, csv, msgpack and cbor readings
+    #[arg(long, default_value("plain"), requires = "fifo")]
+    pub fifo_timestamp: TimestampFormat,
+
+    /// what to do while nothing is reading from the fifo
+    #[arg(long, default_value("buffer"), ignore_case = true, requires = "fifo")]
+    pub fifo_mode: FifoMode,
+
+    /// readings kept for a reader with `--fifo-mode buffer`. past this the oldest are dropped
+    #[arg(long, default_value_t = 1000, requires = "fifo")]
+    pub fifo_buffer: usize,
+}
+
 #[derive(Parser, Debug, Clone, Copy, Serialize)]
 pub struct TimingArgs {
     /// interval at which data is generated in seconds
@@ -56,7 +535,7 @@ impl TimingArgs {
     // the tird one should be inferred from the two provided
     // it should be allowed to provide only one, and a sensible default should be set for the others.
     fn validate(&mut self) -> Result<(), &str> {
-        let provided_args = vec![self.interval, self.duration, self.number];
+        let provided_args = [self.interval, self.duration, self.number];
         let provided_args: Vec<usize> = provided_args
             .iter()
             .enumerate()
@@ -64,34 +543,49 @@ impl TimingArgs {
             .map(|(i, _arg)| i)
             .collect();
 
+        // every inference below divides by the number of readings
+        if self.number == Some(0) {
+            return Err("The number of readings must be at least 1.");
+        }
+
         match provided_args.as_slice() {
-            [] => {
-                return Err(
-                    "Did not provide any arguments to control the timing of data generated. Must provide at least one of: interval, duration, number.",
-                );
-            }
+            [] => Err(
+                "Did not provide any arguments to control the timing of data generated. Must provide at least one of: interval, duration, number.",
+            ),
             [0] => {
                 self.duration = Some(300); // set a sensible default - 5mins
-                return Ok(());
+                Ok(())
             }
             [1] => {
                 self.interval = Some(60); // set a sensible default - 1min 
-                return Ok(());
+                Ok(())
             }
             [2] => {
                 self.duration = Some(300); // set a sensible default - 5mins
-                return Ok(());
+                self.interval = Some((300 / self.number.unwrap()).max(1));
+                Ok(())
             }
-            [0, 1] => return Ok(()),
-            [1, 2] => return Ok(()),
-            [0, 2] => return Ok(()),
+            [0, 1] => Ok(()),
+            [1, 2] => {
+                self.interval = Some((self.duration.unwrap() / self.number.unwrap()).max(1));
+                Ok(())
+            }
+            [0, 2] => match self.interval.unwrap().checked_mul(self.number.unwrap()) {
+                Some(duration) => {
+                    self.duration = Some(duration);
+                    Ok(())
+                }
+                None => Err(
+                    "The provided interval and number are not compatible together. They would run for longer than the maximum duration of 65535 seconds.",
+                ),
+            },
             [0, 1, 2] => {
-                if self.number.unwrap() * self.interval.unwrap() == self.duration.unwrap() {
-                    return Ok(());
+                if self.number.unwrap().checked_mul(self.interval.unwrap()) == self.duration {
+                    Ok(())
                 } else {
-                    return Err(
+                    Err(
                         "The provided timing arguments are not compatible together. It is recommended to only provide options out of interval, duration, and number. The third value will be fixed by the first two ",
-                    );
+                    )
                 }
             }
             _ => panic!(),
@@ -99,6 +593,15 @@ impl TimingArgs {
     }
 }
 
+/// what to run: one sensor in the foreground, or several served over http
+#[derive(Debug, Subcommand, Clone, Serialize)]
+pub enum Command {
+    #[command(flatten)]
+    Sensor(Sensor),
+    /// Run sensors in the background and serve their readings over http
+    Serve(ServeArgs),
+}
+
 #[derive(Debug, Subcommand, Clone, Copy, Serialize)]
 pub enum Sensor {
     /// Simulate a temperature sensor
@@ -121,6 +624,46 @@ pub enum Sensor {
     },
 }
 
+impl Sensor {
+    /// the first three characters of the id of every sensor of this type
+    pub fn id_prefix(&self) -> &'static str {
+        match self {
+            Sensor::Temperature { .. } => "TMP",
+            Sensor::Pressure { .. } => "PRS",
+            Sensor::Humidity { .. } => "HMD",
+        }
+    }
+}
+
+#[derive(Parser, Debug, Clone, Serialize)]
+pub struct ServeArgs {
+    /// port the http server listens on
+    #[arg(long, default_value_t = 8080)]
+    pub port: u16,
+
+    /// address the http server listens on. use 0.0.0.0 to allow connections from other machines
+    #[arg(long, default_value("127.0.0.1"))]
+    pub bind: String,
+
+    /// sensor to run, as `<type>:<unit>` e.g. `pressure:pascal`. can be given more than once.
+    /// defaults to one sensor of each type
+    #[arg(
+        long = "sensor",
+        value_parser = parse_sensor,
+        default_values = ["temperature:celsius", "pressure:bar", "humidity:relative"]
+    )]
+    pub sensors: Vec<Sensor>,
+
+    /// number of recent readings each sensor keeps for `/sensors/{id}/readings`
+    #[arg(long, default_value_t = 1000)]
+    pub history: usize,
+
+    /// number of recent readings a websocket client is sent when it connects, before the live ones.
+    /// a client can ask for a different number with `/ws?last=N`
+    #[arg(long, default_value_t = 20)]
+    pub websocket_backlog: usize,
+}
+
 #[derive(Debug, Clone, ValueEnum, Copy, Serialize)]
 pub enum TemperatureUnit {
     Celsius,
@@ -141,8 +684,181 @@ pub enum HumidityUnit {
 
 #[derive(Debug, Clone, ValueEnum, Copy, Serialize)]
 pub enum FileFormat {
-    CSV,
+    Csv,
+    Json,
+    /// influxdb line protocol, written to `output.lp`
+    Influx,
+    /// messagepack maps one after another, written to `output.msgpack`
+    Msgpack,
+    /// cbor maps one after another, written to `output.cbor`
+    Cbor,
+    /// length prefixed protobuf messages, written to `output.pb`
+    Protobuf,
+    /// a single senml json pack, written to `output.senml.json`
+    #[value(name = "senml-json")]
+    SenmlJson,
+    /// a single senml cbor pack, written to `output.senml.cbor`
+    #[value(name = "senml-cbor")]
+    SenmlCbor,
+}
+
+#[derive(Debug, Clone, ValueEnum, Copy, Serialize)]
+pub enum StdoutFormat {
+    /// `[HH:MM:SS] Sensor ID: 12.34°C`
+    Human,
+    /// csv rows the same as output.csv's, after a single header line
+    Csv,
+    /// one json object per line
+    #[value(name = "ndjson", alias = "json")]
+    Json,
+    /// influxdb line protocol, e.g. to pipe into `influx write`
+    Influx,
+    /// the line given by --stdout-template
+    Template,
+}
+
+#[derive(Debug, Clone, ValueEnum, Copy, Serialize)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+#[derive(Debug, Clone, ValueEnum, Copy, Serialize)]
+pub enum WebhookFormat {
+    Json,
+    /// a senml (rfc 8428) pack, sent as `application/senml+json`
+    Senml,
+}
+
+#[derive(Debug, Clone, ValueEnum, Copy, Serialize)]
+pub enum ArrowFormat {
+    /// the arrow ipc file format, also known as feather v2
+    File,
+    /// the arrow ipc streaming format
+    Stream,
+}
+
+#[derive(Debug, Clone, ValueEnum, Copy, Serialize)]
+pub enum PayloadFormat {
+    /// the reading as a json object, the same as an element of output.json
+    Json,
+    /// the reading as a csv row without a header, the same as a row of output.csv
+    Csv,
+    /// just the value
+    Raw,
+    /// a line of influxdb line protocol
+    Influx,
+    /// the json fields as a messagepack map
+    Msgpack,
+    /// the json fields as a cbor map
+    Cbor,
+    /// the `SensorOutput` message in proto/sensor_output.proto. length prefixed on byte streams
+    Protobuf,
+    /// a senml (rfc 8428) json pack of the one reading
+    #[value(name = "senml-json")]
+    SenmlJson,
+    /// a senml (rfc 8428) cbor pack of the one reading
+    #[value(name = "senml-cbor")]
+    SenmlCbor,
+}
+
+#[derive(Debug, Clone, ValueEnum, Copy, Serialize)]
+pub enum PtyFormat {
+    /// nmea 0183 XDR sentences, e.g. `$WIXDR,C,23.41,C,TMP1ab*1C`
+    Nmea,
+    /// `T=23.4C`, `P=1.013bar` or `H=45.2%`, like a simple sensor board prints
+    #[value(name = "kv")]
+    KeyValue,
+    /// the line given by --pty-template
+    Template,
+}
+
+#[derive(Debug, Clone, ValueEnum, Copy, Serialize)]
+pub enum RegisterEncoding {
+    /// an ieee 754 single precision float
+    Float,
+    /// the value times --modbus-scale, as a signed integer
+    Scaled,
+}
+
+#[derive(Debug, Clone, ValueEnum, Copy, Serialize)]
+pub enum WordOrder {
+    /// high word first, sometimes called ABCD
+    Big,
+    /// low word first, sometimes called CDAB
+    Little,
+}
+
+#[derive(Debug, Clone, ValueEnum, Copy, Serialize)]
+pub enum CoapFormat {
+    /// application/json, content format 50
     Json,
+    /// application/cbor, content format 60
+    Cbor,
+}
+
+#[derive(Debug, Clone, ValueEnum, Copy, Serialize)]
+pub enum KafkaAcks {
+    /// don't wait for an acknowledgement, so failed deliveries go unnoticed
+    None,
+    /// the partition leader has written it
+    Leader,
+    /// every in-sync replica has it
+    All,
+}
+
+/// the facilities a program can sensibly log under, numbered as in rfc 5424
+#[derive(Debug, Clone, ValueEnum, Copy, Serialize)]
+pub enum SyslogFacility {
+    User = 1,
+    Daemon = 3,
+    Local0 = 16,
+    Local1 = 17,
+    Local2 = 18,
+    Local3 = 19,
+    Local4 = 20,
+    Local5 = 21,
+    Local6 = 22,
+    Local7 = 23,
+}
+
+#[derive(Debug, Clone, ValueEnum, Copy, Serialize)]
+pub enum FifoMode {
+    /// wait for a reader, and for it to keep up, holding up the sensor meanwhile
+    Block,
+    /// throw readings away while there is no reader, or it isn't keeping up
+    Drop,
+    /// keep readings until a reader turns up, up to --fifo-buffer of them
+    Buffer,
+}
+
+#[derive(Debug, Clone, ValueEnum, Copy, Serialize)]
+pub enum MqttVersion {
+    #[value(name = "3.1.1", alias = "3")]
+    V3,
+    #[value(name = "5", alias = "5.0")]
+    V5,
+}
+
+#[derive(Debug, Clone, ValueEnum, Copy, Serialize, PartialEq)]
+pub enum OverflowPolicy {
+    /// forget the oldest reading to make room for the new one
+    DropOldest,
+    /// write the buffered readings out to the file sink to make room
+    Flush,
+    /// keep retrying the file sink, holding up the sensor, until there is room. never loses a reading
+    Block,
+}
+
+#[derive(Debug, Clone, ValueEnum, Copy, Serialize, PartialEq)]
+pub enum FaultPoint {
+    /// while the journal is being written, before the batch is committed
+    MidJournal,
+    /// after the journal is committed, half way through appending to the partition
+    MidAppend,
+    /// after the partition is written, before the journal is removed
+    BeforeCleanup,
 }
 
 #[derive(Debug, Clone, ValueEnum, Copy, Serialize)]
@@ -151,10 +867,271 @@ pub enum BooleanArg {
     False,
 }
 
+/// parses sensors given as `<type>:<unit>`, e.g. `temperature:kelvin`. the unit can be left off for the default
+fn parse_sensor(s: &str) -> Result<Sensor, String> {
+    let (category, unit) = match s.split_once(':') {
+        Some((category, unit)) => (category, Some(unit)),
+        None => (s, None),
+    };
+
+    match category.to_lowercase().as_str() {
+        "temperature" => Ok(Sensor::Temperature {
+            unit: TemperatureUnit::from_str(unit.unwrap_or("celsius"), true)?,
+        }),
+        "pressure" => Ok(Sensor::Pressure {
+            unit: PressureUnit::from_str(unit.unwrap_or("bar"), true)?,
+        }),
+        "humidity" => Ok(Sensor::Humidity {
+            unit: HumidityUnit::from_str(unit.unwrap_or("relative"), true)?,
+        }),
+        _ => Err(format!(
+            "`{}` is not a sensor type - use temperature, pressure or humidity",
+            category
+        )),
+    }
+}
+
+/// parses durations like `45`, `45s`, `10m`, `6h` or `2d`. a bare number is in seconds
+fn parse_duration(s: &str) -> Result<std::time::Duration, String> {
+    let s = s.trim();
+    let (number, multiplier) = match s.char_indices().last() {
+        Some((i, 's')) => (&s[..i], 1),
+        Some((i, 'm')) => (&s[..i], 60),
+        Some((i, 'h')) => (&s[..i], 60 * 60),
+        Some((i, 'd')) => (&s[..i], 60 * 60 * 24),
+        _ => (s, 1),
+    };
+
+    let number: u64 = number.parse().map_err(|_| {
+        format!(
+            "`{}` is not a duration - use something like 90s, 15m or 6h",
+            s
+        )
+    })?;
+
+    if number == 0 {
+        return Err("duration must be greater than zero".to_string());
+    }
+
+    Ok(std::time::Duration::from_secs(number * multiplier))
+}
+
 pub fn parse_and_validate() -> Result<Args, String> {
     let mut args = Args::parse();
 
-    args.timing_args.validate().map_err(|e| e.to_string())?;
+    if let Command::Serve(serve_args) = &args.command {
+        if args.output_args.resume.is_some() || args.output_args.from_checkpoint.is_some() {
+            return Err(
+                "serve starts new sensors, so it can't be used with --resume or --from-checkpoint"
+                    .to_string(),
+            );
+        }
+        if serve_args.history == 0 {
+            return Err("--history must be at least 1".to_string());
+        }
+
+        // served sensors run until the server is stopped, unless told otherwise
+        if args.timing_args.duration.is_none() && args.timing_args.number.is_none() {
+            args.timing_args.interval.get_or_insert(1);
+        } else {
+            args.timing_args.validate().map_err(|e| e.to_string())?;
+        }
+    } else if args.output_args.from_checkpoint.is_none() {
+        // a checkpoint carries its own interval and remaining duration
+        args.timing_args.validate().map_err(|e| e.to_string())?;
+    }
+    validate_level(&args.output_args.compress, args.output_args.compress_level)
+        .map_err(|e| e.to_string())?;
+
+    if let StdoutFormat::Template = args.output_args.stdout_format {
+        match &args.output_args.stdout_template {
+            Some(template) => {
+                Template::parse(template).map_err(|e| format!("--stdout-template: {}", e))?;
+            }
+            None => return Err("--stdout-format template needs a --stdout-template".to_string()),
+        }
+    }
+
+    if let PtyFormat::Template = args.pty_args.pty_format {
+        match &args.pty_args.pty_template {
+            Some(template) => {
+                Template::parse(template).map_err(|e| format!("--pty-template: {}", e))?;
+            }
+            None => return Err("--pty-format template needs a --pty-template".to_string()),
+        }
+    }
+
+    if args.arrow_args.arrow_batch_size == 0 {
+        return Err("--arrow-batch-size must be at least 1".to_string());
+    }
+
+    if args.output_args.buffer_capacity == 0 {
+        return Err("--buffer-capacity must be at least 1".to_string());
+    }
+
+    if args.output_args.append_batch_size == 0 {
+        return Err("--append-batch-size must be at least 1".to_string());
+    }
+
+    // a batch is only written once the buffer holds a whole one, so a smaller buffer would never be flushed
+    if args.output_args.to_file != "false"
+        && args.output_args.buffer_capacity < args.output_args.append_batch_size
+    {
+        return Err(format!(
+            "--buffer-capacity ({}) must be at least --append-batch-size ({}) when writing to file",
+            args.output_args.buffer_capacity, args.output_args.append_batch_size
+        ));
+    }
+
+    if let Some(policy @ (OverflowPolicy::Flush | OverflowPolicy::Block)) =
+        args.output_args.overflow_policy
+        && args.output_args.to_file == "false"
+    {
+        return Err(format!(
+            "--overflow-policy {:?} needs somewhere to flush to - set --to-file, or use drop-oldest",
+            policy
+        ));
+    }
+
+    if let Some(id) = &args.output_args.resume {
+        if args.output_args.to_file == "false" {
+            return Err(
+                "--resume needs --to-file to point at the directory the sensor was writing to"
+                    .to_string(),
+            );
+        }
+        if let Command::Sensor(sensor) = &args.command
+            && !id.starts_with(sensor.id_prefix())
+        {
+            return Err(format!(
+                "cannot resume {} as a {:?} sensor - ids for this sensor type start with {}",
+                id,
+                sensor,
+                sensor.id_prefix()
+            ));
+        }
+    }
 
     Ok(args)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn timing(interval: Option<u16>, duration: Option<u16>, number: Option<u16>) -> TimingArgs {
+        TimingArgs {
+            interval,
+            duration,
+            number,
+        }
+    }
+
+    #[test]
+    fn the_missing_timing_option_is_inferred() {
+        let mut args = timing(Some(5), None, Some(10));
+        args.validate().unwrap();
+        assert_eq!(args.duration, Some(50));
+
+        let mut args = timing(None, Some(60), Some(4));
+        args.validate().unwrap();
+        assert_eq!(args.interval, Some(15));
+
+        let mut args = timing(None, None, Some(600));
+        args.validate().unwrap();
+        assert_eq!((args.interval, args.duration), (Some(1), Some(300)));
+    }
+
+    #[test]
+    fn zero_readings_are_rejected() {
+        for mut args in [
+            timing(None, None, Some(0)),
+            timing(None, Some(60), Some(0)),
+            timing(Some(5), None, Some(0)),
+            timing(Some(5), Some(0), Some(0)),
+        ] {
+            assert!(args.validate().is_err(), "{:?} was accepted", args);
+        }
+    }
+
+    #[test]
+    fn a_duration_too_long_to_hold_is_rejected() {
+        assert!(timing(Some(60), None, Some(2000)).validate().is_err());
+        assert!(
+            timing(Some(60), Some(54464), Some(2000))
+                .validate()
+                .is_err()
+        );
+        assert!(timing(Some(60), Some(120), Some(2)).validate().is_ok());
+    }
+
+    #[test]
+    fn parse_duration_reads_each_unit() {
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("15m"), Ok(Duration::from_secs(15 * 60)));
+        assert_eq!(parse_duration("6h"), Ok(Duration::from_secs(6 * 60 * 60)));
+        assert_eq!(
+            parse_duration("2d"),
+            Ok(Duration::from_secs(2 * 24 * 60 * 60))
+        );
+    }
+
+    #[test]
+    fn parse_duration_without_a_unit_is_seconds() {
+        assert_eq!(parse_duration("45"), Ok(Duration::from_secs(45)));
+        assert_eq!(parse_duration(" 45 "), Ok(Duration::from_secs(45)));
+    }
+
+    #[test]
+    fn parse_duration_rejects_zero_and_anything_else() {
+        for duration in ["0", "0s", "", "m", "1.5h", "-5m", "10x", "5 m"] {
+            assert!(
+                parse_duration(duration).is_err(),
+                "{} was accepted",
+                duration
+            );
+        }
+    }
+
+    #[test]
+    fn timestamp_options_take_named_and_custom_formats() {
+        let args = Args::try_parse_from([
+            "sensor_simulator",
+            "--mqtt-timestamp",
+            "epoch-ms",
+            "--webhook-timestamp",
+            "[hour]:[minute]",
+            "temperature",
+            "-u",
+            "celsius",
+        ])
+        .unwrap();
+
+        assert!(matches!(
+            args.mqtt_args.mqtt_timestamp,
+            TimestampFormat::EpochMillis
+        ));
+        // like the other sinks' options, this one doesn't need its sink to be set up
+        assert_eq!(
+            args.webhook_args.webhook_timestamp.to_string(),
+            "[hour]:[minute]"
+        );
+        assert!(matches!(
+            args.socket_args.socket_timestamp,
+            TimestampFormat::Plain
+        ));
+
+        assert!(
+            Args::try_parse_from([
+                "sensor_simulator",
+                "--fifo-timestamp",
+                "%+1",
+                "temperature",
+                "-u",
+                "celsius",
+            ])
+            .is_err()
+        );
+    }
+}
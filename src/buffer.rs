@@ -0,0 +1,151 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+
+/// a fixed size buffer of the most recent items. once it is full, pushing a new item pushes out the oldest,
+/// so the memory it uses never grows past `capacity` however long the sensor runs
+#[derive(Debug, Clone)]
+pub struct RingBuffer<T> {
+    items: VecDeque<T>,
+    capacity: usize,
+}
+
+impl<T> RingBuffer<T> {
+    pub fn with_capacity(capacity: usize) -> RingBuffer<T> {
+        RingBuffer {
+            items: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// adds an item, returning the oldest one if it had to be dropped to make room
+    pub fn push(&mut self, item: T) -> Option<T> {
+        let evicted = if self.is_full() {
+            self.items.pop_front()
+        } else {
+            None
+        };
+
+        self.items.push_back(item);
+
+        evicted
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.items.len() >= self.capacity
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn pop_front(&mut self) -> Option<T> {
+        self.items.pop_front()
+    }
+
+    pub fn last(&self) -> Option<&T> {
+        self.items.back()
+    }
+
+    pub fn iter(&self) -> std::collections::vec_deque::Iter<'_, T> {
+        self.items.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.items.clear();
+    }
+}
+
+impl<'a, T> IntoIterator for &'a RingBuffer<T> {
+    type Item = &'a T;
+    type IntoIter = std::collections::vec_deque::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.items.iter()
+    }
+}
+
+impl<T: Serialize> Serialize for RingBuffer<T> {
+    // serialized oldest first, the same as the Vec it replaced
+    fn serialize<S>(&self, serializer: S) -> core::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_seq(self.items.iter())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn items(buffer: &RingBuffer<u32>) -> Vec<u32> {
+        buffer.iter().copied().collect()
+    }
+
+    #[test]
+    fn holds_items_oldest_first_until_full() {
+        let mut buffer: RingBuffer<u32> = RingBuffer::with_capacity(3);
+
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.push(1), None);
+        assert_eq!(buffer.push(2), None);
+        assert!(!buffer.is_full());
+        assert_eq!(buffer.push(3), None);
+
+        assert!(buffer.is_full());
+        assert_eq!(items(&buffer), [1, 2, 3]);
+        assert_eq!(buffer.last(), Some(&3));
+    }
+
+    #[test]
+    fn pushing_when_full_evicts_and_returns_the_oldest() {
+        let mut buffer: RingBuffer<u32> = RingBuffer::with_capacity(3);
+        for item in 1..=3 {
+            buffer.push(item);
+        }
+
+        assert_eq!(buffer.push(4), Some(1));
+        assert_eq!(buffer.push(5), Some(2));
+
+        assert_eq!(buffer.len(), 3);
+        assert_eq!(items(&buffer), [3, 4, 5]);
+    }
+
+    #[test]
+    fn capacity_of_one_keeps_only_the_latest() {
+        let mut buffer: RingBuffer<u32> = RingBuffer::with_capacity(1);
+
+        assert_eq!(buffer.push(1), None);
+        assert_eq!(buffer.push(2), Some(1));
+        assert_eq!(items(&buffer), [2]);
+    }
+
+    #[test]
+    fn popping_and_clearing_make_room_again() {
+        let mut buffer: RingBuffer<u32> = RingBuffer::with_capacity(2);
+        buffer.push(1);
+        buffer.push(2);
+
+        assert_eq!(buffer.pop_front(), Some(1));
+        assert_eq!(buffer.push(3), None);
+        assert_eq!(items(&buffer), [2, 3]);
+
+        buffer.clear();
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.pop_front(), None);
+    }
+
+    #[test]
+    fn serializes_oldest_first() {
+        let mut buffer: RingBuffer<u32> = RingBuffer::with_capacity(2);
+        for item in 1..=3 {
+            buffer.push(item);
+        }
+
+        assert_eq!(serde_json::to_string(&buffer).unwrap(), "[2,3]");
+    }
+}
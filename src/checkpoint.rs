@@ -0,0 +1,233 @@
+use crate::compression::open_decompressed;
+use crate::resume::find_partitions;
+use rand_pcg::Pcg64;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// bump this whenever a field is added, removed or changes meaning. older checkpoints are refused rather
+/// than half restored, because a resume that isn't bit-exact is worse than no resume at all
+pub const CHECKPOINT_VERSION: u32 = 3;
+
+/// everything needed to carry on a run exactly where it stopped
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub version: u32,
+    pub sensor_id: String,
+    pub random_seed: u64,
+    pub rng: Pcg64,
+    pub base_value: f64,
+    pub last_value: Option<f32>,
+    pub last_timestamp: Option<String>,
+    pub readings_generated: u64,
+    pub interval: u16,
+    pub remaining_seconds: i64,
+    pub file_path: Option<String>,
+    pub current_file_partition: usize,
+    pub rows_in_current_file: usize,
+    /// size of the live partition when the checkpoint was taken - anything after this was written later
+    pub partition_len: u64,
+    /// size of `output.<format>` when the checkpoint was taken, for the same reason
+    pub output_len: u64,
+}
+
+/// the checkpoint file for a sensor, kept next to its partitions
+pub fn checkpoint_path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!("{}.checkpoint.json", id))
+}
+
+/// writes the checkpoint to a temp file and renames it over the old one, so there is always one complete checkpoint
+pub fn save(path: &Path, checkpoint: &Checkpoint) -> Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+
+    let mut file = File::create(&temp_path)?;
+    file.write_all(serde_json::to_string_pretty(checkpoint)?.as_bytes())?;
+    file.sync_all()?;
+
+    std::fs::rename(&temp_path, path)?;
+
+    Ok(())
+}
+
+pub fn load(path: &Path) -> Result<Checkpoint> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("could not read checkpoint {}: {}", path.display(), e))?;
+
+    // check the version on its own first, so an old checkpoint gets a clear message instead of a missing field error
+    let raw: serde_json::Value = serde_json::from_str(&contents)
+        .map_err(|e| format!("{} is not a checkpoint file: {}", path.display(), e))?;
+
+    match raw.get("version").and_then(|v| v.as_u64()) {
+        Some(version) if version == CHECKPOINT_VERSION as u64 => (),
+        Some(version) => {
+            return Err(format!(
+                "checkpoint {} uses format version {}, but this build only reads version {}. it can't be resumed exactly - start a new run instead",
+                path.display(),
+                version,
+                CHECKPOINT_VERSION
+            )
+            .into());
+        }
+        None => return Err(format!("{} has no checkpoint format version", path.display()).into()),
+    }
+
+    // parsed again from the text rather than the Value, which can't hold the rng's 128 bit state
+    let checkpoint: Checkpoint = serde_json::from_str(&contents)
+        .map_err(|e| format!("checkpoint {} is damaged: {}", path.display(), e))?;
+
+    Ok(checkpoint)
+}
+
+/// puts the sensor's partitions back the way they were when the checkpoint was taken.
+///
+/// anything written after the checkpoint will be generated again from the restored rng, so it has to go:
+/// the live partition is cut back to its checkpointed length and any later partitions are removed.
+pub fn rewind_partitions(dir: &Path, checkpoint: &Checkpoint) -> Result<()> {
+    let live_path = dir.join(format!(
+        "{}_output_{}.csv",
+        checkpoint.sensor_id, checkpoint.current_file_partition
+    ));
+
+    for partition in find_partitions(dir, &checkpoint.sensor_id)? {
+        if partition.number > checkpoint.current_file_partition {
+            std::fs::remove_file(&partition.path)?;
+        } else if partition.number == checkpoint.current_file_partition && partition.closed {
+            // the live partition was closed and compressed after the checkpoint - reopen it
+            let mut contents = Vec::new();
+            open_decompressed(&partition.path)?.read_to_end(&mut contents)?;
+            contents.truncate(checkpoint.partition_len as usize);
+
+            let mut file = File::create(&live_path)?;
+            file.write_all(&contents)?;
+            file.sync_all()?;
+
+            std::fs::remove_file(&partition.path)?;
+        }
+    }
+
+    if live_path.exists() {
+        let file = OpenOptions::new().write(true).open(&live_path)?;
+        if file.metadata()?.len() > checkpoint.partition_len {
+            file.set_len(checkpoint.partition_len)?;
+            file.sync_all()?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::Compression;
+    use crate::compression::compress_file;
+    use rand::SeedableRng;
+
+    /// an empty directory of its own for each test, so they can run in parallel
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "sensor_simulator_checkpoint_{}_{}",
+            name,
+            std::process::id()
+        ));
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn checkpoint(current_file_partition: usize, partition_len: u64) -> Checkpoint {
+        Checkpoint {
+            version: CHECKPOINT_VERSION,
+            sensor_id: "TMPabc".to_string(),
+            random_seed: 7,
+            rng: Pcg64::seed_from_u64(7),
+            base_value: 20.0,
+            last_value: Some(21.5),
+            last_timestamp: Some("2025-01-31 09:30:00".to_string()),
+            readings_generated: 4,
+            interval: 1,
+            remaining_seconds: 10,
+            file_path: None,
+            current_file_partition,
+            rows_in_current_file: 2,
+            partition_len,
+            output_len: 0,
+        }
+    }
+
+    #[test]
+    fn saved_checkpoints_load_back() {
+        let dir = test_dir("round_trip");
+        let path = checkpoint_path(&dir, "TMPabc");
+
+        save(&path, &checkpoint(1, 100)).unwrap();
+        let loaded = load(&path).unwrap();
+
+        assert_eq!(loaded.sensor_id, "TMPabc");
+        assert_eq!(loaded.partition_len, 100);
+        assert_eq!(loaded.rng, Pcg64::seed_from_u64(7));
+    }
+
+    #[test]
+    fn other_versions_are_refused() {
+        let dir = test_dir("version");
+        let path = checkpoint_path(&dir, "TMPabc");
+
+        let mut old = checkpoint(0, 0);
+        old.version = CHECKPOINT_VERSION - 1;
+        save(&path, &old).unwrap();
+
+        let error = load(&path).unwrap_err().to_string();
+        assert!(error.contains("format version"), "{}", error);
+
+        std::fs::write(&path, r#"{"sensor_id": "TMPabc"}"#).unwrap();
+        assert!(load(&path).is_err());
+    }
+
+    #[test]
+    fn live_partition_is_cut_back() {
+        let dir = test_dir("cut");
+        let live = dir.join("TMPabc_output_0.csv");
+        std::fs::write(&live, "header\nrow 1\nrow 2\n").unwrap();
+
+        rewind_partitions(&dir, &checkpoint(0, 13)).unwrap();
+
+        assert_eq!(std::fs::read_to_string(&live).unwrap(), "header\nrow 1\n");
+    }
+
+    #[test]
+    fn live_partition_compressed_after_the_checkpoint_is_reopened() {
+        let dir = test_dir("compressed");
+        let live = dir.join("TMPabc_output_1.csv");
+        std::fs::write(&live, "header\nrow 1\nrow 2\n").unwrap();
+        compress_file(&live, &Compression::Zstd, None).unwrap();
+
+        rewind_partitions(&dir, &checkpoint(1, 13)).unwrap();
+
+        assert_eq!(std::fs::read_to_string(&live).unwrap(), "header\nrow 1\n");
+        assert!(!dir.join("TMPabc_output_1.csv.zst").exists());
+    }
+
+    #[test]
+    fn partitions_after_the_checkpoint_are_removed() {
+        let dir = test_dir("later");
+        let closed = dir.join("TMPabc_output_0.csv");
+        std::fs::write(&closed, "header\nrow 1\n").unwrap();
+        compress_file(&closed, &Compression::Gzip, None).unwrap();
+        std::fs::write(dir.join("TMPabc_output_1.csv"), "header\nrow 2\n").unwrap();
+        std::fs::write(dir.join("TMPabc_output_2.csv"), "header\nrow 3\n").unwrap();
+        std::fs::write(dir.join("TMPxyz_output_2.csv"), "someone else's\n").unwrap();
+
+        rewind_partitions(&dir, &checkpoint(1, 13)).unwrap();
+
+        assert!(dir.join("TMPabc_output_0.csv.gz").exists());
+        assert!(dir.join("TMPabc_output_1.csv").exists());
+        assert!(!dir.join("TMPabc_output_2.csv").exists());
+        assert!(dir.join("TMPxyz_output_2.csv").exists());
+    }
+}
@@ -0,0 +1,118 @@
+use crate::args::Compression;
+use flate2::write::GzEncoder;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+pub const GZIP_DEFAULT_LEVEL: i32 = 6;
+pub const ZSTD_DEFAULT_LEVEL: i32 = 3;
+
+/// the file extension added to a file once it has been compressed with the given codec
+pub fn extension(compression: &Compression) -> Option<&'static str> {
+    match compression {
+        Compression::None => None,
+        Compression::Gzip => Some("gz"),
+        Compression::Zstd => Some("zst"),
+    }
+}
+
+pub fn validate_level(compression: &Compression, level: Option<i32>) -> Result<()> {
+    let level = match level {
+        Some(level) => level,
+        None => return Ok(()),
+    };
+
+    match compression {
+        Compression::None => {
+            Err("--compress-level was given without choosing a codec with --compress".into())
+        }
+        Compression::Gzip if !(0..=9).contains(&level) => Err(format!(
+            "gzip compression level must be between 0 and 9, got {}",
+            level
+        )
+        .into()),
+        Compression::Zstd if !zstd::compression_level_range().contains(&level) => Err(format!(
+            "zstd compression level must be between {} and {}, got {}",
+            zstd::compression_level_range().start(),
+            zstd::compression_level_range().end(),
+            level
+        )
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+/// path of the compressed copy of `path`, e.g. `TMPabc_output_0.csv` -> `TMPabc_output_0.csv.zst`
+pub fn compressed_path(path: &Path, compression: &Compression) -> Option<PathBuf> {
+    let extension = extension(compression)?;
+
+    let mut compressed = path.as_os_str().to_owned();
+    compressed.push(".");
+    compressed.push(extension);
+
+    Some(PathBuf::from(compressed))
+}
+
+/// compresses a closed file and replaces it with the compressed version.
+///
+/// the compressed data is written to a `.tmp` file first, synced, and only then renamed into place.
+/// rename is atomic, so if the process dies part way through there is either the untouched original
+/// or a complete compressed file - never a half written one sitting where the partition should be.
+/// the original is only removed once the rename has succeeded.
+pub fn compress_file(path: &Path, compression: &Compression, level: Option<i32>) -> Result<()> {
+    let final_path = match compressed_path(path, compression) {
+        Some(p) => p,
+        None => return Ok(()),
+    };
+
+    let mut temp_path = final_path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+
+    let mut source = File::open(path)?;
+    let temp_file = File::create(&temp_path)?;
+
+    let result = match compression {
+        Compression::None => unreachable!("compressed_path returns None when compression is off"),
+        Compression::Gzip => {
+            let level = level.unwrap_or(GZIP_DEFAULT_LEVEL) as u32;
+            let mut encoder = GzEncoder::new(temp_file, flate2::Compression::new(level));
+            std::io::copy(&mut source, &mut encoder)
+                .and_then(|_| encoder.finish())
+                .and_then(|file| file.sync_all())
+        }
+        Compression::Zstd => {
+            let level = level.unwrap_or(ZSTD_DEFAULT_LEVEL);
+            zstd::stream::Encoder::new(temp_file, level).and_then(|mut encoder| {
+                std::io::copy(&mut source, &mut encoder)
+                    .and_then(|_| encoder.finish())
+                    .and_then(|file| file.sync_all())
+            })
+        }
+    };
+
+    if let Err(e) = result {
+        _ = std::fs::remove_file(&temp_path);
+        return Err(e.into());
+    }
+
+    std::fs::rename(&temp_path, &final_path)?;
+    std::fs::remove_file(path)?;
+
+    Ok(())
+}
+
+/// opens a file for reading, transparently decompressing it if its extension says it was compressed
+pub fn open_decompressed(path: &Path) -> Result<Box<dyn Read>> {
+    let file = File::open(path)?;
+
+    let reader: Box<dyn Read> = match path.extension().and_then(|e| e.to_str()) {
+        Some("gz") => Box::new(flate2::read::GzDecoder::new(file)),
+        Some("zst") => Box::new(zstd::stream::Decoder::new(file)?),
+        _ => Box::new(file),
+    };
+
+    Ok(reader)
+}
@@ -0,0 +1,248 @@
+use crate::args::PayloadFormat;
+use crate::senml;
+use crate::sensor::{SensorOutput, Unit};
+use crate::timestamp::{Timestamp, TimestampFormat, Timezone};
+use crate::utils::unit_parts;
+use serde::Serialize;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// a reading with its timestamp written in the format a sink was asked for. serialises with the same fields as
+/// a reading itself, so the only difference from the file outputs is the timestamp
+#[derive(Debug, Serialize)]
+pub struct FormattedReading<'a> {
+    id: &'a str,
+    timestamp: Timestamp,
+    value: f32,
+    unit: &'a Unit,
+    symbol: &'a str,
+}
+
+impl<'a> FormattedReading<'a> {
+    pub fn new(
+        reading: &'a SensorOutput,
+        timestamps: &TimestampFormat,
+        timezone: &Timezone,
+    ) -> FormattedReading<'a> {
+        FormattedReading {
+            id: &reading.id,
+            timestamp: timestamps.format(&reading.timestamp, timezone),
+            value: reading.value,
+            unit: &reading.unit,
+            symbol: &reading.symbol,
+        }
+    }
+}
+
+/// turns a single reading into the bytes a sink sends. uses the same serde representation as the file outputs,
+/// so a json payload matches a row of `output.json` and a csv payload matches a row of the csv partitions,
+/// apart from the timestamp format. payloads never end in a newline - line based sinks add their own
+pub fn encode(
+    reading: &SensorOutput,
+    format: &PayloadFormat,
+    timestamps: &TimestampFormat,
+) -> Result<Vec<u8>> {
+    encode_local(reading, format, timestamps, &Timezone::Utc)
+}
+
+/// `encode` with the timestamp shown in a timezone other than utc, for the console
+pub fn encode_local(
+    reading: &SensorOutput,
+    format: &PayloadFormat,
+    timestamps: &TimestampFormat,
+    timezone: &Timezone,
+) -> Result<Vec<u8>> {
+    let formatted = FormattedReading::new(reading, timestamps, timezone);
+
+    let payload = match format {
+        PayloadFormat::Json => serde_json::to_vec(&formatted)?,
+        PayloadFormat::Csv => {
+            let mut writer = csv::WriterBuilder::new()
+                .has_headers(false)
+                .from_writer(vec![]);
+            writer.serialize(&formatted)?;
+
+            let mut line = writer.into_inner()?;
+            while line.last() == Some(&b'\n') || line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            line
+        }
+        PayloadFormat::Raw => reading.value.to_string().into_bytes(),
+        PayloadFormat::Influx => line_protocol(reading).into_bytes(),
+        // field names are kept so a msgpack map reads the same as the json object
+        PayloadFormat::Msgpack => rmp_serde::to_vec_named(&formatted)?,
+        PayloadFormat::Cbor => {
+            let mut cbor: Vec<u8> = vec![];
+            ciborium::into_writer(&formatted, &mut cbor)?;
+            cbor
+        }
+        PayloadFormat::Protobuf => protobuf(reading),
+        PayloadFormat::SenmlJson => senml::to_json(&senml::pack([reading]))?,
+        PayloadFormat::SenmlCbor => senml::to_cbor(&senml::pack([reading]))?,
+    };
+
+    Ok(payload)
+}
+
+/// encodes a reading as one frame of a byte stream, the unit every stream-like sink sends. text formats are a
+/// line, msgpack and cbor say where they end themselves, and protobuf is prefixed with its length as a varint
+/// (see `proto/sensor_output.proto`)
+pub fn encode_frame(
+    reading: &SensorOutput,
+    format: &PayloadFormat,
+    timestamps: &TimestampFormat,
+) -> Result<Vec<u8>> {
+    let payload = encode(reading, format, timestamps)?;
+
+    let frame = match format {
+        PayloadFormat::Json
+        | PayloadFormat::Csv
+        | PayloadFormat::Raw
+        | PayloadFormat::Influx
+        | PayloadFormat::SenmlJson => {
+            let mut line = payload;
+            line.push(b'\n');
+            line
+        }
+        PayloadFormat::Msgpack | PayloadFormat::Cbor | PayloadFormat::SenmlCbor => payload,
+        PayloadFormat::Protobuf => {
+            let mut frame: Vec<u8> = vec![];
+            write_varint(&mut frame, payload.len() as u64);
+            frame.extend(payload);
+            frame
+        }
+    };
+
+    Ok(frame)
+}
+
+/// a reading as the `SensorOutput` message in `proto/sensor_output.proto`. the timestamp is a
+/// `google.protobuf.Timestamp`, so it is in utc whichever format the sink was asked for
+pub fn protobuf(reading: &SensorOutput) -> Vec<u8> {
+    let (category, unit) = unit_parts(&reading.unit);
+    let nanos: i128 = reading.timestamp.unix_timestamp_nanos();
+
+    let mut timestamp: Vec<u8> = vec![];
+    write_key(&mut timestamp, 1, WIRE_VARINT);
+    write_varint(
+        &mut timestamp,
+        nanos.div_euclid(1_000_000_000) as i64 as u64,
+    );
+    write_key(&mut timestamp, 2, WIRE_VARINT);
+    write_varint(&mut timestamp, nanos.rem_euclid(1_000_000_000) as u64);
+
+    let mut message: Vec<u8> = vec![];
+    write_bytes(&mut message, 1, reading.id.as_bytes());
+    write_bytes(&mut message, 2, &timestamp);
+    write_key(&mut message, 3, WIRE_FIXED32);
+    message.extend(reading.value.to_le_bytes());
+    write_bytes(&mut message, 4, category.as_bytes());
+    write_bytes(&mut message, 5, unit.as_bytes());
+    write_bytes(&mut message, 6, reading.symbol.as_bytes());
+
+    message
+}
+
+const WIRE_VARINT: u8 = 0;
+const WIRE_LENGTH_DELIMITED: u8 = 2;
+const WIRE_FIXED32: u8 = 5;
+
+fn write_key(buffer: &mut Vec<u8>, field: u32, wire_type: u8) {
+    write_varint(buffer, ((field as u64) << 3) | wire_type as u64);
+}
+
+fn write_bytes(buffer: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    write_key(buffer, field, WIRE_LENGTH_DELIMITED);
+    write_varint(buffer, bytes.len() as u64);
+    buffer.extend_from_slice(bytes);
+}
+
+// seven bits at a time, least significant first, with the top bit set on every byte but the last
+fn write_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+/// one line of influxdb line protocol, e.g. `temperature,id=TMPx1y,unit=celsius value=21.5 1700000000123456789`.
+/// the measurement is the sensor's category and the timestamp is always in nanoseconds, influx's default precision
+pub fn line_protocol(reading: &SensorOutput) -> String {
+    let (category, unit) = unit_parts(&reading.unit);
+
+    format!(
+        "{},id={},unit={} value={} {}",
+        category,
+        escape_tag(&reading.id),
+        unit,
+        reading.value,
+        reading.timestamp.unix_timestamp_nanos()
+    )
+}
+
+// commas, spaces and equals signs separate the parts of a line, so they have to be escaped in tag values
+fn escape_tag(value: &str) -> String {
+    value
+        .replace(',', "\\,")
+        .replace(' ', "\\ ")
+        .replace('=', "\\=")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::TemperatureUnit;
+    use time::UtcDateTime;
+
+    fn varint(value: u64) -> Vec<u8> {
+        let mut buffer: Vec<u8> = vec![];
+        write_varint(&mut buffer, value);
+        buffer
+    }
+
+    #[test]
+    fn varints_are_seven_bits_at_a_time() {
+        assert_eq!(varint(0), [0x00]);
+        assert_eq!(varint(1), [0x01]);
+        assert_eq!(varint(127), [0x7f]);
+        assert_eq!(varint(128), [0x80, 0x01]);
+        assert_eq!(varint(300), [0xac, 0x02]);
+        assert_eq!(varint(16_384), [0x80, 0x80, 0x01]);
+        assert_eq!(
+            varint(u64::MAX),
+            [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]
+        );
+    }
+
+    #[test]
+    fn keys_carry_the_field_and_wire_type() {
+        let mut buffer: Vec<u8> = vec![];
+        write_key(&mut buffer, 1, WIRE_LENGTH_DELIMITED);
+        write_key(&mut buffer, 3, WIRE_FIXED32);
+        write_key(&mut buffer, 16, WIRE_VARINT);
+
+        assert_eq!(buffer, [0x0a, 0x1d, 0x80, 0x01]);
+    }
+
+    #[test]
+    fn protobuf_frames_are_length_prefixed() {
+        let reading = SensorOutput {
+            id: "TMPabc".to_string(),
+            timestamp: UtcDateTime::from_unix_timestamp(1_738_315_800).unwrap(),
+            value: 21.5,
+            unit: Unit::TemperatureUnit(TemperatureUnit::Celsius),
+            symbol: "°C".to_string(),
+        };
+
+        let message = protobuf(&reading);
+        let frame =
+            encode_frame(&reading, &PayloadFormat::Protobuf, &TimestampFormat::Plain).unwrap();
+
+        // the id is the first field, as a length delimited string
+        assert_eq!(&message[..8], b"\x0a\x06TMPabc");
+        assert_eq!(frame[0] as usize, message.len());
+        assert_eq!(&frame[1..], message);
+    }
+}
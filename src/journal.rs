@@ -0,0 +1,384 @@
+use crate::args::FaultPoint;
+use std::fs::{File, OpenOptions};
+use std::io::SeekFrom;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+const JOURNAL_MAGIC: &str = "sensor_simulator_journal_v1";
+
+// appending a batch to a partition goes through a small write-ahead journal next to the partition:
+//
+//   1. the new rows are written to `<partition>.journal.tmp` along with the length the partition had
+//      before the append and a checksum, synced, then renamed to `<partition>.journal`. the rename is
+//      the commit point - once the journal exists the batch is durable.
+//   2. the partition is truncated back to the recorded length (throws away anything torn from an
+//      earlier crash) and the rows are appended and synced.
+//   3. the journal is deleted.
+//
+// only the new rows are ever copied, so the cost is O(batch) rather than O(partition). if the process
+// dies at any point, `recover_directory` either replays a committed journal or throws away an
+// uncommitted one, so every batch ends up in the partition exactly once or not at all.
+
+fn journal_path(partition: &Path) -> PathBuf {
+    let mut path = partition.as_os_str().to_owned();
+    path.push(".journal");
+    PathBuf::from(path)
+}
+
+fn temp_journal_path(partition: &Path) -> PathBuf {
+    let mut path = partition.as_os_str().to_owned();
+    path.push(".journal.tmp");
+    PathBuf::from(path)
+}
+
+fn sync_dir(path: &Path) -> Result<()> {
+    // renames and deletes are only durable once the directory entry itself has been synced
+    if let Some(dir) = path.parent() {
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+fn crash(point: FaultPoint) -> ! {
+    eprintln!("fault injection: killing the process at {:?}", point);
+    std::process::abort();
+}
+
+/// appends `payload` to the partition at `path` through the write-ahead journal.
+///
+/// `fault` is only ever set by `--inject-fault`, and kills the process at the chosen point in the write.
+pub fn append_batch(path: &Path, payload: &[u8], fault: Option<FaultPoint>) -> Result<()> {
+    let offset: u64 = match std::fs::metadata(path) {
+        Ok(metadata) => metadata.len(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e.into()),
+    };
+
+    let header = format!(
+        "{} {} {} {}\n",
+        JOURNAL_MAGIC,
+        offset,
+        payload.len(),
+        crc32fast::hash(payload)
+    );
+
+    let temp_journal = temp_journal_path(path);
+    let journal = journal_path(path);
+
+    let mut file = File::create(&temp_journal)?;
+    file.write_all(header.as_bytes())?;
+
+    if fault == Some(FaultPoint::MidJournal) {
+        file.write_all(&payload[..payload.len() / 2])?;
+        file.sync_all()?;
+        crash(FaultPoint::MidJournal);
+    }
+
+    file.write_all(payload)?;
+    file.sync_all()?;
+    drop(file);
+
+    std::fs::rename(&temp_journal, &journal)?;
+    sync_dir(path)?;
+
+    write_at(path, offset, payload, fault)?;
+
+    if fault == Some(FaultPoint::BeforeCleanup) {
+        crash(FaultPoint::BeforeCleanup);
+    }
+
+    std::fs::remove_file(&journal)?;
+    sync_dir(path)?;
+
+    Ok(())
+}
+
+fn write_at(path: &Path, offset: u64, payload: &[u8], fault: Option<FaultPoint>) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)?;
+
+    file.set_len(offset)?;
+    file.seek(SeekFrom::Start(offset))?;
+
+    if fault == Some(FaultPoint::MidAppend) {
+        file.write_all(&payload[..payload.len() / 2])?;
+        file.sync_all()?;
+        crash(FaultPoint::MidAppend);
+    }
+
+    file.write_all(payload)?;
+    file.sync_all()?;
+
+    Ok(())
+}
+
+/// replays a committed journal into its partition. returns false if the journal was unreadable and discarded.
+fn replay(journal: &Path) -> Result<bool> {
+    let partition = PathBuf::from(
+        journal
+            .to_string_lossy()
+            .strip_suffix(".journal")
+            .ok_or("journal file name does not end in .journal")?,
+    );
+
+    let contents = std::fs::read(journal)?;
+
+    let parsed = contents
+        .iter()
+        .position(|b| *b == b'\n')
+        .and_then(|newline| {
+            let header = std::str::from_utf8(&contents[..newline]).ok()?;
+            let fields: Vec<&str> = header.split(' ').collect();
+            match fields.as_slice() {
+                [magic, offset, len, crc] if *magic == JOURNAL_MAGIC => Some((
+                    offset.parse::<u64>().ok()?,
+                    len.parse::<usize>().ok()?,
+                    crc.parse::<u32>().ok()?,
+                    &contents[newline + 1..],
+                )),
+                _ => None,
+            }
+        });
+
+    match parsed {
+        Some((offset, len, crc, payload))
+            if payload.len() == len && crc32fast::hash(payload) == crc =>
+        {
+            write_at(&partition, offset, payload, None)?;
+            std::fs::remove_file(journal)?;
+            sync_dir(journal)?;
+            Ok(true)
+        }
+        _ => {
+            // journals only appear by rename once fully written, so this should never happen - but if it
+            // does, the partition was never touched for this batch and the journal is safe to drop
+            std::fs::remove_file(journal)?;
+            Ok(false)
+        }
+    }
+}
+
+/// cuts a partition back to its last complete row, if the final row was only partly written
+fn truncate_torn_row(path: &Path) -> Result<bool> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let len = file.metadata()?.len();
+
+    if len == 0 {
+        return Ok(false);
+    }
+
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)?;
+
+    if contents.last() == Some(&b'\n') {
+        return Ok(false);
+    }
+
+    let keep = match contents.iter().rposition(|b| *b == b'\n') {
+        Some(newline) => newline as u64 + 1,
+        None => 0,
+    };
+
+    file.set_len(keep)?;
+    file.sync_all()?;
+
+    Ok(true)
+}
+
+/// repairs everything an interrupted run can leave behind in an output directory:
+/// committed journals are replayed, uncommitted ones are discarded, torn trailing rows are cut off, and
+/// half finished compression temp files are removed. returns the number of files that needed repairing.
+pub fn recover_directory(dir: &Path) -> Result<usize> {
+    let mut repaired: usize = 0;
+
+    if !dir.is_dir() {
+        return Ok(repaired);
+    }
+
+    let mut entries: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .collect();
+    entries.sort();
+
+    for path in entries.iter() {
+        let name = path.to_string_lossy();
+
+        if name.ends_with(".journal.tmp") || name.ends_with(".gz.tmp") || name.ends_with(".zst.tmp")
+        {
+            std::fs::remove_file(path)?;
+            repaired += 1;
+        } else if name.ends_with(".journal") {
+            if replay(path)? {
+                eprintln!("recovered an interrupted write from {}", name);
+            } else {
+                eprintln!("discarded an unreadable journal {}", name);
+            }
+            repaired += 1;
+        }
+    }
+
+    for path in entries.iter() {
+        let name = path.to_string_lossy();
+
+        if !(name.contains("_output_") && name.ends_with(".csv") && path.exists()) {
+            continue;
+        }
+
+        // a crash between renaming a compressed partition into place and deleting the original leaves both.
+        // the compressed copy only exists once it is complete, so the original is the one to drop
+        let compressed_exists = ["gz", "zst"]
+            .iter()
+            .any(|extension| Path::new(&format!("{}.{}", name, extension)).exists());
+
+        if compressed_exists {
+            std::fs::remove_file(path)?;
+            repaired += 1;
+        } else if truncate_torn_row(path)? {
+            eprintln!("removed a partially written row from the end of {}", name);
+            repaired += 1;
+        }
+    }
+
+    Ok(repaired)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// an empty directory of its own for each test, so they can run in parallel
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "sensor_simulator_journal_{}_{}",
+            name,
+            std::process::id()
+        ));
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// a committed journal, as `append_batch` leaves it if the process dies before the partition is written
+    fn write_journal(partition: &Path, offset: u64, payload: &[u8]) {
+        let mut contents = format!(
+            "{} {} {} {}\n",
+            JOURNAL_MAGIC,
+            offset,
+            payload.len(),
+            crc32fast::hash(payload)
+        )
+        .into_bytes();
+        contents.extend_from_slice(payload);
+        std::fs::write(journal_path(partition), contents).unwrap();
+    }
+
+    #[test]
+    fn append_batch_appends_and_removes_its_journal() {
+        let dir = test_dir("append");
+        let partition = dir.join("TMPabc_output_0.csv");
+
+        append_batch(&partition, b"a\n", None).unwrap();
+        append_batch(&partition, b"b\n", None).unwrap();
+
+        assert_eq!(std::fs::read(&partition).unwrap(), b"a\nb\n");
+        assert!(!journal_path(&partition).exists());
+        assert!(!temp_journal_path(&partition).exists());
+    }
+
+    #[test]
+    fn committed_journal_is_replayed_over_a_torn_append() {
+        let dir = test_dir("replay");
+        let partition = dir.join("TMPabc_output_0.csv");
+        // the crash came half way through appending `b\nc\n` after `a\n`
+        std::fs::write(&partition, b"a\nb").unwrap();
+        write_journal(&partition, 2, b"b\nc\n");
+
+        assert_eq!(recover_directory(&dir).unwrap(), 1);
+
+        assert_eq!(std::fs::read(&partition).unwrap(), b"a\nb\nc\n");
+        assert!(!journal_path(&partition).exists());
+    }
+
+    #[test]
+    fn replaying_twice_writes_the_batch_once() {
+        let dir = test_dir("replay_twice");
+        let partition = dir.join("TMPabc_output_0.csv");
+        // the crash came after the append, before the journal was removed
+        std::fs::write(&partition, b"a\nb\n").unwrap();
+        write_journal(&partition, 2, b"b\n");
+
+        recover_directory(&dir).unwrap();
+
+        assert_eq!(std::fs::read(&partition).unwrap(), b"a\nb\n");
+    }
+
+    #[test]
+    fn truncated_journal_is_discarded_without_touching_the_partition() {
+        let dir = test_dir("truncated");
+        let partition = dir.join("TMPabc_output_0.csv");
+        std::fs::write(&partition, b"a\n").unwrap();
+        write_journal(&partition, 2, b"b\nc\n");
+
+        // cut the journal short, so its payload no longer matches the length and checksum in the header
+        let journal = journal_path(&partition);
+        let contents = std::fs::read(&journal).unwrap();
+        std::fs::write(&journal, &contents[..contents.len() - 2]).unwrap();
+
+        assert_eq!(recover_directory(&dir).unwrap(), 1);
+
+        assert_eq!(std::fs::read(&partition).unwrap(), b"a\n");
+        assert!(!journal.exists());
+    }
+
+    #[test]
+    fn torn_row_without_a_journal_is_cut_off() {
+        let dir = test_dir("torn_row");
+        let partition = dir.join("TMPabc_output_0.csv");
+        std::fs::write(&partition, b"a\nb\nc").unwrap();
+
+        assert_eq!(recover_directory(&dir).unwrap(), 1);
+
+        assert_eq!(std::fs::read(&partition).unwrap(), b"a\nb\n");
+    }
+
+    #[test]
+    fn leftover_temp_files_are_cleaned_up() {
+        let dir = test_dir("cleanup");
+        let partition = dir.join("TMPabc_output_0.csv");
+        std::fs::write(&partition, b"a\n").unwrap();
+        std::fs::write(temp_journal_path(&partition), b"half a journal").unwrap();
+        std::fs::write(dir.join("TMPabc_output_1.csv.gz.tmp"), b"half a gzip").unwrap();
+        // a partition that was compressed, but not yet removed
+        std::fs::write(dir.join("TMPabc_output_2.csv"), b"b\n").unwrap();
+        std::fs::write(dir.join("TMPabc_output_2.csv.gz"), b"compressed").unwrap();
+
+        assert_eq!(recover_directory(&dir).unwrap(), 3);
+
+        let mut left: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["TMPabc_output_0.csv", "TMPabc_output_2.csv.gz"]);
+        assert_eq!(std::fs::read(&partition).unwrap(), b"a\n");
+    }
+
+    #[test]
+    fn clean_directory_needs_no_repairs() {
+        let dir = test_dir("clean");
+        std::fs::write(dir.join("TMPabc_output_0.csv"), b"a\nb\n").unwrap();
+
+        assert_eq!(recover_directory(&dir).unwrap(), 0);
+        assert_eq!(recover_directory(&dir.join("missing")).unwrap(), 0);
+    }
+}
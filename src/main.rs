@@ -1,34 +1,114 @@
+/// prints a progress note to stderr, unless `--quiet` was given. errors and warnings use `eprintln!` directly
+macro_rules! note {
+    ($($arg:tt)*) => {
+        if !$crate::utils::is_quiet() {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
 mod args;
+mod buffer;
+mod checkpoint;
+mod compression;
+mod encoding;
+mod journal;
+mod output_file;
+mod resume;
+mod senml;
 mod sensor;
+mod server;
+mod sinks;
+mod template;
+mod timestamp;
 mod utils;
 
-use crate::args::{Sensor, parse_and_validate};
-use crate::sensor::{
-    EnvironmentalSensor, build_humidity_sensor, build_pressure_sensor, build_temp_sensor,
-};
+use crate::args::{Command, parse_and_validate};
+use crate::checkpoint::{Checkpoint, load};
+use crate::sensor::{EnvironmentalSensor, build_sensor};
+use crate::utils::set_quiet;
+use std::path::Path;
 use std::process;
 
 fn main() {
-    let args = parse_and_validate().expect("invalid arguments");
+    let args = match parse_and_validate() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("invalid arguments: {}", e);
+            process::exit(1);
+        }
+    };
+
+    set_quiet(args.output_args.quiet);
+
+    match &args.command {
+        Command::Sensor(sensor_type) => note!("sensor_type: {:?}", sensor_type),
+        Command::Serve(serve_args) => note!("sensor_types: {:?}", serve_args.sensors),
+    }
+    note!("interval: {:?}", args.timing_args.interval);
+    note!("duration: {:?}", args.timing_args.duration);
+    note!("number: {:?}", args.timing_args.number);
 
-    println!("sensor_type: {:?}", args.sensor_type);
-    println!("interval: {:?}", args.timing_args.interval);
-    println!("duration: {:?}", args.timing_args.duration);
-    println!("number: {:?}", args.timing_args.number);
+    let sensor_type = match &args.command {
+        Command::Sensor(sensor_type) => sensor_type,
+        Command::Serve(serve_args) => {
+            // without a duration or number, served sensors run until the server is stopped
+            let duration: i32 = match args.timing_args.duration {
+                Some(duration) => duration as i32,
+                None => i32::MAX,
+            };
 
-    let mut sensor: EnvironmentalSensor = match &args.sensor_type {
-        Sensor::Temperature { .. } => build_temp_sensor(&args),
-        Sensor::Pressure { .. } => build_pressure_sensor(&args),
-        Sensor::Humidity { .. } => build_humidity_sensor(&args),
+            match server::run(
+                &args,
+                serve_args,
+                args.timing_args.interval.unwrap() as i32,
+                duration,
+            ) {
+                Ok(..) => note!("process complete"),
+                Err(e) => {
+                    eprintln!("an error was encountered: {}", e);
+                    process::exit(1);
+                }
+            };
+            return;
+        }
+    };
+
+    let checkpoint: Option<Checkpoint> = match &args.output_args.from_checkpoint {
+        Some(path) => match load(Path::new(path)) {
+            Ok(checkpoint) => Some(checkpoint),
+            Err(e) => {
+                eprintln!("an error was encountered: {}", e);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    // a checkpoint carries its own interval and remaining duration
+    let (interval, duration): (i32, i32) = match &checkpoint {
+        Some(checkpoint) => (
+            checkpoint.interval as i32,
+            checkpoint.remaining_seconds as i32,
+        ),
+        None => (
+            args.timing_args.interval.unwrap() as i32,
+            args.timing_args.duration.unwrap() as i32,
+        ),
     };
 
-    let interval: i32 = args.timing_args.interval.unwrap().clone() as i32;
-    let duration: i32 = args.timing_args.duration.unwrap().clone() as i32;
+    let mut sensor: EnvironmentalSensor = match build_sensor(&args, sensor_type, checkpoint) {
+        Ok(sensor) => sensor,
+        Err(e) => {
+            eprintln!("an error was encountered: {}", e);
+            process::exit(1);
+        }
+    };
 
     match sensor.run_sensor(&interval, &duration) {
-        Ok(..) => println!("process complete"),
+        Ok(..) => note!("process complete"),
         Err(e) => {
-            println!("an error was encountered: {}", e);
+            eprintln!("an error was encountered: {}", e);
             process::exit(1);
         }
     };
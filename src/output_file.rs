@@ -0,0 +1,436 @@
+use crate::args::{Compression, FileFormat, PayloadFormat};
+use crate::compression::{compressed_path, open_decompressed};
+use crate::encoding::{FormattedReading, encode_frame};
+use crate::senml::Record;
+use crate::sensor::SensorOutput;
+use crate::timestamp::{TimestampFormat, Timezone};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use time::UtcDateTime;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// an indefinite length cbor array and the byte that ends it, so a pack can be started before it's known how
+// many records it will hold
+const CBOR_ARRAY_START: u8 = 0x9f;
+const CBOR_BREAK: u8 = 0xff;
+
+/// the `output.<format>` file, which gets every reading of the run in the format picked with --format. readings
+/// are added a batch at a time as they are flushed to the partitions, so the buffer never has to hold the whole
+/// run. json and the senml packs are single documents, so they aren't closed off until the run finishes
+#[derive(Debug)]
+pub struct OutputFile {
+    path: PathBuf,
+    format: FileFormat,
+    timestamps: TimestampFormat,
+    writer: BufWriter<File>,
+    has_readings: bool,
+    senml_base: Option<UtcDateTime>,
+}
+
+impl OutputFile {
+    pub fn create(
+        dir: &Path,
+        format: FileFormat,
+        timestamps: TimestampFormat,
+    ) -> Result<OutputFile> {
+        let path: PathBuf = dir.join(file_name(&format));
+        let mut writer = BufWriter::new(File::create(&path)?);
+
+        match format {
+            FileFormat::Json | FileFormat::SenmlJson => writer.write_all(b"[")?,
+            FileFormat::SenmlCbor => writer.write_all(&[CBOR_ARRAY_START])?,
+            _ => (),
+        }
+
+        Ok(OutputFile {
+            path,
+            format,
+            timestamps,
+            writer,
+            has_readings: false,
+            senml_base: None,
+        })
+    }
+
+    /// carries on with the file an earlier run left, for --resume and --from-checkpoint. a checkpoint gives the
+    /// length the file had when it was taken, and anything written after that is cut off, as it will be generated
+    /// again.
+    ///
+    /// a file compressed at the end of its run is decompressed first. a finished json array or senml pack is
+    /// reopened by taking its closing bracket off, and the senml records added start with a base record of their
+    /// own, which a pack is allowed to have more than one of
+    pub fn reopen(
+        dir: &Path,
+        format: FileFormat,
+        timestamps: TimestampFormat,
+        checkpointed_len: Option<u64>,
+    ) -> Result<OutputFile> {
+        let path: PathBuf = dir.join(file_name(&format));
+
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let compressed: PathBuf = compressed_path(&path, &compression).unwrap();
+            if compressed.exists() && !path.exists() {
+                let mut contents: Vec<u8> = vec![];
+                open_decompressed(&compressed)?.read_to_end(&mut contents)?;
+                std::fs::write(&path, contents)?;
+                std::fs::remove_file(&compressed)?;
+            }
+        }
+
+        if !path.exists() {
+            return OutputFile::create(dir, format, timestamps);
+        }
+
+        let mut file: File = OpenOptions::new().read(true).write(true).open(&path)?;
+
+        if let Some(len) = checkpointed_len
+            && file.metadata()?.len() > len
+        {
+            file.set_len(len)?;
+        }
+
+        let len: u64 = file.metadata()?.len();
+
+        let has_readings: bool = match format {
+            FileFormat::Json | FileFormat::SenmlJson => {
+                let mut len: u64 = len;
+                if last_byte(&mut file, len)? == Some(b']') {
+                    len -= 1;
+                    file.set_len(len)?;
+                }
+                match last_byte(&mut file, len)? {
+                    Some(b'[') => false,
+                    Some(..) => true,
+                    None => {
+                        file.write_all(b"[")?;
+                        false
+                    }
+                }
+            }
+            FileFormat::SenmlCbor => {
+                let mut contents: Vec<u8> = vec![];
+                file.read_to_end(&mut contents)?;
+
+                if contents.is_empty() {
+                    file.write_all(&[CBOR_ARRAY_START])?;
+                    false
+                } else if ciborium::from_reader::<ciborium::Value, _>(&contents[..]).is_ok() {
+                    // only a finished pack parses, as a run that stopped early never wrote the break
+                    file.set_len(len - 1)?;
+                    len > 2
+                } else {
+                    len > 1
+                }
+            }
+            _ => len > 0,
+        };
+
+        file.seek(SeekFrom::End(0))?;
+
+        Ok(OutputFile {
+            path,
+            format,
+            timestamps,
+            writer: BufWriter::new(file),
+            has_readings,
+            senml_base: None,
+        })
+    }
+
+    /// how long the file is with everything written so far, for a checkpoint to rewind it to
+    pub fn len_on_disk(&mut self) -> Result<u64> {
+        self.writer.flush()?;
+
+        Ok(self.writer.get_ref().metadata()?.len())
+    }
+
+    pub fn write<'a>(
+        &mut self,
+        readings: impl IntoIterator<Item = &'a SensorOutput>,
+    ) -> Result<()> {
+        for reading in readings {
+            self.write_reading(reading)?;
+            self.has_readings = true;
+        }
+
+        // flushed with every batch so the file keeps up with the partitions
+        self.writer.flush()?;
+
+        Ok(())
+    }
+
+    fn write_reading(&mut self, reading: &SensorOutput) -> Result<()> {
+        let first: bool = !self.has_readings;
+
+        match self.format {
+            FileFormat::Csv => {
+                let mut writer: csv::Writer<Vec<u8>> = csv::WriterBuilder::new()
+                    .has_headers(first)
+                    .from_writer(vec![]);
+                writer.serialize(FormattedReading::new(
+                    reading,
+                    &self.timestamps,
+                    &Timezone::Utc,
+                ))?;
+
+                self.writer.write_all(&writer.into_inner()?)?;
+            }
+            FileFormat::Json => {
+                if !first {
+                    self.writer.write_all(b",")?;
+                }
+                serde_json::to_writer(
+                    &mut self.writer,
+                    &FormattedReading::new(reading, &self.timestamps, &Timezone::Utc),
+                )?;
+            }
+            FileFormat::Influx => self.write_frame(reading, PayloadFormat::Influx)?,
+            FileFormat::Msgpack => self.write_frame(reading, PayloadFormat::Msgpack)?,
+            FileFormat::Cbor => self.write_frame(reading, PayloadFormat::Cbor)?,
+            FileFormat::Protobuf => self.write_frame(reading, PayloadFormat::Protobuf)?,
+            FileFormat::SenmlJson => {
+                if !first {
+                    self.writer.write_all(b",")?;
+                }
+                serde_json::to_writer(
+                    &mut self.writer,
+                    &Record::new(reading, self.senml_base.as_ref()),
+                )?;
+                self.senml_base.get_or_insert(reading.timestamp);
+            }
+            FileFormat::SenmlCbor => {
+                ciborium::into_writer(
+                    &Record::new(reading, self.senml_base.as_ref()).to_cbor(),
+                    &mut self.writer,
+                )?;
+                self.senml_base.get_or_insert(reading.timestamp);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// the streaming formats are framed the same way as on a socket
+    fn write_frame(&mut self, reading: &SensorOutput, format: PayloadFormat) -> Result<()> {
+        self.writer
+            .write_all(&encode_frame(reading, &format, &self.timestamps)?)?;
+
+        Ok(())
+    }
+
+    /// closes off the json array or senml pack and hands back the path, ready to be compressed
+    pub fn finish(mut self) -> Result<PathBuf> {
+        match self.format {
+            FileFormat::Json | FileFormat::SenmlJson => self.writer.write_all(b"]")?,
+            FileFormat::SenmlCbor => self.writer.write_all(&[CBOR_BREAK])?,
+            _ => (),
+        }
+
+        self.writer.flush()?;
+
+        Ok(self.path)
+    }
+}
+
+fn last_byte(file: &mut File, len: u64) -> Result<Option<u8>> {
+    if len == 0 {
+        return Ok(None);
+    }
+
+    let mut byte = [0u8];
+    file.seek(SeekFrom::Start(len - 1))?;
+    file.read_exact(&mut byte)?;
+
+    Ok(Some(byte[0]))
+}
+
+fn file_name(format: &FileFormat) -> &'static str {
+    match format {
+        FileFormat::Csv => "output.csv",
+        FileFormat::Json => "output.json",
+        FileFormat::Influx => "output.lp",
+        FileFormat::Msgpack => "output.msgpack",
+        FileFormat::Cbor => "output.cbor",
+        FileFormat::Protobuf => "output.pb",
+        FileFormat::SenmlJson => "output.senml.json",
+        FileFormat::SenmlCbor => "output.senml.cbor",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::TemperatureUnit;
+    use crate::compression::compress_file;
+    use crate::sensor::Unit;
+
+    /// an empty directory of its own for each test, so they can run in parallel
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "sensor_simulator_output_file_{}_{}",
+            name,
+            std::process::id()
+        ));
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn readings(from: i64, count: i64) -> Vec<SensorOutput> {
+        (from..from + count)
+            .map(|second| SensorOutput {
+                id: "TMPabc".to_string(),
+                timestamp: UtcDateTime::from_unix_timestamp(1_738_315_800 + second).unwrap(),
+                value: second as f32,
+                unit: Unit::TemperatureUnit(TemperatureUnit::Celsius),
+                symbol: "°C".to_string(),
+            })
+            .collect()
+    }
+
+    fn json_values(path: &Path) -> Vec<f64> {
+        let array: Vec<serde_json::Value> =
+            serde_json::from_slice(&std::fs::read(path).unwrap()).unwrap();
+        array
+            .iter()
+            .map(|reading| reading["value"].as_f64().unwrap())
+            .collect()
+    }
+
+    /// one run of the sensor writing `readings` to the file, then finishing it
+    fn run(file: Result<OutputFile>, readings: &[SensorOutput]) -> PathBuf {
+        let mut file = file.unwrap();
+        file.write(readings).unwrap();
+        file.finish().unwrap()
+    }
+
+    #[test]
+    fn resume_appends_to_a_finished_json_array() {
+        let dir = test_dir("json");
+
+        run(
+            OutputFile::create(&dir, FileFormat::Json, TimestampFormat::Plain),
+            &readings(0, 2),
+        );
+        let path = run(
+            OutputFile::reopen(&dir, FileFormat::Json, TimestampFormat::Plain, None),
+            &readings(2, 1),
+        );
+
+        assert_eq!(json_values(&path), [0.0, 1.0, 2.0]);
+    }
+
+    #[test]
+    fn resume_decompresses_a_finished_file() {
+        let dir = test_dir("compressed");
+
+        let path = run(
+            OutputFile::create(&dir, FileFormat::Json, TimestampFormat::Plain),
+            &readings(0, 2),
+        );
+        compress_file(&path, &Compression::Gzip, None).unwrap();
+        run(
+            OutputFile::reopen(&dir, FileFormat::Json, TimestampFormat::Plain, None),
+            &readings(2, 2),
+        );
+
+        assert!(!dir.join("output.json.gz").exists());
+        assert_eq!(json_values(&path), [0.0, 1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn resume_keeps_a_single_csv_header() {
+        let dir = test_dir("csv");
+
+        run(
+            OutputFile::create(&dir, FileFormat::Csv, TimestampFormat::Plain),
+            &readings(0, 2),
+        );
+        let path = run(
+            OutputFile::reopen(&dir, FileFormat::Csv, TimestampFormat::Plain, None),
+            &readings(2, 1),
+        );
+
+        let contents = std::fs::read_to_string(path).unwrap();
+        assert_eq!(contents.lines().count(), 4);
+        assert_eq!(contents.matches("id,timestamp").count(), 1);
+    }
+
+    #[test]
+    fn resume_closes_an_unfinished_senml_pack() {
+        let dir = test_dir("unfinished");
+
+        // stopped without finishing, so the pack was never closed
+        let mut file =
+            OutputFile::create(&dir, FileFormat::SenmlJson, TimestampFormat::Plain).unwrap();
+        file.write(&readings(0, 2)).unwrap();
+        drop(file);
+
+        let path = run(
+            OutputFile::reopen(&dir, FileFormat::SenmlJson, TimestampFormat::Plain, None),
+            &readings(2, 1),
+        );
+
+        let pack: Vec<serde_json::Value> =
+            serde_json::from_slice(&std::fs::read(path).unwrap()).unwrap();
+        assert_eq!(pack.len(), 3);
+        // the resumed run starts with a base record of its own
+        assert_eq!(pack[2]["bn"], "TMPabc");
+        assert_eq!(pack[2]["bt"], 1_738_315_802.0);
+    }
+
+    #[test]
+    fn resume_reopens_a_finished_senml_cbor_pack() {
+        let dir = test_dir("cbor");
+
+        run(
+            OutputFile::create(&dir, FileFormat::SenmlCbor, TimestampFormat::Plain),
+            &readings(0, 2),
+        );
+        let path = run(
+            OutputFile::reopen(&dir, FileFormat::SenmlCbor, TimestampFormat::Plain, None),
+            &readings(2, 2),
+        );
+
+        let pack: ciborium::Value =
+            ciborium::from_reader(&std::fs::read(path).unwrap()[..]).unwrap();
+        assert_eq!(pack.as_array().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn checkpoint_cuts_off_what_was_written_after_it() {
+        let dir = test_dir("checkpoint");
+
+        let mut file = OutputFile::create(&dir, FileFormat::Json, TimestampFormat::Plain).unwrap();
+        file.write(&readings(0, 2)).unwrap();
+        let checkpointed_len = file.len_on_disk().unwrap();
+        file.write(&readings(2, 3)).unwrap();
+        drop(file);
+
+        let path = run(
+            OutputFile::reopen(
+                &dir,
+                FileFormat::Json,
+                TimestampFormat::Plain,
+                Some(checkpointed_len),
+            ),
+            &readings(2, 1),
+        );
+
+        assert_eq!(json_values(&path), [0.0, 1.0, 2.0]);
+    }
+
+    #[test]
+    fn reopen_without_a_file_starts_one() {
+        let dir = test_dir("missing");
+
+        let path = run(
+            OutputFile::reopen(&dir, FileFormat::Json, TimestampFormat::Plain, None),
+            &readings(0, 1),
+        );
+
+        assert_eq!(json_values(&path), [0.0]);
+    }
+}
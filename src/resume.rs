@@ -0,0 +1,253 @@
+use crate::compression::open_decompressed;
+use crate::timestamp::parse_plain;
+use std::path::{Path, PathBuf};
+use time::UtcDateTime;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// where a stopped sensor left off, rebuilt from the partitions it wrote
+#[derive(Debug)]
+pub struct ResumeState {
+    pub current_file_partition: usize,
+    pub rows_in_current_file: usize,
+    pub last_value: f32,
+    pub last_timestamp: UtcDateTime,
+}
+
+#[derive(Debug)]
+pub struct Partition {
+    pub number: usize,
+    pub path: PathBuf,
+    pub closed: bool,
+}
+
+/// all the partition files written by the sensor with this id, in partition order.
+/// closed partitions may have been compressed, so `.csv.gz` and `.csv.zst` count too
+pub fn find_partitions(dir: &Path, id: &str) -> Result<Vec<Partition>> {
+    let mut partitions: Vec<Partition> = vec![];
+
+    if !dir.is_dir() {
+        return Ok(partitions);
+    }
+
+    let prefix = format!("{}_output_", id);
+
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = match path.file_name().and_then(|n| n.to_str()) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+
+        let rest = match name.strip_prefix(&prefix) {
+            Some(rest) => rest,
+            None => continue,
+        };
+
+        let (number, closed) = if let Some(n) = rest.strip_suffix(".csv") {
+            (n, false)
+        } else if let Some(n) = rest
+            .strip_suffix(".csv.gz")
+            .or_else(|| rest.strip_suffix(".csv.zst"))
+        {
+            (n, true)
+        } else {
+            continue;
+        };
+
+        if let Ok(number) = number.parse::<usize>() {
+            partitions.push(Partition {
+                number,
+                path,
+                closed,
+            });
+        }
+    }
+
+    partitions.sort_by_key(|p| p.number);
+
+    Ok(partitions)
+}
+
+/// paths of every partition that belongs to the sensor with this id
+pub fn existing_files(dir: &Path, id: &str) -> Result<Vec<PathBuf>> {
+    Ok(find_partitions(dir, id)?
+        .into_iter()
+        .map(|p| p.path)
+        .collect())
+}
+
+/// reads the partitions written by `id` and works out where to carry on from.
+/// returns None if the sensor never wrote any readings to this directory.
+///
+/// a last row cut short by the process dying mid write is skipped, so the run carries on from the last whole one
+pub fn scan(dir: &Path, id: &str) -> Result<Option<ResumeState>> {
+    let partitions = find_partitions(dir, id)?;
+
+    // walk backwards so an empty last partition doesn't stop us finding the last reading
+    for partition in partitions.iter().rev() {
+        let mut reader = csv::ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(open_decompressed(&partition.path)?);
+
+        let headers = reader.headers()?.clone();
+        // created but never written to, not even the header
+        if headers.is_empty() {
+            continue;
+        }
+        let value_column = headers
+            .iter()
+            .position(|h| h == "value")
+            .ok_or_else(|| format!("{} has no value column", partition.path.display()))?;
+        let timestamp_column = headers
+            .iter()
+            .position(|h| h == "timestamp")
+            .ok_or_else(|| format!("{} has no timestamp column", partition.path.display()))?;
+
+        let mut rows: usize = 0;
+        let mut last_row: Option<csv::StringRecord> = None;
+
+        for record in reader.records() {
+            let record = record?;
+            if record.len() < headers.len() {
+                continue;
+            }
+            rows += 1;
+            last_row = Some(record);
+        }
+
+        let last_row = match last_row {
+            Some(row) => row,
+            None => continue,
+        };
+
+        let last_partition = partitions.last().unwrap();
+
+        // closed partitions are never appended to again, so carry on in a fresh one
+        let (current_file_partition, rows_in_current_file) = if last_partition.closed {
+            (last_partition.number + 1, 0)
+        } else if last_partition.number == partition.number {
+            (partition.number, rows)
+        } else {
+            (last_partition.number, 0)
+        };
+
+        return Ok(Some(ResumeState {
+            current_file_partition,
+            rows_in_current_file,
+            last_value: last_row[value_column].parse()?,
+            last_timestamp: parse_plain(&last_row[timestamp_column])?,
+        }));
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::Compression;
+    use crate::compression::compress_file;
+    use crate::timestamp::plain;
+
+    const HEADER: &str = "id,timestamp,value,unit,symbol\n";
+
+    /// an empty directory of its own for each test, so they can run in parallel
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "sensor_simulator_resume_{}_{}",
+            name,
+            std::process::id()
+        ));
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn row(second: u32, value: f32) -> String {
+        format!(
+            "TMPabc,2025-01-31 09:30:{:02},{},Celsius,°C\n",
+            second, value
+        )
+    }
+
+    #[test]
+    fn nothing_written_is_nothing_to_resume() {
+        let dir = test_dir("nothing");
+        std::fs::write(
+            dir.join("TMPxyz_output_0.csv"),
+            HEADER.to_string() + &row(0, 1.0),
+        )
+        .unwrap();
+
+        assert!(scan(&dir, "TMPabc").unwrap().is_none());
+    }
+
+    #[test]
+    fn live_partition_is_carried_on() {
+        let dir = test_dir("live");
+        std::fs::write(
+            dir.join("TMPabc_output_0.csv"),
+            HEADER.to_string() + &row(0, 21.0) + &row(1, 21.5),
+        )
+        .unwrap();
+
+        let state = scan(&dir, "TMPabc").unwrap().unwrap();
+
+        assert_eq!(state.current_file_partition, 0);
+        assert_eq!(state.rows_in_current_file, 2);
+        assert_eq!(state.last_value, 21.5);
+        assert_eq!(plain(&state.last_timestamp), "2025-01-31 09:30:01");
+    }
+
+    #[test]
+    fn compressed_partition_starts_a_new_one() {
+        let dir = test_dir("compressed");
+        let partition = dir.join("TMPabc_output_0.csv");
+        std::fs::write(
+            &partition,
+            HEADER.to_string() + &row(0, 21.0) + &row(1, 22.5),
+        )
+        .unwrap();
+        compress_file(&partition, &Compression::Gzip, None).unwrap();
+        assert!(!partition.exists());
+
+        let state = scan(&dir, "TMPabc").unwrap().unwrap();
+
+        assert_eq!(state.current_file_partition, 1);
+        assert_eq!(state.rows_in_current_file, 0);
+        assert_eq!(state.last_value, 22.5);
+    }
+
+    #[test]
+    fn empty_live_partition_falls_back_to_the_one_before() {
+        let dir = test_dir("empty");
+        let closed = dir.join("TMPabc_output_0.csv");
+        std::fs::write(&closed, HEADER.to_string() + &row(0, 21.0) + &row(1, 23.0)).unwrap();
+        compress_file(&closed, &Compression::Zstd, None).unwrap();
+        std::fs::write(dir.join("TMPabc_output_1.csv"), "").unwrap();
+
+        let state = scan(&dir, "TMPabc").unwrap().unwrap();
+
+        assert_eq!(state.current_file_partition, 1);
+        assert_eq!(state.rows_in_current_file, 0);
+        assert_eq!(state.last_value, 23.0);
+        assert_eq!(plain(&state.last_timestamp), "2025-01-31 09:30:01");
+    }
+
+    #[test]
+    fn torn_last_row_is_skipped() {
+        let dir = test_dir("torn");
+        std::fs::write(
+            dir.join("TMPabc_output_0.csv"),
+            HEADER.to_string() + &row(0, 21.0) + &row(1, 21.25) + "TMPabc,2025-01-31 09:30:02,21.",
+        )
+        .unwrap();
+
+        let state = scan(&dir, "TMPabc").unwrap().unwrap();
+
+        assert_eq!(state.rows_in_current_file, 2);
+        assert_eq!(state.last_value, 21.25);
+        assert_eq!(plain(&state.last_timestamp), "2025-01-31 09:30:01");
+    }
+}
@@ -0,0 +1,178 @@
+use crate::args::{HumidityUnit, PressureUnit, TemperatureUnit};
+use crate::sensor::{SensorOutput, Unit};
+use ciborium::Value;
+use serde::Serialize;
+use time::UtcDateTime;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// rfc 8428 section 6 - cbor packs use integer labels instead of the json names
+const BASE_NAME: i64 = -2;
+const BASE_TIME: i64 = -3;
+const BASE_UNIT: i64 = -4;
+const VALUE: i64 = 2;
+const TIME: i64 = 6;
+
+/// one record of a senml pack (rfc 8428). the first record of a pack carries the base name, base time and base
+/// unit, and the rest only their value and their time relative to it
+#[derive(Debug, Serialize)]
+pub struct Record {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bn: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bt: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bu: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    t: Option<f64>,
+    v: f32,
+}
+
+impl Record {
+    /// a record for the reading. with no base it starts a pack, otherwise it is timed relative to the base time
+    pub fn new(reading: &SensorOutput, base: Option<&UtcDateTime>) -> Record {
+        let (unit, scale) = senml_unit(&reading.unit);
+        let value: f32 = (reading.value as f64 * scale) as f32;
+
+        match base {
+            None => Record {
+                bn: Some(reading.id.clone()),
+                bt: Some(reading.timestamp.unix_timestamp_nanos() as f64 / 1e9),
+                bu: Some(unit),
+                t: None,
+                v: value,
+            },
+            Some(base) => Record {
+                bn: None,
+                bt: None,
+                bu: None,
+                t: Some((reading.timestamp - *base).as_seconds_f64()),
+                v: value,
+            },
+        }
+    }
+
+    pub fn to_cbor(&self) -> Value {
+        let mut map: Vec<(Value, Value)> = vec![];
+
+        if let Some(bn) = &self.bn {
+            map.push((BASE_NAME.into(), bn.as_str().into()));
+        }
+        if let Some(bt) = self.bt {
+            map.push((BASE_TIME.into(), bt.into()));
+        }
+        if let Some(bu) = self.bu {
+            map.push((BASE_UNIT.into(), bu.into()));
+        }
+        if let Some(t) = self.t {
+            map.push((TIME.into(), t.into()));
+        }
+        map.push((VALUE.into(), (self.v as f64).into()));
+
+        Value::Map(map)
+    }
+}
+
+/// the readings of one sensor as a pack, e.g.
+/// `[{"bn":"TMPx1y","bt":1700000000.5,"bu":"Cel","v":21.5},{"t":1.0,"v":21.6}]`
+pub fn pack<'a>(readings: impl IntoIterator<Item = &'a SensorOutput>) -> Vec<Record> {
+    let mut records: Vec<Record> = vec![];
+    let mut base: Option<UtcDateTime> = None;
+
+    for reading in readings {
+        records.push(Record::new(reading, base.as_ref()));
+        base.get_or_insert(reading.timestamp);
+    }
+
+    records
+}
+
+pub fn to_json(records: &[Record]) -> Result<Vec<u8>> {
+    Ok(serde_json::to_vec(records)?)
+}
+
+pub fn to_cbor(records: &[Record]) -> Result<Vec<u8>> {
+    let pack = Value::Array(records.iter().map(Record::to_cbor).collect());
+
+    let mut cbor: Vec<u8> = vec![];
+    ciborium::into_writer(&pack, &mut cbor)?;
+
+    Ok(cbor)
+}
+
+/// the unit from the senml units registry, and what to multiply the value by to get it in that unit. bar and
+/// grams aren't registered, so those values are converted to pascals and kilograms
+fn senml_unit(unit: &Unit) -> (&'static str, f64) {
+    match unit {
+        Unit::TemperatureUnit(TemperatureUnit::Celsius) => ("Cel", 1.0),
+        Unit::TemperatureUnit(TemperatureUnit::Kelvin) => ("K", 1.0),
+        Unit::PressureUnit(PressureUnit::Pascal) => ("Pa", 1.0),
+        Unit::PressureUnit(PressureUnit::Bar) => ("Pa", 100_000.0),
+        Unit::HumidityUnit(HumidityUnit::Absolute) => ("kg/m3", 0.001),
+        Unit::HumidityUnit(HumidityUnit::Relative) => ("%RH", 1.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(unix_millis: i128, value: f32, unit: Unit) -> SensorOutput {
+        SensorOutput {
+            id: "TMPabc".to_string(),
+            timestamp: UtcDateTime::from_unix_timestamp_nanos(unix_millis * 1_000_000).unwrap(),
+            value,
+            unit,
+            symbol: String::new(),
+        }
+    }
+
+    #[test]
+    fn only_the_first_record_carries_the_base_fields() {
+        let celsius = Unit::TemperatureUnit(TemperatureUnit::Celsius);
+        let readings = [
+            reading(1_700_000_000_500, 21.5, celsius.clone()),
+            reading(1_700_000_001_500, 21.75, celsius.clone()),
+            reading(1_700_000_003_000, 22.0, celsius),
+        ];
+
+        assert_eq!(
+            String::from_utf8(to_json(&pack(&readings)).unwrap()).unwrap(),
+            r#"[{"bn":"TMPabc","bt":1700000000.5,"bu":"Cel","v":21.5},{"t":1.0,"v":21.75},{"t":2.5,"v":22.0}]"#
+        );
+    }
+
+    #[test]
+    fn unregistered_units_are_converted() {
+        let records = pack([&reading(0, 1.5, Unit::PressureUnit(PressureUnit::Bar))]);
+
+        assert_eq!(records[0].bu, Some("Pa"));
+        assert_eq!(records[0].v, 150_000.0);
+    }
+
+    #[test]
+    fn cbor_packs_use_integer_labels() {
+        let celsius = Unit::TemperatureUnit(TemperatureUnit::Celsius);
+        let readings = [
+            reading(0, 21.5, celsius.clone()),
+            reading(1_000, 21.75, celsius),
+        ];
+
+        let pack: Value = ciborium::from_reader(&to_cbor(&pack(&readings)).unwrap()[..]).unwrap();
+        let records = pack.as_array().unwrap();
+        let labels = |record: &Value| -> Vec<i128> {
+            record
+                .as_map()
+                .unwrap()
+                .iter()
+                .map(|(label, _)| label.as_integer().unwrap().into())
+                .collect()
+        };
+
+        assert_eq!(
+            labels(&records[0]),
+            [BASE_NAME, BASE_TIME, BASE_UNIT, VALUE].map(i128::from)
+        );
+        assert_eq!(labels(&records[1]), [TIME, VALUE].map(i128::from));
+    }
+}
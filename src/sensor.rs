@@ -1,85 +1,48 @@
-use crate::args::{Args, FileFormat, HumidityUnit, PressureUnit, Sensor, TemperatureUnit};
-use crate::utils::{create_id, serialize_timestamp, serialize_unit, setup_db};
-use csv;
-use rand::{self, Rng};
+use crate::args::{
+    Args, BooleanArg, Compression, FaultPoint, FileFormat, HumidityUnit, OverflowPolicy,
+    PressureUnit, Sensor, TemperatureUnit,
+};
+use crate::buffer::RingBuffer;
+use crate::checkpoint::{CHECKPOINT_VERSION, Checkpoint, checkpoint_path, rewind_partitions, save};
+use crate::compression::compress_file;
+use crate::journal::{append_batch, recover_directory};
+use crate::output_file::OutputFile;
+use crate::resume::{existing_files, scan};
+use crate::sinks::{Sink, build_sinks};
+use crate::timestamp::{TimestampFormat, parse_plain, plain, serialize_plain};
+use crate::utils::{create_id, serialize_unit, setup_db};
+use rand::{self, Rng, SeedableRng};
 use rand_distr::{Distribution, Normal};
+use rand_pcg::Pcg64;
 use serde::Serialize;
-use serde_json;
-use std::fmt;
-use std::fs::{File, OpenOptions};
-use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use time::UtcDateTime;
 
+// a partition is rotated once it holds this many full batches' worth of rows
 const MAX_BATCHES_PER_FILE: usize = 10;
-const APPEND_BATCH_SIZE: usize = 250;
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
 #[derive(Debug, Serialize)]
-struct SensorOutput {
-    id: String,
-    #[serde(serialize_with = "serialize_datetime")]
-    timestamp: UtcDateTime,
-    value: f32,
-    unit: Unit,
-    symbol: String,
-}
-
-impl fmt::Display for SensorOutput {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "[{:02}:{:02}:{:02}] Sensor {}: {:.2}{}",
-            self.timestamp.hour(),
-            self.timestamp.minute(),
-            self.timestamp.second(),
-            self.id,
-            self.value,
-            self.symbol
-        )
-    }
-}
-
-fn serialize_datetime<S>(
-    datetime: &UtcDateTime,
-    serializer: S,
-) -> core::result::Result<S::Ok, S::Error>
-where
-    S: serde::Serializer,
-{
-    let mut w: Vec<u8> = Vec::new();
-
-    match write!(
-        w,
-        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
-        datetime.year(),
-        datetime.month() as u8,
-        datetime.day(),
-        datetime.hour(),
-        datetime.minute(),
-        datetime.second(),
-    ) {
-        Ok(_) => {
-            let s = String::from_utf8(w).unwrap();
-            serializer.serialize_str(&s)
-        }
-        Err(e) => {
-            println!("error writing datetime to string: {}", e);
-            Err(serde::ser::Error::custom(e))
-        }
-    }
+pub struct SensorOutput {
+    pub id: String,
+    #[serde(serialize_with = "serialize_plain")]
+    pub timestamp: UtcDateTime,
+    pub value: f32,
+    pub unit: Unit,
+    pub symbol: String,
 }
 
 #[derive(Clone, Debug, Serialize)]
+#[allow(clippy::enum_variant_names)]
 pub enum Unit {
     TemperatureUnit(TemperatureUnit),
     PressureUnit(PressureUnit),
     HumidityUnit(HumidityUnit),
 }
 
-#[derive(Debug, Serialize)]
-pub struct SensorID {}
-
 #[derive(Debug, Serialize)]
 enum SensorType {
     Temperature(String),
@@ -87,36 +50,82 @@ enum SensorType {
     Humidity(String),
 }
 
+/// running totals that can be read from other threads while the sensor runs, e.g. for `/metrics`
+#[derive(Debug, Default)]
+pub struct SensorStats {
+    pub readings_generated: AtomicU64,
+    pub sink_errors: AtomicU64,
+}
+
 #[derive(Debug)]
 pub struct EnvironmentalSensor {
+    #[allow(dead_code)]
     category: SensorType,
     id: String,
-    random_seed: u16,
-    outputs: Vec<SensorOutput>,
+    random_seed: u64,
+    rng: Pcg64,
+    outputs: RingBuffer<SensorOutput>,
+    overflow_policy: OverflowPolicy,
+    readings_generated: u64,
+    last_value: Option<f32>,
+    last_timestamp: Option<UtcDateTime>,
     unit: Unit,
     unit_symbol: &'static str,
     base_value: f64,
     drift_std: f64,
     file_path: Option<String>,
     file_format: FileFormat,
+    file_timestamps: TimestampFormat,
+    output_file: Option<OutputFile>,
+    compression: Compression,
+    compress_level: Option<i32>,
+    append_batch_size: usize,
+    inject_fault: Option<FaultPoint>,
     current_file_partition: usize,
-    batches_in_current_file: usize,
+    rows_in_current_file: usize,
+    resume: bool,
+    overwrite: bool,
+    checkpoint_every: Option<std::time::Duration>,
+    restored_from: Option<Checkpoint>,
     to_sql: bool,
     sql_conn: Option<rusqlite::Connection>,
+    sinks: Vec<Box<dyn Sink>>,
+    stats: Arc<SensorStats>,
 }
 
 impl EnvironmentalSensor {
-    fn generate_output(&mut self) {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+    pub fn unit(&self) -> &Unit {
+        &self.unit
+    }
+    pub fn stats(&self) -> Arc<SensorStats> {
+        self.stats.clone()
+    }
+    /// sends readings somewhere that isn't set up from the command line, e.g. the http server's feeds
+    pub fn add_sink(&mut self, sink: Box<dyn Sink>) {
+        self.sinks.push(sink);
+    }
+    fn generate_output(&mut self) -> Result<()> {
         let timestamp: UtcDateTime = time::UtcDateTime::now();
         // let mean = self.base_value.clone();
         let mean: f64 = 0.0;
-        let std: f64 = self.drift_std.clone();
+        let std: f64 = self.drift_std;
 
-        let change: f32 = Normal::new(mean, std).unwrap().sample(&mut rand::rng()) as f32;
-        let value: f32 = match self.outputs.last() {
-            Some(v) => v.value + change,
+        // all randomness comes from the sensor's own rng so a checkpoint can restore it exactly
+        let change: f32 = Normal::new(mean, std).unwrap().sample(&mut self.rng) as f32;
+        // the last value is kept separately from the outputs, which are cleared every time they are flushed to file
+        let value: f32 = match self.last_value {
+            Some(v) => v + change,
             None => self.base_value as f32 + change,
         };
+        self.last_value = Some(value);
+        self.last_timestamp = Some(timestamp);
+        self.readings_generated += 1;
+        self.stats
+            .readings_generated
+            .store(self.readings_generated, Ordering::Relaxed);
 
         let output: SensorOutput = SensorOutput {
             id: (self.id.clone()),
@@ -126,128 +135,304 @@ impl EnvironmentalSensor {
             symbol: (self.unit_symbol.to_string()),
         };
 
+        self.buffer_output(output)
+    }
+    fn buffer_output(&mut self, output: SensorOutput) -> Result<()> {
+        if self.outputs.is_full() {
+            match self.overflow_policy {
+                // the ring buffer drops the oldest reading itself when it is pushed to
+                OverflowPolicy::DropOldest => (),
+                OverflowPolicy::Flush => self.log_data()?,
+                OverflowPolicy::Block => {
+                    let mut wait = std::time::Duration::from_secs(1);
+                    while let Err(e) = self.log_data() {
+                        eprintln!(
+                            "buffer is full and could not be written to file ({}). waiting {}s before trying again",
+                            e,
+                            wait.as_secs()
+                        );
+                        std::thread::sleep(wait);
+                        wait = (wait * 2).min(std::time::Duration::from_secs(60));
+                    }
+                }
+            }
+        }
+
         self.outputs.push(output);
+
+        Ok(())
     }
     pub fn run_sensor(&mut self, interval: &i32, duration: &i32) -> Result<()> {
         let duration: i64 = *duration as i64;
         let interval: i64 = *interval as i64;
 
         let mut duration = time::Duration::new(duration, 0);
+        let mut last_checkpoint = std::time::Instant::now();
+
+        if let Some(file_path) = self.file_path.clone() {
+            let dir: &Path = Path::new(&file_path);
+            // taken before the directory is prepared, which uses the checkpoint up
+            let output_len: Option<u64> = self.restored_from.as_ref().map(|c| c.output_len);
+
+            // repair anything a previous run left half written before adding to the directory
+            recover_directory(dir)?;
+            self.prepare_output_directory()?;
+
+            // a run carried on from earlier readings keeps them in the output file as well as the partitions
+            self.output_file = Some(if self.resume || output_len.is_some() {
+                OutputFile::reopen(
+                    dir,
+                    self.file_format,
+                    self.file_timestamps.clone(),
+                    output_len,
+                )?
+            } else {
+                OutputFile::create(dir, self.file_format, self.file_timestamps.clone())?
+            });
+        }
 
         while duration.as_seconds_f32() > 0.0 {
             // might need to checkpoint the timestamp here and use time since this point for the interval to avoid adding time taken for the loop to run being added to the interval time - relevent for very short intervals and very long runs (will add up if set to run for a week)
 
-            self.generate_output();
-
-            // call a function here that formats and prints the output - implement repr on SensorOutput?
-            self.read_out();
+            self.generate_output()?;
 
             if self.to_sql {
                 self.insert_to_db()?;
             }
 
-            duration = duration - time::Duration::new(interval, 0);
+            self.send_to_sinks();
+
+            duration -= time::Duration::new(interval, 0);
 
             // wait for the interval
             std::thread::sleep(std::time::Duration::new(interval as u64, 0));
 
-            if self.outputs.len() % APPEND_BATCH_SIZE == 0 {
+            if self.file_path.is_some() && self.outputs.len() >= self.append_batch_size {
+                self.log_data()?;
+            }
+
+            if let Some(every) = self.checkpoint_every
+                && last_checkpoint.elapsed() >= every
+            {
+                self.write_checkpoint(interval, &duration)?;
+                last_checkpoint = std::time::Instant::now();
+            }
+        }
+
+        for sink in self.sinks.iter_mut() {
+            if let Err(e) = sink.close() {
+                eprintln!("{} sink did not close cleanly: {}", sink.name(), e);
+            }
+        }
+
+        if self.file_path.is_some() {
+            // whatever is still buffered is flushed like any other batch, so the partitions and the output file
+            // both end up with every reading
+            if !self.outputs.is_empty() {
                 self.log_data()?;
             }
+            if let Some(output_file) = self.output_file.take() {
+                compress_file(
+                    &output_file.finish()?,
+                    &self.compression,
+                    self.compress_level,
+                )?;
+            }
+            self.close_partition()?;
         }
 
-        match self.file_path {
-            Some(..) => self.write_all_to_file()?,
-            None => (),
+        // a finished run has nothing left to carry on from
+        let checkpoint: PathBuf = checkpoint_path(&self.checkpoint_dir(), &self.id);
+        if checkpoint.exists() {
+            std::fs::remove_file(checkpoint)?;
         }
         Ok(())
     }
-    fn read_out(&self) {
-        let mut outputs_copy: Vec<&SensorOutput> = Vec::from_iter(self.outputs.iter().clone());
-        let most_recent_reading: Option<&SensorOutput> = outputs_copy.pop();
-        println!("{}", most_recent_reading.unwrap())
+    fn send_to_sinks(&mut self) {
+        let most_recent_reading: &SensorOutput = self.outputs.last().unwrap();
+
+        // a sink failing shouldn't end a long run, so errors are reported and counted rather than returned
+        for sink in self.sinks.iter_mut() {
+            if let Err(e) = sink.send(most_recent_reading) {
+                self.stats.sink_errors.fetch_add(1, Ordering::Relaxed);
+                eprintln!("{} sink error: {}", sink.name(), e);
+            }
+        }
     }
-    fn write_all_to_file(&self) -> Result<()> {
-        let result = match self.file_format {
-            FileFormat::CSV => self.write_all_to_csv(),
-            FileFormat::Json => self.write_all_to_json(),
+    /// picks the run up from a checkpoint. the files are rewound to match it once the run starts
+    fn restore_checkpoint(&mut self, checkpoint: Checkpoint) -> Result<()> {
+        self.random_seed = checkpoint.random_seed;
+        self.rng = checkpoint.rng.clone();
+        self.base_value = checkpoint.base_value;
+        self.last_value = checkpoint.last_value;
+        self.last_timestamp = match &checkpoint.last_timestamp {
+            Some(timestamp) => Some(parse_plain(timestamp)?),
+            None => None,
         };
+        self.readings_generated = checkpoint.readings_generated;
+        self.file_path = checkpoint.file_path.clone();
+        self.current_file_partition = checkpoint.current_file_partition;
+        self.rows_in_current_file = checkpoint.rows_in_current_file;
 
-        result
-    }
-    fn write_all_to_csv(&self) -> Result<()> {
-        let path = std::path::Path::new(self.file_path.as_ref().unwrap()).join("output.csv");
-        let mut writer: csv::Writer<std::fs::File> = csv::Writer::from_path(path)?;
+        note!(
+            "restored sensor {} from its checkpoint after {} readings, {} seconds left to run",
+            self.id,
+            self.readings_generated,
+            checkpoint.remaining_seconds
+        );
 
-        for reading in &self.outputs {
-            match writer.serialize(reading) {
-                Ok(..) => (),
-                Err(e) => println!("error during csv serialization: {}", e),
-            };
+        self.restored_from = Some(checkpoint);
+
+        Ok(())
+    }
+    /// checkpoints go next to the partitions, or in the working directory when not writing to file
+    fn checkpoint_dir(&self) -> PathBuf {
+        match &self.file_path {
+            Some(file_path) => PathBuf::from(file_path),
+            None => PathBuf::from("."),
         }
+    }
+    fn write_checkpoint(&mut self, interval: i64, remaining: &time::Duration) -> Result<()> {
+        let dir: PathBuf = self.checkpoint_dir();
+
+        // pending readings go to the partition first, so the files and the checkpoint agree on where the run is up to
+        let partition_len: u64 = if self.file_path.is_some() {
+            if !self.outputs.is_empty() {
+                self.log_data()?;
+            }
+            match std::fs::metadata(self.partition_path()) {
+                Ok(metadata) => metadata.len(),
+                Err(..) => 0,
+            }
+        } else {
+            0
+        };
+        let output_len: u64 = match &mut self.output_file {
+            Some(output_file) => output_file.len_on_disk()?,
+            None => 0,
+        };
 
-        writer.flush()?;
+        let last_timestamp: Option<String> = self.last_timestamp.as_ref().map(plain);
+
+        let checkpoint = Checkpoint {
+            version: CHECKPOINT_VERSION,
+            sensor_id: self.id.clone(),
+            random_seed: self.random_seed,
+            rng: self.rng.clone(),
+            base_value: self.base_value,
+            last_value: self.last_value,
+            last_timestamp,
+            readings_generated: self.readings_generated,
+            interval: interval as u16,
+            remaining_seconds: remaining.whole_seconds(),
+            file_path: self.file_path.clone(),
+            current_file_partition: self.current_file_partition,
+            rows_in_current_file: self.rows_in_current_file,
+            partition_len,
+            output_len,
+        };
 
-        Ok(())
+        save(&checkpoint_path(&dir, &self.id), &checkpoint)
     }
-    fn write_all_to_json(&self) -> Result<()> {
-        let path = std::path::Path::new(self.file_path.as_ref().unwrap()).join("output.json");
+    fn prepare_output_directory(&mut self) -> Result<()> {
+        let dir: PathBuf = PathBuf::from(self.file_path.as_ref().unwrap());
+
+        if let Some(checkpoint) = self.restored_from.take() {
+            return rewind_partitions(&dir, &checkpoint);
+        }
 
-        let mut file = File::create(path)?;
+        if !self.resume {
+            let existing = existing_files(&dir, &self.id)?;
+
+            if !existing.is_empty() && !self.overwrite {
+                return Err(format!(
+                    "{} already has output files from a sensor with id {}. pass --resume {} to carry on from them, or --overwrite to replace them",
+                    dir.display(),
+                    self.id,
+                    self.id
+                )
+                .into());
+            }
+
+            for path in existing {
+                std::fs::remove_file(path)?;
+            }
+
+            return Ok(());
+        }
 
-        let outputs_json = serde_json::to_string(&self.outputs)?;
+        let state = match scan(&dir, &self.id)? {
+            Some(state) => state,
+            None => {
+                return Err(format!(
+                    "no readings from sensor {} were found in {} to resume from",
+                    self.id,
+                    dir.display()
+                )
+                .into());
+            }
+        };
 
-        file.write_all(outputs_json.as_bytes())?;
+        note!(
+            "resuming sensor {} from {:.2}{} recorded at {} ({} seconds ago)",
+            self.id,
+            state.last_value,
+            self.unit_symbol,
+            plain(&state.last_timestamp),
+            (UtcDateTime::now() - state.last_timestamp).whole_seconds()
+        );
+
+        self.current_file_partition = state.current_file_partition;
+        self.rows_in_current_file = state.rows_in_current_file;
+        self.last_value = Some(state.last_value);
+        self.last_timestamp = Some(state.last_timestamp);
 
         Ok(())
     }
-    fn append_to_file(&mut self) -> Result<()> {
+    fn partition_path(&self) -> PathBuf {
         let mut filename: String = self.id.clone();
         filename.push_str("_output_");
-        filename.push_str(&self.current_file_partition.to_string().clone());
+        filename.push_str(&self.current_file_partition.to_string());
         filename.push_str(".csv");
 
-        let path = std::path::Path::new(self.file_path.as_ref().unwrap()).join(filename);
-
-        let file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(path)?;
-
-        let mut writer: csv::Writer<std::fs::File> = if file.metadata()?.len() > 0 {
-            csv::WriterBuilder::new()
-                .has_headers(false)
-                .from_writer(file)
-        } else {
-            csv::Writer::from_writer(file)
-        };
+        Path::new(self.file_path.as_ref().unwrap()).join(filename)
+    }
+    fn close_partition(&self) -> Result<()> {
+        // a partition is never appended to again once it is closed, so this is the point to compress it
+        let path = self.partition_path();
 
-        for reading in &self.outputs {
-            writer.serialize(reading)?;
+        if path.exists() {
+            compress_file(&path, &self.compression, self.compress_level)?;
         }
 
-        writer.flush()?;
-
         Ok(())
     }
     fn log_data(&mut self) -> Result<()> {
-        // add logic for rotating files when they reach max size here
-
-        if self.batches_in_current_file == MAX_BATCHES_PER_FILE {
+        // rotated by rows rather than by flushes, so the partial batches written for checkpoints don't make
+        // partitions any shorter
+        if self.rows_in_current_file >= MAX_BATCHES_PER_FILE * self.append_batch_size {
+            self.close_partition()?;
             self.current_file_partition += 1;
-            self.batches_in_current_file = 0;
+            self.rows_in_current_file = 0;
         }
 
         for attempt in 0..5 {
             match self.flush_outputs() {
-                Ok(..) => return Ok(()),
-                Err(_) if attempt < 5 => continue,
+                Ok(..) => break,
+                Err(_) if attempt < 4 => continue,
                 Err(e) => return Err(e),
             }
         }
 
-        Ok(())
+        // the batch is in the partition by now, so it is cleared even if the output file can't take it rather
+        // than being appended to the partition a second time
+        let written = match &mut self.output_file {
+            Some(output_file) => output_file.write(&self.outputs),
+            None => Ok(()),
+        };
+        self.outputs.clear();
+
+        written
     }
     fn insert_to_db(&mut self) -> Result<()> {
         let conn = self.sql_conn.as_mut().unwrap(); // we have to take the connection as mutable to be able to execute sql with it
@@ -259,7 +444,7 @@ impl EnvironmentalSensor {
             "insert into readings values (?1, ?2, ?3, ?4, ?5)",
             (
                 &most_recent_reading.id,
-                serialize_timestamp(&most_recent_reading.timestamp).unwrap(),
+                plain(&most_recent_reading.timestamp),
                 &most_recent_reading.value,
                 serialize_unit(&most_recent_reading.unit),
                 &most_recent_reading.symbol,
@@ -269,74 +454,143 @@ impl EnvironmentalSensor {
         Ok(())
     }
     fn flush_outputs(&mut self) -> Result<()> {
-        let mut filename: String = self.id.clone();
-        filename.push_str("_output_");
-        filename.push_str(&self.current_file_partition.to_string().clone());
-        filename.push_str(".csv");
-        let path: std::path::PathBuf =
-            std::path::Path::new(self.file_path.as_ref().unwrap()).join(&filename);
+        let path: PathBuf = self.partition_path();
 
-        let mut temp_file: String = filename.clone();
-        temp_file.push_str("temp");
-        let temp_file_path: std::path::PathBuf =
-            std::path::Path::new(self.file_path.as_ref().unwrap()).join(&temp_file);
+        let is_new_file: bool = match std::fs::metadata(&path) {
+            Ok(metadata) => metadata.len() == 0,
+            Err(..) => true,
+        };
 
-        if path.exists() {
-            std::fs::copy(&path, &temp_file_path)?;
+        let mut writer: csv::Writer<Vec<u8>> = csv::WriterBuilder::new()
+            .has_headers(is_new_file)
+            .from_writer(vec![]);
+
+        for reading in &self.outputs {
+            writer.serialize(reading)?;
         }
 
-        let result = self.append_to_file();
+        let payload: Vec<u8> = writer.into_inner()?;
 
-        match result {
-            Ok(..) => {
-                self.batches_in_current_file += 1;
-                self.outputs.clear();
-                _ = std::fs::remove_file(temp_file_path);
-                // im letting the error be ignored here if it occurs bc the transaction is already successful and i dont want the transaction to return error once the file is written and the vector is clear - its complete.
-                // once there are partitioned logs, if this is failing often then there could be an accumulation of temp files. cant be bothered to handle that any time soon
-            }
-            Err(e) => {
-                std::fs::copy(&temp_file_path, &path)?;
-                return Err(e);
-            }
-        }
+        // the fault is injected into the second batch so there is already committed data in the partition to protect
+        let fault: Option<FaultPoint> =
+            if self.current_file_partition == 0 && self.rows_in_current_file > 0 {
+                self.inject_fault
+            } else {
+                None
+            };
+
+        // the journal makes this all or nothing - if it errors, nothing from this batch is left in the partition
+        // and the readings are still in the buffer for the next attempt
+        append_batch(&path, &payload, fault)?;
+
+        self.rows_in_current_file += self.outputs.len();
 
         Ok(())
     }
 }
 
-pub fn build_temp_sensor(args: &Args) -> EnvironmentalSensor {
-    let file_path: Option<String> = if args.output_args.to_file == "false".to_string() {
+/// builds whichever sensor the arguments ask for, carrying on from the checkpoint if there is one. the id is
+/// worked out first, so the sinks are set up with the restored id rather than a new one
+pub fn build_sensor(
+    args: &Args,
+    sensor_type: &Sensor,
+    checkpoint: Option<Checkpoint>,
+) -> Result<EnvironmentalSensor> {
+    let id: String = sensor_id(args, sensor_type, checkpoint.as_ref())?;
+
+    let mut sensor: EnvironmentalSensor = build_sensor_with_id(args, sensor_type, id)?;
+
+    if let Some(checkpoint) = checkpoint {
+        sensor.restore_checkpoint(checkpoint)?;
+    }
+
+    Ok(sensor)
+}
+
+/// builds a new sensor under an id the caller has picked, for when it has to be unique among several sensors
+pub fn build_sensor_with_id(
+    args: &Args,
+    sensor_type: &Sensor,
+    id: String,
+) -> Result<EnvironmentalSensor> {
+    match sensor_type {
+        Sensor::Temperature { .. } => build_temp_sensor(args, sensor_type, id),
+        Sensor::Pressure { .. } => build_pressure_sensor(args, sensor_type, id),
+        Sensor::Humidity { .. } => build_humidity_sensor(args, sensor_type, id),
+    }
+}
+
+fn overflow_policy(args: &Args) -> OverflowPolicy {
+    match args.output_args.overflow_policy {
+        Some(policy) => policy,
+        None if args.output_args.to_file == "false" => OverflowPolicy::DropOldest,
+        None => OverflowPolicy::Flush,
+    }
+}
+
+fn sensor_id(args: &Args, sensor_type: &Sensor, checkpoint: Option<&Checkpoint>) -> Result<String> {
+    let prefix: &str = sensor_type.id_prefix();
+
+    if let Some(checkpoint) = checkpoint {
+        if !checkpoint.sensor_id.starts_with(prefix) {
+            return Err(format!(
+                "checkpoint is for sensor {}, which is not the sensor type that was asked for (ids start with {})",
+                checkpoint.sensor_id, prefix
+            )
+            .into());
+        }
+        return Ok(checkpoint.sensor_id.clone());
+    }
+
+    match &args.output_args.resume {
+        Some(id) => Ok(id.clone()),
+        None => {
+            let mut id = prefix.to_string();
+            id.push_str(&create_id());
+            Ok(id)
+        }
+    }
+}
+
+pub fn build_temp_sensor(
+    args: &Args,
+    sensor_type: &Sensor,
+    id: String,
+) -> Result<EnvironmentalSensor> {
+    let file_path: Option<String> = if args.output_args.to_file == "false" {
         None
     } else {
         Some(args.output_args.to_file.clone())
     };
 
-    let to_sql: bool = if args.output_args.to_file == "false".to_string() {
-        false
-    } else {
-        true
-    };
+    let to_sql: bool = matches!(args.output_args.to_sql, BooleanArg::True);
 
-    let sql_conn: Option<rusqlite::Connection> = if to_sql {
-        Some(setup_db().unwrap())
-    } else {
-        None
-    };
+    let sql_conn: Option<rusqlite::Connection> = if to_sql { Some(setup_db()?) } else { None };
 
-    let mut id = "TMP".to_string();
-    id.push_str(&create_id());
+    let overflow_policy = overflow_policy(args);
+
+    let random_seed: u64 = rand::rng().random();
+    let mut rng = Pcg64::seed_from_u64(random_seed);
+    let base_value: f64 = rng.random_range(10.0..30.0);
 
     let temperature_sensor: EnvironmentalSensor = EnvironmentalSensor {
         category: SensorType::Temperature("temperature".to_string()),
-        id: id,
-        random_seed: 42,
-        outputs: vec![],
-        unit: match &args.sensor_type {
-            Sensor::Temperature { unit } => Unit::TemperatureUnit(unit.clone()),
+        sinks: build_sinks(args, &id, "temperature")
+            .map_err(|e| format!("could not set up output sinks: {}", e))?,
+        stats: Arc::new(SensorStats::default()),
+        id,
+        random_seed,
+        rng,
+        outputs: RingBuffer::with_capacity(args.output_args.buffer_capacity),
+        overflow_policy,
+        readings_generated: 0,
+        last_value: None,
+        last_timestamp: None,
+        unit: match sensor_type {
+            Sensor::Temperature { unit } => Unit::TemperatureUnit(*unit),
             _ => panic!("shouldn't be constructing a temp sensor with a pressure or humidity unit"),
         },
-        unit_symbol: match &args.sensor_type {
+        unit_symbol: match sensor_type {
             Sensor::Temperature {
                 unit: TemperatureUnit::Celsius,
             } => "°C",
@@ -345,51 +599,68 @@ pub fn build_temp_sensor(args: &Args) -> EnvironmentalSensor {
             } => "K",
             _ => panic!("shouldn't be constructing a temp sensor with a pressure or humidity unit"),
         },
-        base_value: rand::rng().random_range(10.0..30.0),
+        base_value,
         drift_std: 0.1,
-        file_path: file_path,
+        file_path,
         file_format: args.output_args.format,
+        file_timestamps: args.output_args.file_timestamp.clone(),
+        output_file: None,
+        compression: args.output_args.compress,
+        compress_level: args.output_args.compress_level,
+        append_batch_size: args.output_args.append_batch_size,
+        inject_fault: args.output_args.inject_fault,
         current_file_partition: 0,
-        batches_in_current_file: 0,
-        to_sql: to_sql,
-        sql_conn: sql_conn,
+        rows_in_current_file: 0,
+        resume: args.output_args.resume.is_some(),
+        overwrite: args.output_args.overwrite,
+        checkpoint_every: args.output_args.checkpoint_every,
+        restored_from: None,
+        to_sql,
+        sql_conn,
     };
 
-    temperature_sensor
+    Ok(temperature_sensor)
 }
 
-pub fn build_pressure_sensor(args: &Args) -> EnvironmentalSensor {
-    let file_path: Option<String> = if args.output_args.to_file == "false".to_string() {
+pub fn build_pressure_sensor(
+    args: &Args,
+    sensor_type: &Sensor,
+    id: String,
+) -> Result<EnvironmentalSensor> {
+    let file_path: Option<String> = if args.output_args.to_file == "false" {
         None
     } else {
         Some(args.output_args.to_file.clone())
     };
 
-    let to_sql: bool = if args.output_args.to_file == "false".to_string() {
-        false
-    } else {
-        true
-    };
+    let to_sql: bool = matches!(args.output_args.to_sql, BooleanArg::True);
 
-    let sql_conn: Option<rusqlite::Connection> = if to_sql {
-        Some(setup_db().unwrap())
-    } else {
-        None
-    };
+    let sql_conn: Option<rusqlite::Connection> = if to_sql { Some(setup_db()?) } else { None };
 
-    let mut id = "PRS".to_string();
-    id.push_str(&create_id());
+    let overflow_policy = overflow_policy(args);
+
+    let random_seed: u64 = rand::rng().random();
+    let mut rng = Pcg64::seed_from_u64(random_seed);
+    let base_value: f64 = rng.random_range(0.9..1.1);
 
     let pressure_sensor: EnvironmentalSensor = EnvironmentalSensor {
         category: SensorType::Pressure("pressure".to_string()),
-        id: id,
-        random_seed: 42,
-        outputs: vec![],
-        unit: match &args.sensor_type {
-            Sensor::Pressure { unit } => Unit::PressureUnit(unit.clone()),
+        sinks: build_sinks(args, &id, "pressure")
+            .map_err(|e| format!("could not set up output sinks: {}", e))?,
+        stats: Arc::new(SensorStats::default()),
+        id,
+        random_seed,
+        rng,
+        outputs: RingBuffer::with_capacity(args.output_args.buffer_capacity),
+        overflow_policy,
+        readings_generated: 0,
+        last_value: None,
+        last_timestamp: None,
+        unit: match sensor_type {
+            Sensor::Pressure { unit } => Unit::PressureUnit(*unit),
             _ => panic!("shouldn't be constructing a pressure sensor with a temp or humidity unit"),
         },
-        unit_symbol: match &args.sensor_type {
+        unit_symbol: match sensor_type {
             Sensor::Pressure {
                 unit: PressureUnit::Bar,
             } => "bar",
@@ -398,51 +669,68 @@ pub fn build_pressure_sensor(args: &Args) -> EnvironmentalSensor {
             } => "Pa",
             _ => panic!("shouldn't be constructing a pressure sensor with a temp or humidity unit"),
         },
-        base_value: rand::rng().random_range(0.9..1.1),
+        base_value,
         drift_std: 0.1,
-        file_path: file_path,
+        file_path,
         file_format: args.output_args.format,
+        file_timestamps: args.output_args.file_timestamp.clone(),
+        output_file: None,
+        compression: args.output_args.compress,
+        compress_level: args.output_args.compress_level,
+        append_batch_size: args.output_args.append_batch_size,
+        inject_fault: args.output_args.inject_fault,
         current_file_partition: 0,
-        batches_in_current_file: 0,
-        to_sql: to_sql,
-        sql_conn: sql_conn,
+        rows_in_current_file: 0,
+        resume: args.output_args.resume.is_some(),
+        overwrite: args.output_args.overwrite,
+        checkpoint_every: args.output_args.checkpoint_every,
+        restored_from: None,
+        to_sql,
+        sql_conn,
     };
 
-    pressure_sensor
+    Ok(pressure_sensor)
 }
 
-pub fn build_humidity_sensor(args: &Args) -> EnvironmentalSensor {
-    let file_path: Option<String> = if args.output_args.to_file == "false".to_string() {
+pub fn build_humidity_sensor(
+    args: &Args,
+    sensor_type: &Sensor,
+    id: String,
+) -> Result<EnvironmentalSensor> {
+    let file_path: Option<String> = if args.output_args.to_file == "false" {
         None
     } else {
         Some(args.output_args.to_file.clone())
     };
 
-    let to_sql: bool = if args.output_args.to_file == "false".to_string() {
-        false
-    } else {
-        true
-    };
+    let to_sql: bool = matches!(args.output_args.to_sql, BooleanArg::True);
 
-    let sql_conn: Option<rusqlite::Connection> = if to_sql {
-        Some(setup_db().unwrap())
-    } else {
-        None
-    };
+    let sql_conn: Option<rusqlite::Connection> = if to_sql { Some(setup_db()?) } else { None };
+
+    let overflow_policy = overflow_policy(args);
 
-    let mut id = "HMD".to_string();
-    id.push_str(&create_id());
+    let random_seed: u64 = rand::rng().random();
+    let mut rng = Pcg64::seed_from_u64(random_seed);
+    let base_value: f64 = rng.random_range(40.0..60.0);
 
     let humidity_sensor: EnvironmentalSensor = EnvironmentalSensor {
         category: SensorType::Humidity("humidity".to_string()),
-        id: id,
-        random_seed: 42,
-        outputs: vec![],
-        unit: match &args.sensor_type {
-            Sensor::Humidity { unit } => Unit::HumidityUnit(unit.clone()),
+        sinks: build_sinks(args, &id, "humidity")
+            .map_err(|e| format!("could not set up output sinks: {}", e))?,
+        stats: Arc::new(SensorStats::default()),
+        id,
+        random_seed,
+        rng,
+        outputs: RingBuffer::with_capacity(args.output_args.buffer_capacity),
+        overflow_policy,
+        readings_generated: 0,
+        last_value: None,
+        last_timestamp: None,
+        unit: match sensor_type {
+            Sensor::Humidity { unit } => Unit::HumidityUnit(*unit),
             _ => panic!("shouldn't be constructing a humidity sensor with a pressure or temp unit"),
         },
-        unit_symbol: match &args.sensor_type {
+        unit_symbol: match sensor_type {
             Sensor::Humidity {
                 unit: HumidityUnit::Absolute,
             } => "g/m^3",
@@ -451,15 +739,126 @@ pub fn build_humidity_sensor(args: &Args) -> EnvironmentalSensor {
             } => "%",
             _ => panic!("shouldn't be constructing a humidity sensor with a pressure or temp unit"),
         },
-        base_value: rand::rng().random_range(40.0..60.0),
+        base_value,
         drift_std: 0.3,
-        file_path: file_path,
+        file_path,
         file_format: args.output_args.format,
+        file_timestamps: args.output_args.file_timestamp.clone(),
+        output_file: None,
+        compression: args.output_args.compress,
+        compress_level: args.output_args.compress_level,
+        append_batch_size: args.output_args.append_batch_size,
+        inject_fault: args.output_args.inject_fault,
         current_file_partition: 0,
-        batches_in_current_file: 0,
-        to_sql: to_sql,
-        sql_conn: sql_conn,
+        rows_in_current_file: 0,
+        resume: args.output_args.resume.is_some(),
+        overwrite: args.output_args.overwrite,
+        checkpoint_every: args.output_args.checkpoint_every,
+        restored_from: None,
+        to_sql,
+        sql_conn,
     };
 
-    humidity_sensor
+    Ok(humidity_sensor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::Command;
+    use clap::Parser;
+
+    /// an empty directory of its own for each test, so they can run in parallel
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "sensor_simulator_sensor_{}_{}",
+            name,
+            std::process::id()
+        ));
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn sensor(options: &[&str]) -> EnvironmentalSensor {
+        let mut command_line: Vec<&str> = vec!["sensor_simulator", "-n", "1"];
+        command_line.extend_from_slice(options);
+        command_line.extend_from_slice(&["temperature", "-u", "celsius"]);
+
+        let args = Args::parse_from(command_line);
+        let Command::Sensor(sensor_type) = args.command else {
+            unreachable!()
+        };
+
+        build_sensor(&args, &sensor_type, None).unwrap()
+    }
+
+    /// generates readings, returning their values in order
+    fn generate(sensor: &mut EnvironmentalSensor, count: usize) -> Vec<f32> {
+        (0..count)
+            .map(|_| {
+                sensor.generate_output().unwrap();
+                sensor.last_value.unwrap()
+            })
+            .collect()
+    }
+
+    fn buffered(sensor: &EnvironmentalSensor) -> Vec<f32> {
+        sensor.outputs.iter().map(|reading| reading.value).collect()
+    }
+
+    fn rows_in_partition(sensor: &EnvironmentalSensor) -> usize {
+        let mut reader = csv::Reader::from_path(sensor.partition_path()).unwrap();
+        reader.records().count()
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_latest_readings() {
+        let mut sensor = sensor(&["--buffer-capacity", "3", "--overflow-policy", "drop-oldest"]);
+
+        let values = generate(&mut sensor, 5);
+
+        assert_eq!(buffered(&sensor), values[2..]);
+    }
+
+    #[test]
+    fn flush_writes_the_full_buffer_to_file() {
+        let dir = test_dir("flush");
+        let mut sensor = sensor(&[
+            "-f",
+            dir.to_str().unwrap(),
+            "--buffer-capacity",
+            "2",
+            "--append-batch-size",
+            "2",
+            "--overflow-policy",
+            "flush",
+        ]);
+
+        let values = generate(&mut sensor, 5);
+
+        assert_eq!(rows_in_partition(&sensor), 4);
+        assert_eq!(buffered(&sensor), values[4..]);
+    }
+
+    #[test]
+    fn block_writes_the_full_buffer_before_carrying_on() {
+        let dir = test_dir("block");
+        let mut sensor = sensor(&[
+            "-f",
+            dir.to_str().unwrap(),
+            "--buffer-capacity",
+            "3",
+            "--append-batch-size",
+            "1",
+            "--overflow-policy",
+            "block",
+        ]);
+
+        let values = generate(&mut sensor, 4);
+
+        // nothing is dropped - the first three were written out to make room for the fourth
+        assert_eq!(rows_in_partition(&sensor), 3);
+        assert_eq!(buffered(&sensor), values[3..]);
+    }
 }
@@ -0,0 +1,716 @@
+use crate::args::{Args, Sensor, ServeArgs};
+use crate::buffer::RingBuffer;
+use crate::sensor::{EnvironmentalSensor, SensorOutput, SensorStats, build_sensor_with_id};
+use crate::sinks::Sink;
+use crate::timestamp::parse_plain;
+use crate::utils::{create_id, unit_parts};
+use std::collections::BTreeMap;
+use std::io::BufReader;
+use std::io::prelude::*;
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{RecvTimeoutError, SyncSender, TrySendError, sync_channel};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use time::UtcDateTime;
+
+mod websocket;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// how often an idle event stream is sent a comment, so proxies keep it open and a closed client is noticed
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+// readings waiting to be written to an event stream. a client that falls this far behind is disconnected
+const QUEUED_EVENTS: usize = 256;
+
+const JSON: &str = "application/json";
+const PROMETHEUS_TEXT: &str = "text/plain; version=0.0.4; charset=utf-8";
+const OPENMETRICS_TEXT: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// a new reading, as handed to the event streams and websockets
+#[derive(Debug)]
+struct Event {
+    id: String,
+    category: &'static str,
+    json: String,
+}
+
+/// a reading kept for the http api, already serialized the same way as `output.json`
+#[derive(Debug)]
+struct StoredReading {
+    timestamp: UtcDateTime,
+    value: f32,
+    json: String,
+}
+
+/// everything the server knows about one sensor
+#[derive(Debug)]
+struct Feed {
+    category: &'static str,
+    /// the unit without the category, e.g. `celsius`, as used in the metric labels
+    unit: &'static str,
+    stats: Arc<SensorStats>,
+    readings: RingBuffer<StoredReading>,
+    subscribers: Vec<SyncSender<Arc<Event>>>,
+}
+
+/// the feeds of every sensor being served, plus the event streams and websockets that want readings from all of them
+#[derive(Debug, Default)]
+struct Hub {
+    sensors: BTreeMap<String, Feed>,
+    subscribers: Vec<SyncSender<Arc<Event>>>,
+    /// readings a websocket client is sent when it connects, unless it asks for a different number
+    websocket_backlog: usize,
+}
+
+/// hands each reading a sensor generates to the http server
+#[derive(Debug)]
+struct FeedSink {
+    id: String,
+    hub: Arc<Mutex<Hub>>,
+}
+
+impl Sink for FeedSink {
+    fn name(&self) -> &str {
+        "http feed"
+    }
+
+    fn send(&mut self, reading: &SensorOutput) -> Result<()> {
+        let json: String = serde_json::to_string(reading)?;
+
+        let mut guard = self.hub.lock().unwrap();
+        let hub: &mut Hub = &mut guard;
+        let feed = hub
+            .sensors
+            .get_mut(&self.id)
+            .ok_or_else(|| format!("sensor {} was never registered with the server", self.id))?;
+
+        let event = Arc::new(Event {
+            id: self.id.clone(),
+            category: feed.category,
+            json: json.clone(),
+        });
+        publish(&mut feed.subscribers, &event);
+        publish(&mut hub.subscribers, &event);
+
+        feed.readings.push(StoredReading {
+            // to the second, the same as the timestamp in the json, so `since` picks up what the client was shown
+            timestamp: reading.timestamp.replace_nanosecond(0)?,
+            value: reading.value,
+            json,
+        });
+
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        // dropping the senders ends this sensor's event streams
+        if let Some(feed) = self.hub.lock().unwrap().sensors.get_mut(&self.id) {
+            feed.subscribers.clear();
+        }
+        Ok(())
+    }
+}
+
+/// sends a reading to every event stream, forgetting the ones that have closed or fallen too far behind
+fn publish(subscribers: &mut Vec<SyncSender<Arc<Event>>>, event: &Arc<Event>) {
+    subscribers.retain(|subscriber| match subscriber.try_send(event.clone()) {
+        Ok(..) => true,
+        Err(TrySendError::Full(..)) | Err(TrySendError::Disconnected(..)) => false,
+    });
+}
+
+fn category(sensor: &Sensor) -> &'static str {
+    match sensor {
+        Sensor::Temperature { .. } => "temperature",
+        Sensor::Pressure { .. } => "pressure",
+        Sensor::Humidity { .. } => "humidity",
+    }
+}
+
+/// the arguments for one of the served sensors. anything that can only be used by one sensor at a time gets
+/// the sensor's type and position added to it
+fn sensor_args(args: &Args, sensor_type: &Sensor, index: usize) -> Args {
+    let mut sensor_args: Args = args.clone();
+    let suffix: String = format!("{}_{}", category(sensor_type), index);
+
+    // every sensor writes output.csv and recovers its directory on start up, so they can't share one
+    if args.output_args.to_file != "false" {
+        sensor_args.output_args.to_file = Path::new(&args.output_args.to_file)
+            .join(&suffix)
+            .to_string_lossy()
+            .into_owned();
+    }
+
+    // each sensor has a pty of its own, so each needs its own link to it as well
+    if let Some(link) = &args.pty_args.pty_link {
+        sensor_args.pty_args.pty_link = Some(format!("{}_{}", link, suffix));
+    }
+
+    // a broker drops the older connection when a client id is reused, so sensors sharing one would keep
+    // knocking each other off
+    if let Some(client_id) = &args.mqtt_args.mqtt_client_id {
+        sensor_args.mqtt_args.mqtt_client_id = Some(format!("{}_{}", client_id, suffix));
+    }
+
+    sensor_args
+}
+
+/// a new id for a sensor of this type that no other served sensor has. ids are only three random characters after
+/// the prefix, so with enough sensors two can come up the same, and the second would replace the first's feed
+fn unused_id(hub: &Hub, sensor_type: &Sensor) -> Result<String> {
+    for _ in 0..1000 {
+        let id: String = format!("{}{}", sensor_type.id_prefix(), create_id());
+        if !hub.sensors.contains_key(&id) {
+            return Ok(id);
+        }
+    }
+    Err(format!(
+        "could not find an unused id for another {} sensor",
+        category(sensor_type)
+    )
+    .into())
+}
+
+/// runs every sensor asked for on its own thread and serves their readings over http until they finish.
+///
+/// the sensors run exactly as they would from their own subcommands - the server only sees their readings
+/// through a sink, so every other output option still applies to each of them. with --to-file each sensor
+/// writes to its own subdirectory, named for its type and position, e.g. `temperature_0`, and --pty-link and
+/// --mqtt-client-id get the same suffix, e.g. `/tmp/ttySENSOR_temperature_0`
+pub fn run(args: &Args, serve_args: &ServeArgs, interval: i32, duration: i32) -> Result<()> {
+    let hub: Arc<Mutex<Hub>> = Arc::new(Mutex::new(Hub {
+        websocket_backlog: serve_args.websocket_backlog,
+        ..Hub::default()
+    }));
+
+    let listener = TcpListener::bind((serve_args.bind.as_str(), serve_args.port)).map_err(|e| {
+        format!(
+            "could not listen on {}:{}: {}",
+            serve_args.bind, serve_args.port, e
+        )
+    })?;
+
+    let mut sensors: Vec<EnvironmentalSensor> = vec![];
+
+    for (index, sensor_type) in serve_args.sensors.iter().enumerate() {
+        let sensor_args: Args = sensor_args(args, sensor_type, index);
+
+        if sensor_args.output_args.to_file != "false" {
+            std::fs::create_dir_all(&sensor_args.output_args.to_file).map_err(|e| {
+                format!(
+                    "could not create {}: {}",
+                    sensor_args.output_args.to_file, e
+                )
+            })?;
+        }
+
+        let id: String = unused_id(&hub.lock().unwrap(), sensor_type)?;
+        let mut sensor: EnvironmentalSensor =
+            build_sensor_with_id(&sensor_args, sensor_type, id.clone())?;
+
+        hub.lock().unwrap().sensors.insert(
+            id.clone(),
+            Feed {
+                category: category(sensor_type),
+                unit: unit_parts(sensor.unit()).1,
+                stats: sensor.stats(),
+                readings: RingBuffer::with_capacity(serve_args.history),
+                subscribers: vec![],
+            },
+        );
+        sensor.add_sink(Box::new(FeedSink {
+            id,
+            hub: hub.clone(),
+        }));
+
+        sensors.push(sensor);
+    }
+
+    note!(
+        "serving {} sensors on http://{}:{}",
+        sensors.len(),
+        serve_args.bind,
+        serve_args.port
+    );
+
+    let server_hub = hub.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let hub = server_hub.clone();
+                    std::thread::spawn(move || {
+                        if let Err(e) = handle_connection(stream, &hub) {
+                            eprintln!("http: {}", e);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("http: could not accept a connection: {}", e),
+            }
+        }
+    });
+
+    let handles: Vec<JoinHandle<std::result::Result<(), String>>> = sensors
+        .into_iter()
+        .map(|mut sensor| {
+            std::thread::spawn(move || {
+                sensor
+                    .run_sensor(&interval, &duration)
+                    .map_err(|e| format!("sensor {}: {}", sensor.id(), e))
+            })
+        })
+        .collect();
+
+    let mut errors: Vec<String> = vec![];
+    for handle in handles {
+        match handle.join() {
+            Ok(Ok(..)) => (),
+            Ok(Err(e)) => errors.push(e),
+            Err(..) => errors.push("a sensor thread panicked".to_string()),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join(", ").into())
+    }
+}
+
+fn handle_connection(mut stream: TcpStream, hub: &Arc<Mutex<Hub>>) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // header names are case insensitive, so they are kept lowercased
+    let mut headers: Vec<(String, String)> = vec![];
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_lowercase(), value.trim().to_string()));
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method: &str = parts.next().unwrap_or("");
+    let target: &str = parts.next().unwrap_or("");
+
+    if method != "GET" {
+        return respond(
+            &mut stream,
+            405,
+            JSON,
+            &error_body("only GET requests are supported"),
+        );
+    }
+
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    match segments.as_slice() {
+        ["events"] => stream_events(stream, hub, None),
+        ["metrics"] => {
+            let openmetrics: bool = header(&headers, "accept")
+                .is_some_and(|accept| accept.contains("application/openmetrics-text"));
+            let content_type = if openmetrics {
+                OPENMETRICS_TEXT
+            } else {
+                PROMETHEUS_TEXT
+            };
+            respond(&mut stream, 200, content_type, &metrics(hub, openmetrics))
+        }
+        ["sensors", id, "events"] => stream_events(stream, hub, Some(id)),
+        ["ws"] => websocket::serve_websocket(stream, hub, &headers, query),
+        _ => {
+            let (status, body) = route(hub, &segments, query);
+            respond(&mut stream, status, JSON, &body)
+        }
+    }
+}
+
+/// works out the response to every request apart from the event streams
+fn route(hub: &Arc<Mutex<Hub>>, segments: &[&str], query: &str) -> (u16, String) {
+    let hub = hub.lock().unwrap();
+
+    match segments {
+        ["sensors"] => {
+            let sensors: Vec<String> = hub
+                .sensors
+                .iter()
+                .map(|(id, feed)| {
+                    format!(
+                        "{{\"id\":{},\"category\":{},\"readings\":{},\"latest\":{}}}",
+                        serde_json::to_string(id).unwrap(),
+                        serde_json::to_string(feed.category).unwrap(),
+                        feed.readings.len(),
+                        feed.readings
+                            .last()
+                            .map_or("null", |reading| reading.json.as_str())
+                    )
+                })
+                .collect();
+
+            (200, format!("[{}]", sensors.join(",")))
+        }
+        ["sensors", id, "latest"] => match hub.sensors.get(*id) {
+            Some(feed) => match feed.readings.last() {
+                Some(reading) => (200, reading.json.clone()),
+                None => (
+                    404,
+                    error_body(&format!("sensor {} has no readings yet", id)),
+                ),
+            },
+            None => (404, error_body(&format!("there is no sensor {}", id))),
+        },
+        ["sensors", id, "readings"] => {
+            let feed = match hub.sensors.get(*id) {
+                Some(feed) => feed,
+                None => return (404, error_body(&format!("there is no sensor {}", id))),
+            };
+
+            let since: Option<UtcDateTime> = match query_param(query, "since") {
+                Some(since) => match parse_since(&since) {
+                    Ok(since) => Some(since),
+                    Err(..) => {
+                        return (
+                            400,
+                            error_body(&format!(
+                                "`{}` is not a timestamp - use the format readings are given in, e.g. 2025-01-31 09:30:00",
+                                since
+                            )),
+                        );
+                    }
+                },
+                None => None,
+            };
+
+            let readings: Vec<&str> = feed
+                .readings
+                .iter()
+                .filter(|reading| since.is_none_or(|since| reading.timestamp > since))
+                .map(|reading| reading.json.as_str())
+                .collect();
+
+            (200, format!("[{}]", readings.join(",")))
+        }
+        _ => (404, error_body("not found")),
+    }
+}
+
+/// keeps the connection open and writes each new reading to it as a server-sent event
+fn stream_events(mut stream: TcpStream, hub: &Arc<Mutex<Hub>>, id: Option<&str>) -> Result<()> {
+    let (sender, receiver) = sync_channel::<Arc<Event>>(QUEUED_EVENTS);
+
+    {
+        let mut hub = hub.lock().unwrap();
+        match id {
+            Some(id) => match hub.sensors.get_mut(id) {
+                Some(feed) => feed.subscribers.push(sender),
+                None => {
+                    return respond(
+                        &mut stream,
+                        404,
+                        JSON,
+                        &error_body(&format!("there is no sensor {}", id)),
+                    );
+                }
+            },
+            None => hub.subscribers.push(sender),
+        }
+    }
+
+    stream.write_all(
+        b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: keep-alive\r\nAccess-Control-Allow-Origin: *\r\n\r\n",
+    )?;
+    stream.flush()?;
+
+    loop {
+        let written = match receiver.recv_timeout(KEEP_ALIVE) {
+            Ok(event) => write!(stream, "data: {}\n\n", event.json),
+            Err(RecvTimeoutError::Timeout) => stream.write_all(b": keep-alive\n\n"),
+            // the sensor finished, or this client fell too far behind to keep up
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+
+        // a failed write just means the client has gone away, which is how event streams normally end
+        if written.and_then(|_| stream.flush()).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// the latest value of each sensor as a gauge, plus counters of its readings and sink errors, in the
+/// prometheus text format. openmetrics is nearly the same, but has to end with `# EOF`
+fn metrics(hub: &Arc<Mutex<Hub>>, openmetrics: bool) -> String {
+    let hub = hub.lock().unwrap();
+    let mut body = String::new();
+
+    let labels = |id: &str, feed: &Feed| -> String {
+        format!(
+            "id=\"{}\",category=\"{}\",unit=\"{}\"",
+            escape_label(id),
+            feed.category,
+            feed.unit
+        )
+    };
+
+    body.push_str("# HELP sensor_simulator_value latest reading from the sensor\n");
+    body.push_str("# TYPE sensor_simulator_value gauge\n");
+    for (id, feed) in &hub.sensors {
+        // a sensor that hasn't produced a reading yet has no value to report
+        if let Some(reading) = feed.readings.last() {
+            body.push_str(&format!(
+                "sensor_simulator_value{{{}}} {}\n",
+                labels(id, feed),
+                reading.value
+            ));
+        }
+    }
+
+    // openmetrics names the counter family without the _total its samples end in, prometheus names it with it
+    let counters: [(&str, &str, Counter); 2] = [
+        (
+            "sensor_simulator_readings_generated",
+            "readings generated by the sensor",
+            |stats| &stats.readings_generated,
+        ),
+        (
+            "sensor_simulator_sink_errors",
+            "readings a sink failed to send",
+            |stats| &stats.sink_errors,
+        ),
+    ];
+
+    for (name, help, counter) in counters {
+        let family: String = if openmetrics {
+            name.to_string()
+        } else {
+            format!("{}_total", name)
+        };
+        body.push_str(&format!("# HELP {} {}\n", family, help));
+        body.push_str(&format!("# TYPE {} counter\n", family));
+
+        for (id, feed) in &hub.sensors {
+            body.push_str(&format!(
+                "{}_total{{{}}} {}\n",
+                name,
+                labels(id, feed),
+                counter(&feed.stats).load(Ordering::Relaxed)
+            ));
+        }
+    }
+
+    if openmetrics {
+        body.push_str("# EOF\n");
+    }
+
+    body
+}
+
+/// picks one of the counters out of a sensor's stats
+type Counter = fn(&SensorStats) -> &AtomicU64;
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn respond(stream: &mut TcpStream, status: u16, content_type: &str, body: &str) -> Result<()> {
+    let reason: &str = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "",
+    };
+
+    // cors is allowed from anywhere, so a dashboard served from somewhere else can poll the simulator
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\nAccess-Control-Allow-Origin: *\r\n\r\n{}",
+        status,
+        reason,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+
+    Ok(())
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(header, _)| header == name)
+        .map(|(_, value)| value.as_str())
+}
+
+fn error_body(message: &str) -> String {
+    format!("{{\"error\":{}}}", serde_json::to_string(message).unwrap())
+}
+
+fn query_param(query: &str, name: &str) -> Option<String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| percent_decode(value))
+}
+
+/// accepts the timestamp format used in the readings, and the `T` separated iso 8601 form of it
+fn parse_since(since: &str) -> Result<UtcDateTime> {
+    Ok(parse_plain(
+        since
+            .trim()
+            .trim_end_matches('Z')
+            .replacen('T', " ", 1)
+            .as_str(),
+    )?)
+}
+
+/// undoes the url encoding of a query value, e.g. `2025-01-31%2009:30:00` or `2025-01-31+09:30:00`
+fn percent_decode(value: &str) -> String {
+    let bytes: &[u8] = value.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            // from_str_radix alone would take a sign, e.g. `%+1`
+            b'%' if i + 2 < bytes.len()
+                && bytes[i + 1].is_ascii_hexdigit()
+                && bytes[i + 2].is_ascii_hexdigit() =>
+            {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap();
+                decoded.push(u8::from_str_radix(hex, 16).unwrap());
+                i += 2;
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::{PressureUnit, TemperatureUnit};
+    use clap::Parser;
+
+    #[test]
+    fn served_sensors_get_their_own_files_link_and_client_id() {
+        let args = Args::parse_from([
+            "sensor_simulator",
+            "-n",
+            "1",
+            "-f",
+            "out",
+            "--pty",
+            "--pty-link",
+            "/tmp/ttySENSOR",
+            "--mqtt-host",
+            "localhost",
+            "--mqtt-client-id",
+            "rig",
+            "serve",
+        ]);
+
+        let first = sensor_args(
+            &args,
+            &Sensor::Temperature {
+                unit: TemperatureUnit::Celsius,
+            },
+            0,
+        );
+        let second = sensor_args(
+            &args,
+            &Sensor::Pressure {
+                unit: PressureUnit::Bar,
+            },
+            1,
+        );
+
+        assert_eq!(
+            Path::new(&first.output_args.to_file),
+            Path::new("out").join("temperature_0")
+        );
+        assert_eq!(
+            first.pty_args.pty_link.as_deref(),
+            Some("/tmp/ttySENSOR_temperature_0")
+        );
+        assert_eq!(
+            first.mqtt_args.mqtt_client_id.as_deref(),
+            Some("rig_temperature_0")
+        );
+        assert_eq!(
+            second.mqtt_args.mqtt_client_id.as_deref(),
+            Some("rig_pressure_1")
+        );
+    }
+
+    #[test]
+    fn served_sensors_leave_unset_options_alone() {
+        let args = Args::parse_from(["sensor_simulator", "-n", "1", "serve"]);
+
+        let sensor = sensor_args(
+            &args,
+            &Sensor::Temperature {
+                unit: TemperatureUnit::Celsius,
+            },
+            0,
+        );
+
+        assert_eq!(sensor.output_args.to_file, "false");
+        assert_eq!(sensor.pty_args.pty_link, None);
+        // left to the sink, which names the client after the sensor's id
+        assert_eq!(sensor.mqtt_args.mqtt_client_id, None);
+    }
+
+    #[test]
+    fn percent_decode_undoes_url_encoding() {
+        assert_eq!(
+            percent_decode("2025-01-31%2009:30:00"),
+            "2025-01-31 09:30:00"
+        );
+        assert_eq!(percent_decode("2025-01-31+09:30:00"), "2025-01-31 09:30:00");
+        assert_eq!(percent_decode("%2B05%3a30"), "+05:30");
+        // an escape right at the end
+        assert_eq!(percent_decode("a%20"), "a ");
+    }
+
+    #[test]
+    fn percent_decode_leaves_bad_escapes_alone() {
+        assert_eq!(percent_decode("50%"), "50%");
+        assert_eq!(percent_decode("%2"), "%2");
+        assert_eq!(percent_decode("%zz"), "%zz");
+        assert_eq!(percent_decode("100%%20"), "100% ");
+        // only hex digits, not a sign
+        assert_eq!(percent_decode("%+1"), "% 1");
+        assert_eq!(percent_decode("%-1x"), "%-1x");
+    }
+
+    #[test]
+    fn query_param_finds_and_decodes_a_value() {
+        let query = "last=5&since=2025-01-31+09%3A30%3A00";
+
+        assert_eq!(
+            query_param(query, "since").as_deref(),
+            Some("2025-01-31 09:30:00")
+        );
+        assert_eq!(query_param(query, "last").as_deref(), Some("5"));
+        assert_eq!(query_param(query, "id"), None);
+    }
+}
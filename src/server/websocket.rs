@@ -0,0 +1,248 @@
+use super::{Event, Hub, JSON, QUEUED_EVENTS, error_body, header, query_param, respond};
+use serde::Deserialize;
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::sync::mpsc::{TryRecvError, sync_channel};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use time::UtcDateTime;
+use tungstenite::handshake::derive_accept_key;
+use tungstenite::protocol::{Role, WebSocket};
+use tungstenite::{Message, Utf8Bytes};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// how long a read waits for a message from the client before checking for new readings again
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+// a client that can't take a reading in this long is treated as gone
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// which readings a websocket client wants. readings matching either list are sent, and an empty filter
+/// gets everything
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Filter {
+    #[serde(default)]
+    ids: Vec<String>,
+    #[serde(default)]
+    categories: Vec<String>,
+}
+
+impl Filter {
+    /// reads the comma separated `id` and `category` query parameters, e.g. `/ws?category=temperature,humidity`
+    fn from_query(query: &str) -> Filter {
+        let list = |name: &str| -> Vec<String> {
+            match query_param(query, name) {
+                Some(values) => values
+                    .split(',')
+                    .filter(|value| !value.is_empty())
+                    .map(|value| value.to_string())
+                    .collect(),
+                None => vec![],
+            }
+        };
+
+        Filter {
+            ids: list("id"),
+            categories: list("category"),
+        }
+    }
+
+    fn matches(&self, id: &str, category: &str) -> bool {
+        (self.ids.is_empty() && self.categories.is_empty())
+            || self.ids.iter().any(|wanted| wanted == id)
+            || self
+                .categories
+                .iter()
+                .any(|wanted| wanted.eq_ignore_ascii_case(category))
+    }
+}
+
+/// upgrades the connection to a websocket and sends it every new reading that passes the client's filter,
+/// as the same json as `output.json`.
+///
+/// the client is first sent the most recent readings that pass the filter, oldest first, then live ones as they
+/// are generated. the filter starts from the query string, and the client can replace it at any time by sending
+/// `{"ids": [...], "categories": [...]}`
+pub fn serve_websocket(
+    mut stream: TcpStream,
+    hub: &Arc<Mutex<Hub>>,
+    headers: &[(String, String)],
+    query: &str,
+) -> Result<()> {
+    let key: &str = match (
+        header(headers, "upgrade"),
+        header(headers, "sec-websocket-key"),
+    ) {
+        (Some(upgrade), Some(key)) if upgrade.eq_ignore_ascii_case("websocket") => key,
+        _ => {
+            return respond(
+                &mut stream,
+                400,
+                JSON,
+                &error_body("/ws expects a websocket upgrade request"),
+            );
+        }
+    };
+
+    let last: Option<usize> = match query_param(query, "last") {
+        Some(last) => match last.parse() {
+            Ok(last) => Some(last),
+            Err(..) => {
+                return respond(
+                    &mut stream,
+                    400,
+                    JSON,
+                    &error_body("`last` should be a number of readings"),
+                );
+            }
+        },
+        None => None,
+    };
+
+    let mut filter = Filter::from_query(query);
+    let (sender, receiver) = sync_channel::<Arc<Event>>(QUEUED_EVENTS);
+
+    // the backlog is gathered and the client subscribed under one lock, so no reading is missed or sent twice
+    let backlog: Vec<String> = {
+        let mut hub = hub.lock().unwrap();
+        let last: usize = last.unwrap_or(hub.websocket_backlog);
+
+        let mut readings: Vec<(UtcDateTime, &String)> = hub
+            .sensors
+            .iter()
+            .filter(|(id, feed)| filter.matches(id, feed.category))
+            .flat_map(|(_, feed)| {
+                feed.readings
+                    .iter()
+                    .map(|reading| (reading.timestamp, &reading.json))
+            })
+            .collect();
+        // a stable sort, so readings from the same second keep the order each sensor made them in
+        readings.sort_by_key(|(timestamp, _)| *timestamp);
+
+        let skip: usize = readings.len().saturating_sub(last);
+        let backlog: Vec<String> = readings
+            .into_iter()
+            .skip(skip)
+            .map(|(_, json)| json.clone())
+            .collect();
+
+        hub.subscribers.push(sender);
+        backlog
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        derive_accept_key(key.as_bytes())
+    )?;
+    stream.flush()?;
+
+    // reads time out quickly so the same thread can go back to sending readings in between
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let mut socket = WebSocket::from_raw_socket(stream, Role::Server, None);
+
+    for json in backlog {
+        if socket.send(Message::text(json)).is_err() {
+            return Ok(());
+        }
+    }
+
+    loop {
+        match socket.read() {
+            Ok(Message::Text(text)) => {
+                if let Some(reply) = update_filter(&mut filter, &text)
+                    && socket.send(Message::text(reply)).is_err()
+                {
+                    return Ok(());
+                }
+            }
+            // tungstenite queues the pong itself, it just needs sending
+            Ok(Message::Ping(..)) => _ = socket.flush(),
+            Ok(..) => (),
+            Err(tungstenite::Error::Io(e))
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) => {}
+            // the client closed the connection or went away
+            Err(..) => return Ok(()),
+        }
+
+        loop {
+            match receiver.try_recv() {
+                Ok(event) => {
+                    if filter.matches(&event.id, event.category)
+                        && socket.send(Message::text(event.json.clone())).is_err()
+                    {
+                        return Ok(());
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                // the client fell too far behind and was dropped
+                Err(TryRecvError::Disconnected) => {
+                    _ = socket.close(None);
+                    _ = socket.flush();
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// replaces the filter with one sent by the client, returning an error to send back if it doesn't make sense
+fn update_filter(filter: &mut Filter, text: &Utf8Bytes) -> Option<String> {
+    match serde_json::from_str::<Filter>(text.as_str()) {
+        Ok(new_filter) => {
+            *filter = new_filter;
+            None
+        }
+        Err(e) => Some(error_body(&format!(
+            "filters look like {{\"ids\": [...], \"categories\": [...]}}: {}",
+            e
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_empty_filter_matches_everything() {
+        let filter = Filter::from_query("");
+
+        assert!(filter.matches("TMPabc", "temperature"));
+        assert!(filter.matches("PRSdef", "pressure"));
+    }
+
+    #[test]
+    fn ids_and_categories_each_let_a_reading_through() {
+        let filter = Filter::from_query("id=PRSdef&category=Temperature,humidity");
+
+        assert!(filter.matches("TMPabc", "temperature"));
+        assert!(filter.matches("HMDghi", "humidity"));
+        assert!(filter.matches("PRSdef", "pressure"));
+        assert!(!filter.matches("PRSxyz", "pressure"));
+    }
+
+    #[test]
+    fn ids_are_matched_exactly() {
+        let filter = Filter::from_query("id=TMPabc");
+
+        assert!(filter.matches("TMPabc", "temperature"));
+        assert!(!filter.matches("TMPABC", "temperature"));
+        assert!(!filter.matches("TMPabcd", "temperature"));
+    }
+
+    #[test]
+    fn empty_values_in_the_query_are_ignored() {
+        let filter = Filter::from_query("id=&category=pressure,,");
+
+        assert!(filter.ids.is_empty());
+        assert_eq!(filter.categories, ["pressure"]);
+        assert!(!filter.matches("TMPabc", "temperature"));
+    }
+}
@@ -0,0 +1,225 @@
+use crate::args::{ArrowArgs, ArrowFormat};
+use crate::sensor::SensorOutput;
+use crate::sinks::Sink;
+use crate::utils::serialize_unit;
+use arrow_array::builder::{Float32Builder, StringBuilder, TimestampNanosecondBuilder};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_ipc::writer::{FileWriter, StreamWriter};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use std::collections::BTreeMap;
+use std::io::prelude::*;
+use std::sync::{Arc, Mutex};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+type Destination = Box<dyn Write + Send>;
+
+enum IpcWriter {
+    File(FileWriter<Destination>),
+    Stream(StreamWriter<Destination>),
+}
+
+impl std::fmt::Debug for IpcWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IpcWriter::File(..) => write!(f, "IpcWriter::File"),
+            IpcWriter::Stream(..) => write!(f, "IpcWriter::Stream"),
+        }
+    }
+}
+
+/// the readings waiting to go into the next record batch
+#[derive(Debug, Default)]
+struct Columns {
+    id: StringBuilder,
+    timestamp: TimestampNanosecondBuilder,
+    value: Float32Builder,
+    unit: StringBuilder,
+    symbol: StringBuilder,
+    rows: usize,
+}
+
+/// an arrow output and the sinks writing to it
+#[derive(Debug)]
+struct Output {
+    writer: IpcWriter,
+    schema: SchemaRef,
+    columns: Columns,
+    batch_size: usize,
+    sinks: usize,
+}
+
+// one writer per output, so with `serve` every sensor's readings go into the same batches. an ipc stream
+// can't be written by two writers at once
+static OUTPUTS: Mutex<BTreeMap<String, Arc<Mutex<Output>>>> = Mutex::new(BTreeMap::new());
+
+/// writes readings as apache arrow ipc, so they can be picked up by pyarrow, polars or pandas without parsing.
+///
+/// the columns are the same as the sqlite table's, with the timestamp as nanoseconds in utc. readings are
+/// collected into record batches of --arrow-batch-size. the stream format writes and flushes each batch as soon
+/// as it is full, so `pyarrow.ipc.open_stream` can read along while the sensor runs. the file format (feather v2)
+/// is only complete once its footer is written at the end of the run
+#[derive(Debug)]
+pub struct ArrowSink {
+    path: String,
+    output: Arc<Mutex<Output>>,
+}
+
+impl ArrowSink {
+    pub fn new(args: &ArrowArgs) -> Result<ArrowSink> {
+        let path: String = args.arrow.clone().ok_or("the arrow sink needs --arrow")?;
+
+        let output: Arc<Mutex<Output>> = {
+            let mut outputs = OUTPUTS.lock().unwrap();
+            match outputs.get(&path) {
+                Some(output) => output.clone(),
+                None => {
+                    let output = Arc::new(Mutex::new(open(&path, args)?));
+                    outputs.insert(path.clone(), output.clone());
+                    output
+                }
+            }
+        };
+        output.lock().unwrap().sinks += 1;
+
+        Ok(ArrowSink { path, output })
+    }
+}
+
+fn open(path: &str, args: &ArrowArgs) -> Result<Output> {
+    let schema: SchemaRef = Arc::new(Schema::new(vec![
+        Field::new("id", DataType::Utf8, false),
+        Field::new(
+            "timestamp",
+            DataType::Timestamp(TimeUnit::Nanosecond, Some("UTC".into())),
+            false,
+        ),
+        Field::new("value", DataType::Float32, false),
+        Field::new("unit", DataType::Utf8, false),
+        Field::new("symbol", DataType::Utf8, false),
+    ]));
+
+    let format: ArrowFormat = match args.arrow_format {
+        Some(format) => format,
+        None if path == "-" => ArrowFormat::Stream,
+        None => ArrowFormat::File,
+    };
+
+    let destination: Destination = if path == "-" {
+        Box::new(std::io::stdout())
+    } else {
+        let file =
+            std::fs::File::create(path).map_err(|e| format!("could not create {}: {}", path, e))?;
+        Box::new(std::io::BufWriter::new(file))
+    };
+
+    let writer = match format {
+        ArrowFormat::File => IpcWriter::File(FileWriter::try_new(destination, &schema)?),
+        ArrowFormat::Stream => {
+            let mut writer = StreamWriter::try_new(destination, &schema)?;
+            // a reader can open the stream as soon as it has the schema, before the first batch
+            writer.flush()?;
+            IpcWriter::Stream(writer)
+        }
+    };
+
+    Ok(Output {
+        writer,
+        schema,
+        columns: Columns::default(),
+        batch_size: args.arrow_batch_size,
+        sinks: 0,
+    })
+}
+
+impl Output {
+    fn push(&mut self, reading: &SensorOutput) {
+        let columns = &mut self.columns;
+        columns.id.append_value(&reading.id);
+        columns
+            .timestamp
+            .append_value(reading.timestamp.unix_timestamp_nanos() as i64);
+        columns.value.append_value(reading.value);
+        columns.unit.append_value(serialize_unit(&reading.unit));
+        columns.symbol.append_value(&reading.symbol);
+        columns.rows += 1;
+    }
+
+    /// writes whatever has been collected as one record batch
+    fn write_batch(&mut self) -> Result<()> {
+        if self.columns.rows == 0 {
+            return Ok(());
+        }
+
+        let columns = &mut self.columns;
+        let arrays: Vec<ArrayRef> = vec![
+            Arc::new(columns.id.finish()),
+            Arc::new(columns.timestamp.finish().with_timezone("UTC")),
+            Arc::new(columns.value.finish()),
+            Arc::new(columns.unit.finish()),
+            Arc::new(columns.symbol.finish()),
+        ];
+        columns.rows = 0;
+
+        let batch = RecordBatch::try_new(self.schema.clone(), arrays)?;
+
+        match &mut self.writer {
+            IpcWriter::File(writer) => writer.write(&batch)?,
+            IpcWriter::Stream(writer) => {
+                writer.write(&batch)?;
+                writer.flush()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.write_batch()?;
+
+        match &mut self.writer {
+            IpcWriter::File(writer) => {
+                writer.finish()?;
+                writer.get_mut().flush()?;
+            }
+            IpcWriter::Stream(writer) => {
+                writer.finish()?;
+                writer.get_mut().flush()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Sink for ArrowSink {
+    fn name(&self) -> &str {
+        "arrow"
+    }
+
+    fn send(&mut self, reading: &SensorOutput) -> Result<()> {
+        let mut output = self.output.lock().unwrap();
+        output.push(reading);
+
+        if output.columns.rows >= output.batch_size {
+            output.write_batch()?;
+        }
+
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        let mut output = self.output.lock().unwrap();
+        output.sinks -= 1;
+
+        // the last sensor to finish writes the final batch and the footer, so it has everyone's readings
+        if output.sinks > 0 {
+            return Ok(());
+        }
+        OUTPUTS.lock().unwrap().remove(&self.path);
+
+        output
+            .finish()
+            .map_err(|e| format!("could not finish {}: {}", self.path, e).into())
+    }
+}
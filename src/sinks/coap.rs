@@ -0,0 +1,632 @@
+use crate::args::{CoapArgs, CoapFormat};
+use crate::encoding::FormattedReading;
+use crate::sensor::SensorOutput;
+use crate::sinks::Sink;
+use crate::timestamp::{TimestampFormat, Timezone};
+use std::collections::BTreeMap;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+const VERSION: u8 = 1;
+
+// message types
+const CONFIRMABLE: u8 = 0;
+const NON_CONFIRMABLE: u8 = 1;
+const ACKNOWLEDGEMENT: u8 = 2;
+const RESET: u8 = 3;
+
+// codes, written class.detail in the rfc and sent as class << 5 | detail
+const EMPTY: u8 = 0x00;
+const GET: u8 = 0x01;
+const CONTENT: u8 = 0x45;
+const BAD_REQUEST: u8 = 0x80;
+const BAD_OPTION: u8 = 0x82;
+const NOT_FOUND: u8 = 0x84;
+const METHOD_NOT_ALLOWED: u8 = 0x85;
+const NOT_ACCEPTABLE: u8 = 0x86;
+const SERVICE_UNAVAILABLE: u8 = 0xa3;
+
+// option numbers
+const OBSERVE: u16 = 6;
+const URI_PATH: u16 = 11;
+const CONTENT_FORMAT: u16 = 12;
+const ACCEPT: u16 = 17;
+// critical options a request may carry that don't change the answer here: uri-host, uri-port, uri-query and
+// block2, since every payload fits in one datagram anyway. any other critical option is refused
+const HARMLESS_OPTIONS: [u16; 4] = [3, 7, 15, 23];
+
+// content formats
+const LINK_FORMAT: u16 = 40;
+const JSON: u16 = 50;
+const CBOR: u16 = 60;
+
+// observe sequence numbers are 24 bits
+const MAX_SEQUENCE: u32 = 0xff_ffff;
+
+/// an option number and its value
+type CoapOption = (u16, Vec<u8>);
+
+/// a coap message, as described in rfc 7252
+#[derive(Debug, Default)]
+struct Message {
+    kind: u8,
+    code: u8,
+    message_id: u16,
+    token: Vec<u8>,
+    /// kept sorted by option number, which is the order they are sent in
+    options: Vec<CoapOption>,
+    payload: Vec<u8>,
+}
+
+impl Message {
+    fn parse(bytes: &[u8]) -> Option<Message> {
+        if bytes.len() < 4 || bytes[0] >> 6 != VERSION {
+            return None;
+        }
+        let token_length = (bytes[0] & 0x0f) as usize;
+        if token_length > 8 {
+            return None;
+        }
+
+        let mut message = Message {
+            kind: (bytes[0] >> 4) & 0x03,
+            code: bytes[1],
+            message_id: u16::from_be_bytes([bytes[2], bytes[3]]),
+            token: bytes.get(4..4 + token_length)?.to_vec(),
+            ..Default::default()
+        };
+
+        let mut i: usize = 4 + token_length;
+        let mut number: u16 = 0;
+        while i < bytes.len() {
+            if bytes[i] == 0xff {
+                message.payload = bytes[i + 1..].to_vec();
+                // a payload marker with nothing after it is a format error
+                if message.payload.is_empty() {
+                    return None;
+                }
+                break;
+            }
+
+            let delta = bytes[i] >> 4;
+            let length = bytes[i] & 0x0f;
+            i += 1;
+            let delta = extended(delta, bytes, &mut i)?;
+            let length = extended(length, bytes, &mut i)? as usize;
+
+            number = number.checked_add(delta)?;
+            message
+                .options
+                .push((number, bytes.get(i..i + length)?.to_vec()));
+            i += length;
+        }
+
+        Some(message)
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes: Vec<u8> = vec![
+            VERSION << 6 | self.kind << 4 | self.token.len() as u8,
+            self.code,
+        ];
+        bytes.extend_from_slice(&self.message_id.to_be_bytes());
+        bytes.extend_from_slice(&self.token);
+
+        let mut previous: u16 = 0;
+        for (number, value) in &self.options {
+            let (delta, delta_extension) = nibble((number - previous) as usize)?;
+            let (length, length_extension) = nibble(value.len())?;
+            bytes.push(delta << 4 | length);
+            bytes.extend_from_slice(&delta_extension);
+            bytes.extend_from_slice(&length_extension);
+            bytes.extend_from_slice(value);
+            previous = *number;
+        }
+
+        if !self.payload.is_empty() {
+            bytes.push(0xff);
+            bytes.extend_from_slice(&self.payload);
+        }
+
+        Ok(bytes)
+    }
+
+    fn option(&self, number: u16) -> Option<&[u8]> {
+        self.options
+            .iter()
+            .find(|(n, _)| *n == number)
+            .map(|(_, value)| value.as_slice())
+    }
+}
+
+/// reads the extended form of an option delta or length, which follows the option's first byte
+fn extended(nibble: u8, bytes: &[u8], i: &mut usize) -> Option<u16> {
+    match nibble {
+        0..=12 => Some(nibble as u16),
+        13 => {
+            let value = *bytes.get(*i)? as u16 + 13;
+            *i += 1;
+            Some(value)
+        }
+        14 => {
+            let value =
+                u16::from_be_bytes([*bytes.get(*i)?, *bytes.get(*i + 1)?]).checked_add(269)?;
+            *i += 2;
+            Some(value)
+        }
+        // 15 is reserved for the payload marker
+        _ => None,
+    }
+}
+
+/// the opposite of `extended`. two extra bytes on top of 269 is as far as it goes
+fn nibble(value: usize) -> Result<(u8, Vec<u8>)> {
+    match value {
+        0..=12 => Ok((value as u8, vec![])),
+        13..=268 => Ok((13, vec![(value - 13) as u8])),
+        269..=65804 => Ok((14, ((value - 269) as u16).to_be_bytes().to_vec())),
+        _ => Err(format!(
+            "an option delta or length of {} is more than coap can encode",
+            value
+        )
+        .into()),
+    }
+}
+
+/// option values that are numbers are sent big endian with no leading zero bytes, so 0 is no bytes at all
+fn encode_uint(value: u32) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let first = bytes.iter().position(|byte| *byte != 0).unwrap_or(4);
+    bytes[first..].to_vec()
+}
+
+fn decode_uint(bytes: &[u8]) -> Option<u32> {
+    if bytes.len() > 4 {
+        return None;
+    }
+    Some(
+        bytes
+            .iter()
+            .fold(0, |value, byte| value << 8 | *byte as u32),
+    )
+}
+
+#[derive(Debug)]
+struct Observer {
+    address: SocketAddr,
+    token: Vec<u8>,
+    format: CoapFormat,
+    /// of the last notification sent, so a reset in reply to it can be matched up
+    message_id: u16,
+}
+
+#[derive(Debug)]
+struct Resource {
+    category: String,
+    /// the latest reading, encoded both ways so requests don't need to do it
+    json: Option<Vec<u8>>,
+    cbor: Option<Vec<u8>>,
+    sequence: u32,
+    observers: Vec<Observer>,
+}
+
+impl Resource {
+    fn payload(&self, format: CoapFormat) -> Option<&Vec<u8>> {
+        match format {
+            CoapFormat::Json => self.json.as_ref(),
+            CoapFormat::Cbor => self.cbor.as_ref(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Server {
+    socket: UdpSocket,
+    format: CoapFormat,
+    resources: BTreeMap<String, Resource>,
+    next_message_id: u16,
+}
+
+// one server per listen address, shared by every sensor in the process, so with `serve` all of them are
+// resources on the same endpoint
+static SERVERS: Mutex<BTreeMap<String, Arc<Mutex<Server>>>> = Mutex::new(BTreeMap::new());
+
+/// makes a sensor an observable resource at `/sensors/{id}` on a coap server.
+///
+/// a GET returns the latest reading, and a GET with the observe option also subscribes to a non-confirmable
+/// notification for every new one, until the client resets one of them or asks again without observe. payloads
+/// are the same fields as `output.json` with the timestamp in --coap-timestamp's format, as json or cbor
+/// depending on the accept option, falling back to --coap-format. the resources are listed at `/.well-known/core`
+#[derive(Debug)]
+pub struct CoapSink {
+    id: String,
+    timestamps: TimestampFormat,
+    server: Arc<Mutex<Server>>,
+}
+
+impl CoapSink {
+    pub fn new(args: &CoapArgs, id: &str, category: &str) -> Result<CoapSink> {
+        let address: &str = args
+            .coap_listen
+            .as_deref()
+            .ok_or("the coap server needs --coap-listen")?;
+
+        let server: Arc<Mutex<Server>> = {
+            let mut servers = SERVERS.lock().unwrap();
+            match servers.get(address) {
+                Some(server) => server.clone(),
+                None => {
+                    let server = start_server(address, args.coap_format)?;
+                    servers.insert(address.to_string(), server.clone());
+                    server
+                }
+            }
+        };
+
+        server.lock().unwrap().resources.insert(
+            id.to_string(),
+            Resource {
+                category: category.to_string(),
+                json: None,
+                cbor: None,
+                sequence: 0,
+                observers: vec![],
+            },
+        );
+
+        note!(
+            "coap: sensor {} is at coap://{}/sensors/{}",
+            id,
+            address,
+            id
+        );
+
+        Ok(CoapSink {
+            id: id.to_string(),
+            timestamps: args.coap_timestamp.clone(),
+            server,
+        })
+    }
+}
+
+impl Sink for CoapSink {
+    fn name(&self) -> &str {
+        "coap"
+    }
+
+    fn send(&mut self, reading: &SensorOutput) -> Result<()> {
+        let reading = FormattedReading::new(reading, &self.timestamps, &Timezone::Utc);
+        let json: Vec<u8> = serde_json::to_vec(&reading)?;
+        let mut cbor: Vec<u8> = vec![];
+        ciborium::into_writer(&reading, &mut cbor)?;
+
+        let mut server = self.server.lock().unwrap();
+        let Server {
+            socket,
+            resources,
+            next_message_id,
+            ..
+        } = &mut *server;
+        let resource = resources
+            .get_mut(&self.id)
+            .ok_or("sensor is missing from the coap server")?;
+
+        resource.json = Some(json);
+        resource.cbor = Some(cbor);
+        resource.sequence = (resource.sequence + 1) & MAX_SEQUENCE;
+
+        let sequence = resource.sequence;
+        let mut errors: Vec<String> = vec![];
+        for observer in &mut resource.observers {
+            *next_message_id = next_message_id.wrapping_add(1);
+            observer.message_id = *next_message_id;
+
+            let notification = Message {
+                kind: NON_CONFIRMABLE,
+                code: CONTENT,
+                message_id: observer.message_id,
+                token: observer.token.clone(),
+                options: vec![
+                    (OBSERVE, encode_uint(sequence)),
+                    (CONTENT_FORMAT, encode_uint(content_format(observer.format))),
+                ],
+                payload: match observer.format {
+                    CoapFormat::Json => resource.json.clone().unwrap(),
+                    CoapFormat::Cbor => resource.cbor.clone().unwrap(),
+                },
+            };
+
+            let sent = notification
+                .to_bytes()
+                .and_then(|bytes| Ok(socket.send_to(&bytes, observer.address)?));
+            if let Err(e) = sent {
+                errors.push(format!("could not notify {}: {}", observer.address, e));
+            }
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors.join(", ").into()),
+        }
+    }
+}
+
+fn content_format(format: CoapFormat) -> u32 {
+    match format {
+        CoapFormat::Json => JSON as u32,
+        CoapFormat::Cbor => CBOR as u32,
+    }
+}
+
+fn start_server(address: &str, format: CoapFormat) -> Result<Arc<Mutex<Server>>> {
+    let socket = UdpSocket::bind(address)
+        .map_err(|e| format!("could not listen for coap on {}: {}", address, e))?;
+    let receiver = socket.try_clone()?;
+
+    let server = Arc::new(Mutex::new(Server {
+        socket,
+        format,
+        resources: BTreeMap::new(),
+        next_message_id: rand::random(),
+    }));
+
+    let served = server.clone();
+    std::thread::spawn(move || {
+        // big enough for any request a client would reasonably send
+        let mut buffer = [0u8; 1500];
+        loop {
+            let (length, from) = match receiver.recv_from(&mut buffer) {
+                Ok(received) => received,
+                // e.g. an icmp port unreachable from an observer that went away
+                Err(..) => continue,
+            };
+
+            // anything that isn't a coap message is silently ignored, as the rfc asks
+            if let Some(message) = Message::parse(&buffer[..length]) {
+                served.lock().unwrap().handle(message, from);
+            }
+        }
+    });
+
+    Ok(server)
+}
+
+impl Server {
+    fn handle(&mut self, message: Message, from: SocketAddr) {
+        match (message.kind, message.code) {
+            // an empty confirmable message is a ping, answered with a reset
+            (CONFIRMABLE, EMPTY) => {
+                let reset = Message {
+                    kind: RESET,
+                    message_id: message.message_id,
+                    ..Default::default()
+                };
+                if let Ok(bytes) = reset.to_bytes() {
+                    _ = self.socket.send_to(&bytes, from);
+                }
+            }
+            // a reset in reply to a notification means the client has lost interest
+            (RESET, _) => {
+                for resource in self.resources.values_mut() {
+                    resource.observers.retain(|observer| {
+                        observer.address != from || observer.message_id != message.message_id
+                    });
+                }
+            }
+            // requests are codes in class 0
+            (CONFIRMABLE | NON_CONFIRMABLE, code) if code >> 5 == 0 && code != EMPTY => {
+                let (code, options, payload) = self.answer(&message, from);
+
+                // confirmable requests get the answer piggybacked on the acknowledgement
+                let kind = match message.kind {
+                    CONFIRMABLE => ACKNOWLEDGEMENT,
+                    _ => NON_CONFIRMABLE,
+                };
+                let message_id = match message.kind {
+                    CONFIRMABLE => message.message_id,
+                    _ => {
+                        self.next_message_id = self.next_message_id.wrapping_add(1);
+                        self.next_message_id
+                    }
+                };
+
+                let response = Message {
+                    kind,
+                    code,
+                    message_id,
+                    token: message.token,
+                    options,
+                    payload,
+                };
+                if let Ok(bytes) = response.to_bytes() {
+                    _ = self.socket.send_to(&bytes, from);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// works out the response code, options and payload for a request. errors carry a short diagnostic
+    /// message as their payload
+    fn answer(&mut self, request: &Message, from: SocketAddr) -> (u8, Vec<CoapOption>, Vec<u8>) {
+        let error = |code: u8, message: &str| (code, vec![], message.as_bytes().to_vec());
+
+        if let Some((number, _)) = request.options.iter().find(|(number, _)| {
+            number % 2 == 1
+                && ![URI_PATH, ACCEPT].contains(number)
+                && !HARMLESS_OPTIONS.contains(number)
+        }) {
+            return error(BAD_OPTION, &format!("option {} is not supported", number));
+        }
+
+        if request.code != GET {
+            return error(METHOD_NOT_ALLOWED, "only GET is supported");
+        }
+
+        let path: Vec<String> = request
+            .options
+            .iter()
+            .filter(|(number, _)| *number == URI_PATH)
+            .map(|(_, segment)| String::from_utf8_lossy(segment).into_owned())
+            .collect();
+
+        if path == [".well-known", "core"] {
+            let links: Vec<String> = self
+                .resources
+                .iter()
+                .map(|(id, resource)| {
+                    format!(
+                        "</sensors/{}>;rt=\"{}\";obs;ct=\"{} {}\"",
+                        id, resource.category, JSON, CBOR
+                    )
+                })
+                .collect();
+            return (
+                CONTENT,
+                vec![(CONTENT_FORMAT, encode_uint(LINK_FORMAT as u32))],
+                links.join(",").into_bytes(),
+            );
+        }
+
+        let resource: &mut Resource = match path.as_slice() {
+            [sensors, id] if sensors == "sensors" => match self.resources.get_mut(id) {
+                Some(resource) => resource,
+                None => return error(NOT_FOUND, &format!("no sensor with id {}", id)),
+            },
+            _ => return error(NOT_FOUND, "resources are at /sensors/{id}"),
+        };
+
+        let format: CoapFormat = match request.option(ACCEPT).map(decode_uint) {
+            None => self.format,
+            Some(Some(accept)) if accept == JSON as u32 => CoapFormat::Json,
+            Some(Some(accept)) if accept == CBOR as u32 => CoapFormat::Cbor,
+            Some(..) => {
+                return error(
+                    NOT_ACCEPTABLE,
+                    "readings are available as json (50) or cbor (60)",
+                );
+            }
+        };
+
+        let observe: Option<u32> = match request.option(OBSERVE).map(decode_uint) {
+            None => None,
+            Some(Some(observe)) => Some(observe),
+            Some(None) => return error(BAD_REQUEST, "observe should be 0 or 1"),
+        };
+
+        let payload: Vec<u8> = match resource.payload(format) {
+            Some(payload) => payload.clone(),
+            None => return error(SERVICE_UNAVAILABLE, "no readings yet"),
+        };
+
+        // any other GET from the same client and token replaces its subscription, or ends it
+        resource
+            .observers
+            .retain(|observer| observer.address != from || observer.token != request.token);
+
+        let mut options: Vec<CoapOption> = vec![];
+        if observe == Some(0) {
+            resource.observers.push(Observer {
+                address: from,
+                token: request.token.clone(),
+                format,
+                message_id: 0,
+            });
+            options.push((OBSERVE, encode_uint(resource.sequence)));
+        }
+        options.push((CONTENT_FORMAT, encode_uint(content_format(format))));
+
+        (CONTENT, options, payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_survive_a_round_trip() {
+        let message = Message {
+            kind: CONFIRMABLE,
+            code: CONTENT,
+            message_id: 0x1234,
+            token: vec![0xaa, 0xbb],
+            options: vec![
+                (OBSERVE, encode_uint(70_000)),
+                (URI_PATH, b"temperature".to_vec()),
+                // a 13 nibble for the length, and a 14 for the delta
+                (URI_PATH, vec![b'x'; 20]),
+                (2000, vec![b'y'; 300]),
+            ],
+            payload: b"{}".to_vec(),
+        };
+
+        let parsed = Message::parse(&message.to_bytes().unwrap()).unwrap();
+
+        assert_eq!(parsed.kind, message.kind);
+        assert_eq!(parsed.code, message.code);
+        assert_eq!(parsed.message_id, message.message_id);
+        assert_eq!(parsed.token, message.token);
+        assert_eq!(parsed.options, message.options);
+        assert_eq!(parsed.payload, message.payload);
+        assert_eq!(decode_uint(parsed.option(OBSERVE).unwrap()), Some(70_000));
+    }
+
+    #[test]
+    fn observe_registration_is_read() {
+        // con GET, token 0xaa, observe 0 (no bytes), uri-path "temperature"
+        let mut bytes: Vec<u8> = vec![0x41, GET, 0x12, 0x34, 0xaa, 0x60, 0x5b];
+        bytes.extend_from_slice(b"temperature");
+
+        let message = Message::parse(&bytes).unwrap();
+
+        assert_eq!(message.kind, CONFIRMABLE);
+        assert_eq!(message.token, [0xaa]);
+        assert_eq!(decode_uint(message.option(OBSERVE).unwrap()), Some(0));
+        assert_eq!(message.option(URI_PATH), Some(&b"temperature"[..]));
+        assert!(message.payload.is_empty());
+    }
+
+    #[test]
+    fn extended_nibbles_are_written_at_their_boundaries() {
+        assert_eq!(nibble(12).unwrap(), (12, vec![]));
+        assert_eq!(nibble(13).unwrap(), (13, vec![0]));
+        assert_eq!(nibble(268).unwrap(), (13, vec![255]));
+        assert_eq!(nibble(269).unwrap(), (14, vec![0, 0]));
+        assert_eq!(nibble(65804).unwrap(), (14, vec![255, 255]));
+        assert!(nibble(65805).is_err());
+    }
+
+    #[test]
+    fn options_too_long_to_encode_are_an_error() {
+        let message = Message {
+            options: vec![(URI_PATH, vec![0; 65805])],
+            ..Default::default()
+        };
+
+        assert!(message.to_bytes().is_err());
+    }
+
+    #[test]
+    fn reserved_nibbles_are_rejected() {
+        // a delta of 15, and then a length of 15
+        assert!(Message::parse(&[0x40, GET, 0, 1, 0xf1, 0]).is_none());
+        assert!(Message::parse(&[0x40, GET, 0, 1, 0x1f, 0]).is_none());
+    }
+
+    #[test]
+    fn truncated_messages_are_rejected() {
+        // an option longer than what is left
+        assert!(Message::parse(&[0x40, GET, 0, 1, 0xb5, b'a', b'b']).is_none());
+        // a two byte extended delta with only one byte
+        assert!(Message::parse(&[0x40, GET, 0, 1, 0xe0, 0x01]).is_none());
+        // a token longer than the message
+        assert!(Message::parse(&[0x42, GET, 0, 1, 0xaa]).is_none());
+        // a payload marker with no payload
+        assert!(Message::parse(&[0x40, GET, 0, 1, 0xff]).is_none());
+        // not coap version 1
+        assert!(Message::parse(&[0x80, GET, 0, 1]).is_none());
+    }
+}
@@ -0,0 +1,124 @@
+use crate::args::DuckDbArgs;
+use crate::sensor::SensorOutput;
+use crate::sinks::Sink;
+use crate::utils::serialize_unit;
+use duckdb::Connection;
+use duckdb::types::{TimeUnit, Value};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+const SCHEMA: &str = "
+    create table if not exists readings (
+        id varchar not null,
+        timestamp timestamp not null,
+        value real not null,
+        unit varchar not null,
+        symbol varchar not null
+    );";
+
+/// a database file and the sinks writing to it
+#[derive(Debug)]
+struct Database {
+    connection: Connection,
+    sinks: usize,
+    parquet: Option<String>,
+}
+
+// a duckdb file can only be opened once per process, so with `serve` every sensor shares the one connection
+static DATABASES: Mutex<BTreeMap<String, Arc<Mutex<Database>>>> = Mutex::new(BTreeMap::new());
+
+/// writes readings to a duckdb database file, for querying with sql straight after (or during) a run.
+///
+/// `readings` has the same columns as the sqlite table, except the timestamp is a real `timestamp` (in utc)
+/// rather than text. readings are added to what is already in the file. with --duckdb-parquet the whole table
+/// is exported to a parquet file by duckdb when the run finishes
+#[derive(Debug)]
+pub struct DuckDbSink {
+    path: String,
+    database: Arc<Mutex<Database>>,
+}
+
+impl DuckDbSink {
+    pub fn new(args: &DuckDbArgs) -> Result<DuckDbSink> {
+        let path: String = args
+            .duckdb
+            .clone()
+            .ok_or("the duckdb sink needs --duckdb")?;
+
+        let database: Arc<Mutex<Database>> = {
+            let mut databases = DATABASES.lock().unwrap();
+            match databases.get(&path) {
+                Some(database) => database.clone(),
+                None => {
+                    let connection = Connection::open(&path)
+                        .map_err(|e| format!("could not open duckdb database {}: {}", path, e))?;
+                    connection.execute_batch(SCHEMA)?;
+
+                    let database = Arc::new(Mutex::new(Database {
+                        connection,
+                        sinks: 0,
+                        parquet: args.duckdb_parquet.clone(),
+                    }));
+                    databases.insert(path.clone(), database.clone());
+                    database
+                }
+            }
+        };
+        database.lock().unwrap().sinks += 1;
+
+        Ok(DuckDbSink { path, database })
+    }
+}
+
+impl Sink for DuckDbSink {
+    fn name(&self) -> &str {
+        "duckdb"
+    }
+
+    fn send(&mut self, reading: &SensorOutput) -> Result<()> {
+        let timestamp = Value::Timestamp(
+            TimeUnit::Microsecond,
+            (reading.timestamp.unix_timestamp_nanos() / 1000) as i64,
+        );
+
+        let database = self.database.lock().unwrap();
+        database
+            .connection
+            .prepare_cached("insert into readings values (?, ?, ?, ?, ?)")?
+            .execute(duckdb::params![
+                reading.id,
+                timestamp,
+                reading.value,
+                serialize_unit(&reading.unit),
+                reading.symbol,
+            ])?;
+
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        let mut database = self.database.lock().unwrap();
+        database.sinks -= 1;
+
+        // the export waits for the last sensor, so it has everyone's readings
+        if database.sinks > 0 {
+            return Ok(());
+        }
+        DATABASES.lock().unwrap().remove(&self.path);
+
+        if let Some(parquet) = &database.parquet {
+            database
+                .connection
+                .execute_batch(&format!(
+                    "copy readings to '{}' (format parquet)",
+                    parquet.replace('\'', "''")
+                ))
+                .map_err(|e| format!("could not export readings to {}: {}", parquet, e))?;
+            note!("duckdb: exported readings to {}", parquet);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,207 @@
+use crate::args::{FifoArgs, FifoMode, PayloadFormat};
+use crate::buffer::RingBuffer;
+use crate::encoding::encode_frame;
+use crate::sensor::SensorOutput;
+use crate::sinks::Sink;
+use crate::timestamp::TimestampFormat;
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::ErrorKind;
+use std::io::prelude::*;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::path::PathBuf;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// writes readings into a named pipe, one per line, for another process to read.
+///
+/// the fifo is made if it doesn't exist, and removed again at the end if it was made here. what happens when
+/// nobody is reading depends on --fifo-mode: `block` waits for a reader, holding up the sensor, `drop` throws
+/// readings away until one turns up, and `buffer` keeps the latest --fifo-buffer readings and sends them first
+/// once one does. a reader going away is the same as there not being one yet - rust ignores SIGPIPE, so it only
+/// shows up as an error from the write, never as a crash. lines still in the pipe when a reader goes away are
+/// lost with it, as they had already been written. lines are shorter than PIPE_BUF, so the pipe always takes a
+/// whole line or none of it and a reader never sees half of one
+#[derive(Debug)]
+pub struct FifoSink {
+    path: PathBuf,
+    format: PayloadFormat,
+    timestamps: TimestampFormat,
+    mode: FifoMode,
+    created: bool,
+    fifo: Option<File>,
+    pending: RingBuffer<Vec<u8>>,
+}
+
+impl FifoSink {
+    pub fn new(args: &FifoArgs) -> Result<FifoSink> {
+        let path: PathBuf = args
+            .fifo
+            .clone()
+            .ok_or("the fifo sink needs --fifo")?
+            .into();
+
+        let created = match std::fs::metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_fifo() => false,
+            Ok(..) => return Err(format!("{} exists and is not a fifo", path.display()).into()),
+            Err(..) => {
+                let c_path = CString::new(path.as_os_str().as_bytes())?;
+                if unsafe { libc::mkfifo(c_path.as_ptr(), 0o644) } != 0 {
+                    return Err(format!(
+                        "could not make a fifo at {}: {}",
+                        path.display(),
+                        std::io::Error::last_os_error()
+                    )
+                    .into());
+                }
+                true
+            }
+        };
+
+        note!("fifo: writing readings to {}", path.display());
+
+        Ok(FifoSink {
+            path,
+            format: args.fifo_format,
+            timestamps: args.fifo_timestamp.clone(),
+            mode: args.fifo_mode,
+            created,
+            fifo: None,
+            pending: RingBuffer::with_capacity(args.fifo_buffer.max(1)),
+        })
+    }
+
+    /// opens the fifo if a reader has it open, without waiting for one
+    fn try_open(&mut self) -> Result<()> {
+        if self.fifo.is_some() {
+            return Ok(());
+        }
+
+        match OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&self.path)
+        {
+            Ok(fifo) => {
+                note!("fifo: a reader opened {}", self.path.display());
+                self.fifo = Some(fifo);
+                Ok(())
+            }
+            // no reader yet
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => Ok(()),
+            Err(e) => Err(format!("could not open {}: {}", self.path.display(), e).into()),
+        }
+    }
+
+    /// writes one line to the open fifo, returning whether the reader took it
+    fn write(&mut self, line: &[u8]) -> Result<bool> {
+        let fifo = match &mut self.fifo {
+            Some(fifo) => fifo,
+            None => return Ok(false),
+        };
+
+        match fifo.write_all(line) {
+            Ok(..) => Ok(true),
+            // the reader is there but isn't keeping up, and the pipe is full
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) if e.kind() == ErrorKind::BrokenPipe => {
+                note!("fifo: the reader closed {}", self.path.display());
+                self.fifo = None;
+                Ok(false)
+            }
+            Err(e) => {
+                self.fifo = None;
+                Err(format!("could not write to {}: {}", self.path.display(), e).into())
+            }
+        }
+    }
+
+    /// sends as much of the buffer as the reader will take, oldest first
+    fn drain(&mut self) -> Result<()> {
+        self.try_open()?;
+
+        while let Some(line) = self.pending.iter().next().cloned() {
+            if !self.write(&line)? {
+                break;
+            }
+            self.pending.pop_front();
+        }
+
+        Ok(())
+    }
+
+    /// waits for a reader and for room in the pipe, as long as it takes
+    fn write_blocking(&mut self, line: &[u8]) -> Result<()> {
+        loop {
+            if self.fifo.is_none() {
+                // a blocking open only returns once there is a reader
+                self.fifo = Some(
+                    OpenOptions::new()
+                        .write(true)
+                        .open(&self.path)
+                        .map_err(|e| format!("could not open {}: {}", self.path.display(), e))?,
+                );
+            }
+
+            if self.write(line)? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl Sink for FifoSink {
+    fn name(&self) -> &str {
+        "fifo"
+    }
+
+    fn send(&mut self, reading: &SensorOutput) -> Result<()> {
+        let line = encode_frame(reading, &self.format, &self.timestamps)?;
+
+        match self.mode {
+            FifoMode::Block => self.write_blocking(&line),
+            FifoMode::Drop => {
+                self.try_open()?;
+                self.write(&line)?;
+                Ok(())
+            }
+            FifoMode::Buffer => {
+                let dropped = self.pending.push(line);
+                self.drain()?;
+
+                match dropped {
+                    Some(..) => Err(format!(
+                        "nobody has read {} for too long, the oldest buffered reading was dropped",
+                        self.path.display()
+                    )
+                    .into()),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+
+    fn close(&mut self) -> Result<()> {
+        let result = match self.mode {
+            FifoMode::Buffer => self.drain().and_then(|_| match self.pending.len() {
+                0 => Ok(()),
+                n => Err(format!(
+                    "{} readings were never read from {}",
+                    n,
+                    self.path.display()
+                )
+                .into()),
+            }),
+            _ => Ok(()),
+        };
+
+        // closing is what tells the reader there is nothing more to come
+        self.fifo = None;
+        if self.created {
+            std::fs::remove_file(&self.path)?;
+        }
+
+        result
+    }
+}
@@ -0,0 +1,72 @@
+use crate::args::InfluxArgs;
+use crate::sinks::webhook::{BodyFormat, Endpoint, WebhookSink};
+use std::time::Duration;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+const RETRIES: u32 = 5;
+const BACKOFF: Duration = Duration::from_secs(1);
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// writes readings to influxdb's `/api/v2/write` endpoint as line protocol.
+///
+/// this is the webhook sink with a different body and url - influx answers a bad write with a 4xx and an
+/// overloaded one with 429 or 503, which is exactly what the webhook retries are built around
+pub fn new_influx_sink(args: &InfluxArgs) -> Result<WebhookSink> {
+    let base_url: &str = args
+        .influx_url
+        .as_deref()
+        .ok_or("the influxdb sink needs --influx-url")?;
+    let bucket: &str = args
+        .influx_bucket
+        .as_deref()
+        .ok_or("--influx-url needs a --influx-bucket to write to")?;
+
+    let mut url: String = format!(
+        "{}/api/v2/write?bucket={}&precision=ns",
+        base_url.trim_end_matches('/'),
+        url_encode(bucket)
+    );
+    if let Some(org) = &args.influx_org {
+        url.push_str(&format!("&org={}", url_encode(org)));
+    }
+
+    // a local instance might have auth switched off, so a missing token is only a warning
+    let mut headers: Vec<(String, String)> = vec![];
+    match std::env::var(&args.influx_token_env) {
+        Ok(token) => headers.push(("Authorization".to_string(), format!("Token {}", token))),
+        Err(..) => eprintln!(
+            "influxdb: {} is not set, so writes are sent without a token",
+            args.influx_token_env
+        ),
+    }
+
+    let endpoint = Endpoint {
+        url,
+        headers,
+        retries: RETRIES,
+        backoff: BACKOFF,
+        timeout: TIMEOUT,
+        dead_letter: None,
+    };
+
+    Ok(WebhookSink::start(
+        "influxdb",
+        endpoint,
+        BodyFormat::LineProtocol,
+        args.influx_batch_size as usize,
+    ))
+}
+
+/// percent encodes everything but the characters that are always safe in a query string
+fn url_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
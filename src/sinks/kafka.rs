@@ -0,0 +1,193 @@
+use crate::args::{KafkaAcks, KafkaArgs, PayloadFormat};
+use crate::encoding::encode;
+use crate::sensor::SensorOutput;
+use crate::sinks::{Sink, fill_template};
+use crate::timestamp::TimestampFormat;
+use rdkafka::ClientConfig;
+use rdkafka::client::ClientContext;
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use rdkafka::producer::{BaseProducer, BaseRecord, DeliveryResult, Producer, ProducerContext};
+use std::sync::Mutex;
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::time::Duration;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// how long to wait for the brokers when starting up, and for queued records when the run finishes
+const METADATA_TIMEOUT: Duration = Duration::from_secs(10);
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// collects the records librdkafka gave up on, for the sink to report
+struct DeliveryReports {
+    failures: Mutex<Sender<String>>,
+}
+
+impl ClientContext for DeliveryReports {}
+
+impl ProducerContext for DeliveryReports {
+    type DeliveryOpaque = ();
+
+    fn delivery(&self, result: &DeliveryResult<'_>, _: ()) {
+        if let Err((e, _)) = result {
+            _ = self.failures.lock().unwrap().send(e.to_string());
+        }
+    }
+}
+
+/// produces readings to a kafka topic.
+///
+/// each reading is one record, keyed by the sensor id unless --kafka-key says otherwise, so all of a sensor's
+/// readings land on the same partition and stay in order. librdkafka does the batching in the background,
+/// sending up to --kafka-batch-size records at a time and waiting at most --kafka-linger-ms for a batch to fill,
+/// and retries until the message timeout. records that still aren't delivered are reported as errors from the
+/// next `send`, so they show up in the sensor's sink error count like any other failure
+pub struct KafkaSink {
+    producer: BaseProducer<DeliveryReports>,
+    topic: String,
+    key: String,
+    format: PayloadFormat,
+    timestamps: TimestampFormat,
+    failures: Receiver<String>,
+}
+
+impl std::fmt::Debug for KafkaSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KafkaSink")
+            .field("topic", &self.topic)
+            .field("key", &self.key)
+            .field("format", &self.format)
+            .field("timestamps", &self.timestamps)
+            .finish_non_exhaustive()
+    }
+}
+
+impl KafkaSink {
+    pub fn new(args: &KafkaArgs, id: &str, category: &str) -> Result<KafkaSink> {
+        if args.kafka_brokers.is_empty() {
+            return Err("the kafka sink needs --kafka-brokers".into());
+        }
+
+        let mut config = ClientConfig::new();
+        config
+            .set("bootstrap.servers", args.kafka_brokers.join(","))
+            .set("client.id", "sensor_simulator")
+            .set("linger.ms", args.kafka_linger_ms.to_string())
+            .set("batch.num.messages", args.kafka_batch_size.to_string())
+            .set(
+                "acks",
+                match args.kafka_acks {
+                    KafkaAcks::None => "0",
+                    KafkaAcks::Leader => "1",
+                    KafkaAcks::All => "all",
+                },
+            );
+
+        // anything else librdkafka understands, e.g. compression or sasl settings, overriding the above
+        for setting in &args.kafka_config {
+            let (name, value) = setting.split_once('=').ok_or_else(|| {
+                format!("kafka setting `{}` should look like `name=value`", setting)
+            })?;
+            config.set(name.trim(), value.trim());
+        }
+
+        let (sender, failures) = channel::<String>();
+        let producer: BaseProducer<DeliveryReports> =
+            config.create_with_context(DeliveryReports {
+                failures: Mutex::new(sender),
+            })?;
+
+        let topic: String = fill_template(&args.kafka_topic, id, category);
+
+        // fail now rather than on every reading if the brokers can't be reached. asking for the topic by name
+        // also gets a broker that auto creates topics to make it
+        producer
+            .client()
+            .fetch_metadata(Some(&topic), METADATA_TIMEOUT)
+            .map_err(|e| {
+                format!(
+                    "could not reach kafka at {}: {}",
+                    args.kafka_brokers.join(","),
+                    e
+                )
+            })?;
+
+        Ok(KafkaSink {
+            producer,
+            topic,
+            key: fill_template(&args.kafka_key, id, category),
+            format: args.kafka_format,
+            timestamps: args.kafka_timestamp.clone(),
+            failures,
+        })
+    }
+
+    /// everything librdkafka has failed to deliver since the last call, as one error
+    fn delivery_failures(&self) -> Result<()> {
+        let mut failures: Vec<String> = self.failures.try_iter().collect();
+        let count: usize = failures.len();
+        // a broker going away fails a whole batch with the same error, which only needs saying once
+        failures.dedup();
+
+        match count {
+            0 => Ok(()),
+            1 => Err(format!(
+                "a reading was not delivered to {}: {}",
+                self.topic, failures[0]
+            )
+            .into()),
+            n => Err(format!(
+                "{} readings were not delivered to {}: {}",
+                n,
+                self.topic,
+                failures.join(", ")
+            )
+            .into()),
+        }
+    }
+}
+
+impl Sink for KafkaSink {
+    fn name(&self) -> &str {
+        "kafka"
+    }
+
+    fn send(&mut self, reading: &SensorOutput) -> Result<()> {
+        let value: Vec<u8> = encode(reading, &self.format, &self.timestamps)?;
+
+        // an empty key is sent as no key at all, which spreads records across the partitions
+        let mut record: BaseRecord<'_, str, [u8]> = BaseRecord::to(&self.topic).payload(&value);
+        if !self.key.is_empty() {
+            record = record.key(&self.key);
+        }
+
+        let sent = self.producer.send(record);
+
+        // hands over delivery reports for anything that has finished since the last reading
+        self.producer.poll(Duration::ZERO);
+
+        match sent {
+            Ok(..) => self.delivery_failures(),
+            Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), _)) => {
+                Err("too many readings waiting for kafka, reading dropped".into())
+            }
+            Err((e, _)) => Err(format!("could not produce to {}: {}", self.topic, e).into()),
+        }
+    }
+
+    fn close(&mut self) -> Result<()> {
+        let flushed = self.producer.flush(FLUSH_TIMEOUT);
+        self.producer.poll(Duration::ZERO);
+
+        self.delivery_failures()?;
+        if flushed.is_err() {
+            return Err(format!(
+                "{} readings were still waiting for kafka after {}s",
+                self.producer.in_flight_count(),
+                FLUSH_TIMEOUT.as_secs()
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+}
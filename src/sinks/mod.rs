@@ -0,0 +1,167 @@
+use crate::args::Args;
+use crate::sensor::SensorOutput;
+
+pub mod arrow;
+pub mod coap;
+pub mod duckdb;
+#[cfg(unix)]
+pub mod fifo;
+pub mod influx;
+pub mod kafka;
+pub mod modbus;
+pub mod mqtt;
+pub mod postgres;
+#[cfg(unix)]
+pub mod pty;
+pub mod socket;
+pub mod stdout;
+pub mod syslog;
+pub mod webhook;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// somewhere readings are sent to as they are generated, on top of the file outputs.
+///
+/// a sink that fails doesn't stop the sensor - the error is reported and counted, and the next reading is
+/// sent as normal, so sinks that can recover (e.g. by reconnecting) should do that themselves. sinks are `Send`
+/// so a sensor can be handed to its own thread, as `serve` does
+pub trait Sink: std::fmt::Debug + Send {
+    /// short name used in error messages
+    fn name(&self) -> &str;
+
+    fn send(&mut self, reading: &SensorOutput) -> Result<()>;
+
+    /// called once when the run finishes, to flush anything buffered and say goodbye cleanly
+    fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// sets up every sink switched on by the command line arguments, for the sensor with the given id and category
+pub fn build_sinks(args: &Args, id: &str, category: &str) -> Result<Vec<Box<dyn Sink>>> {
+    let mut sinks: Vec<Box<dyn Sink>> = vec![];
+
+    // the console always comes first, so a reading is printed before anything slower is done with it. unless
+    // arrow is being written there, which would be corrupted by anything else
+    if args.arrow_args.arrow.as_deref() != Some("-") {
+        sinks.push(Box::new(stdout::StdoutSink::new(&args.output_args)?));
+    }
+
+    if args.mqtt_args.mqtt_host.is_some() {
+        sinks.push(Box::new(mqtt::MqttSink::new(
+            &args.mqtt_args,
+            id,
+            category,
+        )?));
+    }
+
+    if args.webhook_args.webhook_url.is_some() {
+        sinks.push(Box::new(webhook::WebhookSink::new(&args.webhook_args)?));
+    }
+
+    if args.influx_args.influx_url.is_some() {
+        sinks.push(Box::new(influx::new_influx_sink(&args.influx_args)?));
+    }
+
+    let sockets = &args.socket_args;
+    if let Some(address) = &sockets.tcp_connect {
+        sinks.push(Box::new(socket::StreamClientSink::new(
+            "tcp",
+            socket::Address::Tcp(address.clone()),
+            sockets.socket_format,
+            sockets.socket_timestamp.clone(),
+            sockets.socket_buffer,
+        )));
+    }
+    if let Some(address) = &sockets.tcp_listen {
+        sinks.push(Box::new(socket::StreamServerSink::new(
+            "tcp server",
+            socket::Address::Tcp(address.clone()),
+            sockets.socket_format,
+            sockets.socket_timestamp.clone(),
+        )?));
+    }
+    if let Some(address) = &sockets.udp {
+        sinks.push(Box::new(socket::UdpSink::new(
+            address,
+            sockets.socket_format,
+            sockets.socket_timestamp.clone(),
+        )?));
+    }
+    if let Some(path) = &sockets.unix_connect {
+        sinks.push(Box::new(socket::StreamClientSink::new(
+            "unix",
+            socket::Address::Unix(path.into()),
+            sockets.socket_format,
+            sockets.socket_timestamp.clone(),
+            sockets.socket_buffer,
+        )));
+    }
+    if let Some(path) = &sockets.unix_listen {
+        sinks.push(Box::new(socket::StreamServerSink::new(
+            "unix server",
+            socket::Address::Unix(path.into()),
+            sockets.socket_format,
+            sockets.socket_timestamp.clone(),
+        )?));
+    }
+
+    if !args.kafka_args.kafka_brokers.is_empty() {
+        sinks.push(Box::new(kafka::KafkaSink::new(
+            &args.kafka_args,
+            id,
+            category,
+        )?));
+    }
+
+    if args.duckdb_args.duckdb.is_some() {
+        sinks.push(Box::new(duckdb::DuckDbSink::new(&args.duckdb_args)?));
+    }
+
+    if args.arrow_args.arrow.is_some() {
+        sinks.push(Box::new(arrow::ArrowSink::new(&args.arrow_args)?));
+    }
+
+    let postgres = &args.postgres_args;
+    if postgres.postgres_url.is_some() || postgres.postgres_url_env.is_some() {
+        sinks.push(Box::new(postgres::PostgresSink::new(postgres, category)?));
+    }
+
+    let syslog = &args.syslog_args;
+    if syslog.syslog_udp.is_some() || syslog.syslog_socket.is_some() {
+        sinks.push(Box::new(syslog::SyslogSink::new(syslog, category)?));
+    }
+
+    if args.modbus_args.modbus_listen.is_some() {
+        sinks.push(Box::new(modbus::ModbusSink::new(&args.modbus_args, id)?));
+    }
+
+    if args.coap_args.coap_listen.is_some() {
+        sinks.push(Box::new(coap::CoapSink::new(
+            &args.coap_args,
+            id,
+            category,
+        )?));
+    }
+
+    if args.fifo_args.fifo.is_some() {
+        #[cfg(unix)]
+        sinks.push(Box::new(fifo::FifoSink::new(&args.fifo_args)?));
+        #[cfg(not(unix))]
+        return Err("--fifo is only available on unix".into());
+    }
+
+    if args.pty_args.pty {
+        #[cfg(unix)]
+        sinks.push(Box::new(pty::PtySink::new(&args.pty_args)?));
+        #[cfg(not(unix))]
+        return Err("--pty is only available on unix".into());
+    }
+
+    Ok(sinks)
+}
+
+/// fills `{id}` and `{category}` into a user supplied template such as an mqtt topic
+pub fn fill_template(template: &str, id: &str, category: &str) -> String {
+    template.replace("{id}", id).replace("{category}", category)
+}
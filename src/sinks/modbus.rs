@@ -0,0 +1,301 @@
+use crate::args::{ModbusArgs, RegisterEncoding, WordOrder};
+use crate::sensor::SensorOutput;
+use crate::sinks::Sink;
+use std::collections::BTreeMap;
+use std::io::prelude::*;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// registers given to each sensor: its value in 0-1, and a count of its readings in 2-3 so a poller can tell a
+/// new reading from a repeated one
+pub const REGISTERS_PER_SENSOR: usize = 4;
+// the most registers a single read may ask for, from the modbus spec
+const MAX_READ: u16 = 125;
+
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const READ_INPUT_REGISTERS: u8 = 0x04;
+const ILLEGAL_FUNCTION: u8 = 0x01;
+const ILLEGAL_DATA_ADDRESS: u8 = 0x02;
+const ILLEGAL_DATA_VALUE: u8 = 0x03;
+
+type Registers = Arc<Mutex<Vec<u16>>>;
+
+// one server per listen address, shared by every sensor in the process. with `serve` each sensor gets the next
+// block of registers on the same port, the way several measurements share one device on a real bus
+static SERVERS: Mutex<BTreeMap<String, Registers>> = Mutex::new(BTreeMap::new());
+
+/// keeps a sensor's registers on the modbus tcp server up to date with its latest reading.
+///
+/// the same registers are served as both holding (function 03) and input registers (function 04), since
+/// clients differ in which they expect read-only measurements in. the server is read-only - writes are refused
+/// with an illegal function exception, as a real sensor would
+#[derive(Debug)]
+pub struct ModbusSink {
+    registers: Registers,
+    first_register: usize,
+    encoding: RegisterEncoding,
+    scale: f32,
+    word_order: WordOrder,
+    readings: u32,
+}
+
+impl ModbusSink {
+    pub fn new(args: &ModbusArgs, id: &str) -> Result<ModbusSink> {
+        let address: &str = args
+            .modbus_listen
+            .as_deref()
+            .ok_or("the modbus server needs --modbus-listen")?;
+
+        let registers: Registers = {
+            let mut servers = SERVERS.lock().unwrap();
+            match servers.get(address) {
+                Some(registers) => registers.clone(),
+                None => {
+                    let registers = start_server(address)?;
+                    servers.insert(address.to_string(), registers.clone());
+                    registers
+                }
+            }
+        };
+
+        let first_register: usize = {
+            let mut registers = registers.lock().unwrap();
+            let first_register = registers.len();
+            registers.resize(first_register + REGISTERS_PER_SENSOR, 0);
+            first_register
+        };
+
+        note!(
+            "modbus: sensor {} is at registers {}-{} on {}",
+            id,
+            first_register,
+            first_register + REGISTERS_PER_SENSOR - 1,
+            address
+        );
+
+        Ok(ModbusSink {
+            registers,
+            first_register,
+            encoding: args.modbus_encoding,
+            scale: args.modbus_scale,
+            word_order: args.modbus_word_order,
+            readings: 0,
+        })
+    }
+}
+
+impl Sink for ModbusSink {
+    fn name(&self) -> &str {
+        "modbus"
+    }
+
+    fn send(&mut self, reading: &SensorOutput) -> Result<()> {
+        let value: u32 = match self.encoding {
+            RegisterEncoding::Float => reading.value.to_bits(),
+            RegisterEncoding::Scaled => {
+                let scaled = (reading.value * self.scale).round();
+                if scaled < i32::MIN as f32 || scaled > i32::MAX as f32 {
+                    return Err(format!(
+                        "{} scaled by {} doesn't fit in two registers",
+                        reading.value, self.scale
+                    )
+                    .into());
+                }
+                scaled as i32 as u32
+            }
+        };
+        self.readings = self.readings.wrapping_add(1);
+
+        let mut registers = self.registers.lock().unwrap();
+        let block = &mut registers[self.first_register..self.first_register + REGISTERS_PER_SENSOR];
+        block[..2].copy_from_slice(&words(value, self.word_order));
+        block[2..].copy_from_slice(&words(self.readings, self.word_order));
+
+        Ok(())
+    }
+}
+
+/// splits a 32 bit value across two registers. modbus itself only says how the bytes in a register are ordered,
+/// so devices differ in which half comes first
+fn words(value: u32, word_order: WordOrder) -> [u16; 2] {
+    let high = (value >> 16) as u16;
+    let low = value as u16;
+
+    match word_order {
+        WordOrder::Big => [high, low],
+        WordOrder::Little => [low, high],
+    }
+}
+
+fn start_server(address: &str) -> Result<Registers> {
+    let listener = TcpListener::bind(address)
+        .map_err(|e| format!("could not listen for modbus on {}: {}", address, e))?;
+    let registers: Registers = Arc::new(Mutex::new(vec![]));
+
+    let served = registers.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let registers = served.clone();
+            std::thread::spawn(move || {
+                // a client hanging up is the normal end of a connection, so there is nothing to report
+                _ = handle_client(stream, &registers);
+            });
+        }
+    });
+
+    Ok(registers)
+}
+
+/// answers requests from one client until it disconnects. clients usually keep the connection open and poll
+fn handle_client(mut stream: TcpStream, registers: &Registers) -> Result<()> {
+    loop {
+        // the mbap header: transaction id, protocol id (always 0), length of what follows, unit id
+        let mut header = [0u8; 7];
+        stream.read_exact(&mut header)?;
+
+        let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+        if length < 2 {
+            return Err("modbus request with no function code".into());
+        }
+
+        let mut pdu = vec![0u8; length - 1];
+        stream.read_exact(&mut pdu)?;
+
+        let response: Vec<u8> = respond(&pdu, registers);
+
+        // the transaction, protocol and unit ids are echoed back, so this answers to any unit id
+        let mut frame: Vec<u8> = Vec::with_capacity(7 + response.len());
+        frame.extend_from_slice(&header[..4]);
+        frame.extend_from_slice(&((response.len() + 1) as u16).to_be_bytes());
+        frame.push(header[6]);
+        frame.extend_from_slice(&response);
+
+        stream.write_all(&frame)?;
+    }
+}
+
+fn respond(pdu: &[u8], registers: &Registers) -> Vec<u8> {
+    let function: u8 = pdu[0];
+
+    if function != READ_HOLDING_REGISTERS && function != READ_INPUT_REGISTERS {
+        return exception(function, ILLEGAL_FUNCTION);
+    }
+    if pdu.len() != 5 {
+        return exception(function, ILLEGAL_DATA_VALUE);
+    }
+
+    let start = u16::from_be_bytes([pdu[1], pdu[2]]) as usize;
+    let count = u16::from_be_bytes([pdu[3], pdu[4]]);
+
+    if count == 0 || count > MAX_READ {
+        return exception(function, ILLEGAL_DATA_VALUE);
+    }
+
+    let registers = registers.lock().unwrap();
+    let end: usize = start + count as usize;
+    if end > registers.len() {
+        return exception(function, ILLEGAL_DATA_ADDRESS);
+    }
+
+    let mut response: Vec<u8> = vec![function, (count * 2) as u8];
+    for register in &registers[start..end] {
+        response.extend_from_slice(&register.to_be_bytes());
+    }
+
+    response
+}
+
+fn exception(function: u8, code: u8) -> Vec<u8> {
+    vec![function | 0x80, code]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registers(values: &[u16]) -> Registers {
+        Arc::new(Mutex::new(values.to_vec()))
+    }
+
+    #[test]
+    fn read_holding_registers_response_layout() {
+        let registers = registers(&[0x0102, 0x0304, 0x0506, 0x0708]);
+
+        // function, byte count, then each register high byte first
+        assert_eq!(
+            respond(&[READ_HOLDING_REGISTERS, 0, 1, 0, 2], &registers),
+            vec![READ_HOLDING_REGISTERS, 4, 0x03, 0x04, 0x05, 0x06]
+        );
+        assert_eq!(
+            respond(&[READ_INPUT_REGISTERS, 0, 0, 0, 1], &registers),
+            vec![READ_INPUT_REGISTERS, 2, 0x01, 0x02]
+        );
+    }
+
+    #[test]
+    fn float_word_order() {
+        // 21.5 is 0x41ac0000
+        let bits: u32 = 21.5f32.to_bits();
+        assert_eq!(words(bits, WordOrder::Big), [0x41ac, 0x0000]);
+        assert_eq!(words(bits, WordOrder::Little), [0x0000, 0x41ac]);
+
+        // and a sink's registers read back as the float in either order
+        for word_order in [WordOrder::Big, WordOrder::Little] {
+            let registers = registers(&words(bits, word_order));
+            let response = respond(&[READ_HOLDING_REGISTERS, 0, 0, 0, 2], &registers);
+            let first = u16::from_be_bytes([response[2], response[3]]) as u32;
+            let second = u16::from_be_bytes([response[4], response[5]]) as u32;
+            let value = match word_order {
+                WordOrder::Big => first << 16 | second,
+                WordOrder::Little => second << 16 | first,
+            };
+            assert_eq!(f32::from_bits(value), 21.5);
+        }
+    }
+
+    #[test]
+    fn illegal_function() {
+        let registers = registers(&[0; REGISTERS_PER_SENSOR]);
+
+        // write single register is refused, the server is read-only
+        assert_eq!(
+            respond(&[0x06, 0, 0, 0, 1], &registers),
+            vec![0x86, ILLEGAL_FUNCTION]
+        );
+    }
+
+    #[test]
+    fn illegal_data_address() {
+        let registers = registers(&[0; REGISTERS_PER_SENSOR]);
+
+        assert_eq!(
+            respond(&[READ_HOLDING_REGISTERS, 0, 4, 0, 1], &registers),
+            vec![0x83, ILLEGAL_DATA_ADDRESS]
+        );
+        // starting inside the registers but running past the end
+        assert_eq!(
+            respond(&[READ_INPUT_REGISTERS, 0, 3, 0, 2], &registers),
+            vec![0x84, ILLEGAL_DATA_ADDRESS]
+        );
+    }
+
+    #[test]
+    fn illegal_data_value() {
+        let registers = registers(&[0; REGISTERS_PER_SENSOR]);
+
+        assert_eq!(
+            respond(&[READ_HOLDING_REGISTERS, 0, 0, 0, 0], &registers),
+            vec![0x83, ILLEGAL_DATA_VALUE]
+        );
+        assert_eq!(
+            respond(&[READ_HOLDING_REGISTERS, 0, 0, 0, 126], &registers),
+            vec![0x83, ILLEGAL_DATA_VALUE]
+        );
+        assert_eq!(
+            respond(&[READ_HOLDING_REGISTERS, 0, 0], &registers),
+            vec![0x83, ILLEGAL_DATA_VALUE]
+        );
+    }
+}
@@ -0,0 +1,259 @@
+use crate::args::{MqttArgs, MqttVersion, PayloadFormat};
+use crate::encoding::encode;
+use crate::sensor::SensorOutput;
+use crate::sinks::{Sink, fill_template};
+use crate::timestamp::TimestampFormat;
+use rumqttc::v5::mqttbytes::v5::Packet as PacketV5;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+enum MqttClient {
+    V3(rumqttc::Client),
+    V5(rumqttc::v5::Client),
+}
+
+// the rumqttc clients don't implement Debug, and the sensor needs its sinks to
+impl std::fmt::Debug for MqttClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MqttClient::V3(..) => write!(f, "MqttClient::V3"),
+            MqttClient::V5(..) => write!(f, "MqttClient::V5"),
+        }
+    }
+}
+
+fn qos_v3(level: u8) -> rumqttc::QoS {
+    match level {
+        0 => rumqttc::QoS::AtMostOnce,
+        1 => rumqttc::QoS::AtLeastOnce,
+        _ => rumqttc::QoS::ExactlyOnce,
+    }
+}
+
+fn qos_v5(level: u8) -> rumqttc::v5::mqttbytes::QoS {
+    match level {
+        0 => rumqttc::v5::mqttbytes::QoS::AtMostOnce,
+        1 => rumqttc::v5::mqttbytes::QoS::AtLeastOnce,
+        _ => rumqttc::v5::mqttbytes::QoS::ExactlyOnce,
+    }
+}
+
+impl MqttClient {
+    // try_publish rather than publish: while the broker is unreachable messages queue up in the client's buffer,
+    // and once that is full we would rather drop a reading than hold up the sensor
+    fn publish(&self, topic: &str, qos: u8, retain: bool, payload: Vec<u8>) -> Result<()> {
+        match self {
+            MqttClient::V3(client) => client.try_publish(topic, qos_v3(qos), retain, payload)?,
+            MqttClient::V5(client) => client.try_publish(topic, qos_v5(qos), retain, payload)?,
+        }
+        Ok(())
+    }
+
+    fn disconnect(&self) -> Result<()> {
+        match self {
+            MqttClient::V3(client) => client.disconnect()?,
+            MqttClient::V5(client) => client.disconnect()?,
+        }
+        Ok(())
+    }
+}
+
+/// publishes every reading to an mqtt broker, the same way a real device on the network would.
+///
+/// the connection is run on a background thread which reconnects whenever the broker goes away. a retained
+/// "online" message is published to `<topic>/status` on every connect, and the broker is left a last will
+/// of "offline" on the same topic so subscribers can tell when the simulator dies without saying goodbye
+#[derive(Debug)]
+pub struct MqttSink {
+    client: MqttClient,
+    topic: String,
+    status_topic: String,
+    payload_format: PayloadFormat,
+    timestamps: TimestampFormat,
+    qos: u8,
+    retain: bool,
+    connection: Option<JoinHandle<()>>,
+}
+
+impl MqttSink {
+    pub fn new(args: &MqttArgs, id: &str, category: &str) -> Result<MqttSink> {
+        let host: String = args
+            .mqtt_host
+            .clone()
+            .ok_or("the mqtt sink needs --mqtt-host")?;
+        let topic: String = fill_template(&args.mqtt_topic, id, category);
+        let status_topic: String = format!("{}/status", topic);
+        let client_id: String = args
+            .mqtt_client_id
+            .clone()
+            .unwrap_or_else(|| format!("sensor_simulator_{}", id));
+
+        // a persistent session lets the broker and client resend qos 1 and 2 messages that were in flight when
+        // the connection dropped. qos 0 makes no delivery promises, so there is nothing to keep
+        let clean_session: bool = args.mqtt_qos == 0;
+
+        let (client, connection) = match args.mqtt_version {
+            MqttVersion::V3 => {
+                let mut options = rumqttc::MqttOptions::new(client_id, host, args.mqtt_port);
+                options.set_keep_alive(KEEP_ALIVE);
+                options.set_clean_session(clean_session);
+                options.set_last_will(rumqttc::LastWill::new(
+                    &status_topic,
+                    "offline",
+                    qos_v3(args.mqtt_qos),
+                    true,
+                ));
+
+                let (client, connection) = rumqttc::Client::new(options, args.mqtt_buffer);
+                let handle = spawn_connection_v3(
+                    connection,
+                    client.clone(),
+                    status_topic.clone(),
+                    args.mqtt_qos,
+                );
+
+                (MqttClient::V3(client), handle)
+            }
+            MqttVersion::V5 => {
+                let mut options = rumqttc::v5::MqttOptions::new(client_id, host, args.mqtt_port);
+                options.set_keep_alive(KEEP_ALIVE);
+                options.set_clean_start(clean_session);
+                options.set_last_will(rumqttc::v5::mqttbytes::v5::LastWill::new(
+                    &status_topic,
+                    "offline",
+                    qos_v5(args.mqtt_qos),
+                    true,
+                    None,
+                ));
+
+                let (client, connection) = rumqttc::v5::Client::new(options, args.mqtt_buffer);
+                let handle = spawn_connection_v5(
+                    connection,
+                    client.clone(),
+                    status_topic.clone(),
+                    args.mqtt_qos,
+                );
+
+                (MqttClient::V5(client), handle)
+            }
+        };
+
+        Ok(MqttSink {
+            client,
+            topic,
+            status_topic,
+            payload_format: args.mqtt_payload,
+            timestamps: args.mqtt_timestamp.clone(),
+            qos: args.mqtt_qos,
+            retain: args.mqtt_retain,
+            connection: Some(connection),
+        })
+    }
+}
+
+impl Sink for MqttSink {
+    fn name(&self) -> &str {
+        "mqtt"
+    }
+
+    fn send(&mut self, reading: &SensorOutput) -> Result<()> {
+        let payload = encode(reading, &self.payload_format, &self.timestamps)?;
+
+        self.client
+            .publish(&self.topic, self.qos, self.retain, payload)
+            .map_err(|e| format!("could not queue reading for {}: {}", self.topic, e).into())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        // a clean disconnect doesn't trigger the last will, so say we are going offline ourselves
+        self.client
+            .publish(&self.status_topic, self.qos, true, b"offline".to_vec())?;
+        self.client.disconnect()?;
+
+        // give the connection thread a moment to send whatever is still buffered
+        if let Some(handle) = self.connection.take() {
+            let started = Instant::now();
+            while !handle.is_finished() && started.elapsed() < CLOSE_TIMEOUT {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            if handle.is_finished() {
+                _ = handle.join();
+            } else {
+                eprintln!("mqtt: gave up waiting for buffered messages to be sent");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn spawn_connection_v3(
+    mut connection: rumqttc::Connection,
+    client: rumqttc::Client,
+    status_topic: String,
+    qos: u8,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut connected = false;
+
+        for event in connection.iter() {
+            match event {
+                Ok(rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(..))) => {
+                    if !connected {
+                        note!("mqtt: connected");
+                    }
+                    connected = true;
+                    _ = client.try_publish(&status_topic, qos_v3(qos), true, "online");
+                }
+                Ok(rumqttc::Event::Outgoing(rumqttc::Outgoing::Disconnect)) => break,
+                Ok(..) => (),
+                Err(e) => {
+                    if connected {
+                        eprintln!("mqtt: lost connection to the broker ({}), reconnecting", e);
+                    }
+                    connected = false;
+                    // iterating again reconnects, so all that is needed is to not spin while the broker is down
+                    std::thread::sleep(RECONNECT_DELAY);
+                }
+            }
+        }
+    })
+}
+
+fn spawn_connection_v5(
+    mut connection: rumqttc::v5::Connection,
+    client: rumqttc::v5::Client,
+    status_topic: String,
+    qos: u8,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut connected = false;
+
+        for event in connection.iter() {
+            match event {
+                Ok(rumqttc::v5::Event::Incoming(PacketV5::ConnAck(..))) => {
+                    if !connected {
+                        note!("mqtt: connected");
+                    }
+                    connected = true;
+                    _ = client.try_publish(&status_topic, qos_v5(qos), true, "online");
+                }
+                Ok(rumqttc::v5::Event::Outgoing(rumqttc::Outgoing::Disconnect)) => break,
+                Ok(..) => (),
+                Err(e) => {
+                    if connected {
+                        eprintln!("mqtt: lost connection to the broker ({}), reconnecting", e);
+                    }
+                    connected = false;
+                    std::thread::sleep(RECONNECT_DELAY);
+                }
+            }
+        }
+    })
+}
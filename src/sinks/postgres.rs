@@ -0,0 +1,251 @@
+use crate::args::PostgresArgs;
+use crate::sensor::SensorOutput;
+use crate::sinks::Sink;
+use crate::utils::serialize_unit;
+use postgres::{Client, Config, NoTls};
+use std::io::prelude::*;
+use std::sync::mpsc::{Receiver, Sender, SyncSender, TrySendError, channel, sync_channel};
+use std::thread::JoinHandle;
+use time::format_description::well_known::Rfc3339;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// batches waiting for the writer thread. past this the database is too far behind and batches are dropped
+const QUEUED_BATCHES: usize = 64;
+
+const SCHEMA: &str = "
+    create table if not exists sensors (
+        id varchar primary key,
+        category varchar not null,
+        unit varchar not null,
+        symbol varchar not null,
+        first_seen timestamptz not null default now()
+    );
+    create table if not exists readings (
+        id varchar not null,
+        timestamp timestamptz not null,
+        value real not null,
+        unit varchar not null,
+        symbol varchar not null
+    );";
+
+const HYPERTABLE: &str = "
+    create extension if not exists timescaledb;
+    select create_hypertable('readings', 'timestamp', if_not_exists => true, migrate_data => true);";
+
+const INDEX: &str =
+    "create index if not exists readings_id_timestamp_idx on readings (id, timestamp desc);";
+
+/// the sensor's row in the `sensors` table, written along with every batch in case it is the first
+#[derive(Debug, Clone)]
+struct SensorRow {
+    id: String,
+    category: String,
+    unit: &'static str,
+    symbol: String,
+}
+
+/// readings in postgres COPY text format, one per line, along with how many there are
+#[derive(Debug)]
+struct Batch {
+    sensor: SensorRow,
+    rows: String,
+    count: usize,
+}
+
+/// writes readings to postgres, or timescaledb with --postgres-hypertable.
+///
+/// `readings` has the same columns as the sqlite table, and `sensors` gets a row for every sensor that has
+/// written to it. both are created if they don't exist yet. readings are written in batches with COPY, which is
+/// much quicker than an insert per row, from a background thread so a slow database doesn't hold up the sensor.
+/// a batch that fails is reported as an error from the next `send` and dropped, and the connection is made
+/// again for the next one
+#[derive(Debug)]
+pub struct PostgresSink {
+    category: String,
+    batch: Option<Batch>,
+    batch_size: usize,
+    sender: Option<SyncSender<Batch>>,
+    worker: Option<JoinHandle<()>>,
+    errors: Receiver<String>,
+}
+
+impl PostgresSink {
+    pub fn new(args: &PostgresArgs, category: &str) -> Result<PostgresSink> {
+        // the url is best kept in the environment, since it usually has a password in it
+        let url: String = match (&args.postgres_url, &args.postgres_url_env) {
+            (Some(url), _) => url.clone(),
+            (None, Some(var)) => std::env::var(var)
+                .map_err(|_| format!("--postgres-url-env names {}, but it is not set", var))?,
+            (None, None) => {
+                return Err("the postgres sink needs --postgres-url or --postgres-url-env".into());
+            }
+        };
+
+        let config: Config = url
+            .parse()
+            .map_err(|e| format!("could not understand the postgres url: {}", e))?;
+        let mut client: Client = config
+            .connect(NoTls)
+            .map_err(|e| format!("could not connect to postgres: {}", describe(e)))?;
+
+        client.batch_execute(SCHEMA).map_err(describe)?;
+        if args.postgres_hypertable {
+            client.batch_execute(HYPERTABLE).map_err(|e| {
+                format!(
+                    "could not make readings a timescaledb hypertable: {}",
+                    describe(e)
+                )
+            })?;
+        }
+        client.batch_execute(INDEX).map_err(describe)?;
+
+        let (sender, receiver) = sync_channel::<Batch>(QUEUED_BATCHES);
+        let (error_sender, errors) = channel::<String>();
+        let worker = std::thread::spawn(move || run_worker(config, client, receiver, error_sender));
+
+        Ok(PostgresSink {
+            category: category.to_string(),
+            batch: None,
+            batch_size: args.postgres_batch_size as usize,
+            sender: Some(sender),
+            worker: Some(worker),
+            errors,
+        })
+    }
+
+    fn dispatch(&mut self) -> Result<()> {
+        let batch = match self.batch.take() {
+            Some(batch) => batch,
+            None => return Ok(()),
+        };
+
+        match self.sender.as_ref().unwrap().try_send(batch) {
+            Ok(..) => Ok(()),
+            Err(TrySendError::Full(batch)) | Err(TrySendError::Disconnected(batch)) => {
+                Err(format!(
+                    "too many batches waiting for postgres, {} readings dropped",
+                    batch.count
+                )
+                .into())
+            }
+        }
+    }
+
+    /// everything the writer thread has failed to write since the last call, as one error
+    fn write_errors(&self) -> Result<()> {
+        let errors: Vec<String> = self.errors.try_iter().collect();
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors.join(", ").into()),
+        }
+    }
+}
+
+impl Sink for PostgresSink {
+    fn name(&self) -> &str {
+        "postgres"
+    }
+
+    fn send(&mut self, reading: &SensorOutput) -> Result<()> {
+        let batch = self.batch.get_or_insert_with(|| Batch {
+            sensor: SensorRow {
+                id: reading.id.clone(),
+                category: self.category.clone(),
+                unit: serialize_unit(&reading.unit),
+                symbol: reading.symbol.clone(),
+            },
+            rows: String::new(),
+            count: 0,
+        });
+
+        let fields: [String; 5] = [
+            reading.id.clone(),
+            reading.timestamp.format(&Rfc3339)?,
+            reading.value.to_string(),
+            serialize_unit(&reading.unit).to_string(),
+            reading.symbol.clone(),
+        ];
+        let fields: Vec<String> = fields.iter().map(|field| copy_escape(field)).collect();
+        batch.rows.push_str(&fields.join("\t"));
+        batch.rows.push('\n');
+        batch.count += 1;
+
+        if batch.count >= self.batch_size {
+            self.dispatch()?;
+        }
+
+        self.write_errors()
+    }
+
+    fn close(&mut self) -> Result<()> {
+        let result = self.dispatch();
+
+        // dropping the sender lets the worker write what is queued and exit
+        self.sender.take();
+        if let Some(worker) = self.worker.take() {
+            _ = worker.join();
+        }
+
+        result.and(self.write_errors())
+    }
+}
+
+/// escapes a field for COPY's text format, where tabs separate fields and newlines separate rows
+fn copy_escape(field: &str) -> String {
+    field
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+fn run_worker(config: Config, client: Client, receiver: Receiver<Batch>, errors: Sender<String>) {
+    let mut client: Option<Client> = Some(client);
+
+    for batch in receiver {
+        let result = match &mut client {
+            Some(connected) if !connected.is_closed() => write_batch(connected, &batch),
+            _ => match config.connect(NoTls) {
+                Ok(connected) => write_batch(client.insert(connected), &batch),
+                Err(e) => Err(format!("could not connect to postgres: {}", describe(e)).into()),
+            },
+        };
+
+        if let Err(e) = result {
+            _ = errors.send(match batch.count {
+                1 => format!("a reading was not written: {}", e),
+                n => format!("{} readings were not written: {}", n, e),
+            });
+        }
+    }
+}
+
+fn write_batch(client: &mut Client, batch: &Batch) -> Result<()> {
+    let mut transaction = client.transaction().map_err(describe)?;
+
+    let sensor = &batch.sensor;
+    transaction.execute(
+        "insert into sensors (id, category, unit, symbol) values ($1, $2, $3, $4) on conflict (id) do nothing",
+        &[&sensor.id, &sensor.category, &sensor.unit, &sensor.symbol],
+    ).map_err(describe)?;
+
+    let mut writer = transaction
+        .copy_in("copy readings (id, timestamp, value, unit, symbol) from stdin")
+        .map_err(describe)?;
+    writer.write_all(batch.rows.as_bytes())?;
+    writer.finish().map_err(describe)?;
+
+    transaction.commit().map_err(describe)?;
+
+    Ok(())
+}
+
+/// postgres errors only say e.g. `db error` when displayed, with what the server actually said in their source
+fn describe(e: postgres::Error) -> String {
+    match std::error::Error::source(&e) {
+        Some(source) => format!("{}: {}", e, source),
+        None => e.to_string(),
+    }
+}
@@ -0,0 +1,348 @@
+use crate::args::{HumidityUnit, PressureUnit, PtyArgs, PtyFormat, TemperatureUnit};
+use crate::sensor::{SensorOutput, Unit};
+use crate::sinks::Sink;
+use crate::template::Template;
+use crate::timestamp::{TimestampFormat, Timezone};
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// a pseudo-terminal that readings are written to the way a sensor wired to a uart would send them, so host
+/// software can open `/dev/pts/N` (or the `--pty-link`) as if it were a real serial port.
+///
+/// like a real serial line, nothing is kept for a reader that isn't there - once the terminal's buffer is full,
+/// readings are dropped rather than holding up the sensor. only whole readings are dropped though: a line the
+/// buffer cut short is finished before the next one is started, so a reader never sees half a line
+#[derive(Debug)]
+pub struct PtySink {
+    // the master side. the terminal exists for as long as it is open
+    master: File,
+    path: PathBuf,
+    link: Option<PathBuf>,
+    format: PtyFormat,
+    template: Option<Template>,
+    timestamps: TimestampFormat,
+    // the part of the last line that didn't fit in the terminal's buffer
+    pending: Vec<u8>,
+}
+
+impl PtySink {
+    pub fn new(args: &PtyArgs) -> Result<PtySink> {
+        let template: Option<Template> = match &args.pty_template {
+            Some(template) => Some(Template::parse(template)?),
+            None => None,
+        };
+
+        let (master, path) = open_pty()?;
+
+        if let Some(link) = &args.pty_link {
+            // replace a link left by an earlier run, but never a real file
+            if std::fs::symlink_metadata(link).is_ok_and(|metadata| metadata.is_symlink()) {
+                std::fs::remove_file(link)?;
+            }
+            std::os::unix::fs::symlink(&path, link)
+                .map_err(|e| format!("could not link {} to {}: {}", link, path.display(), e))?;
+        }
+
+        note!(
+            "pty: writing readings to {}{}",
+            path.display(),
+            match &args.pty_link {
+                Some(link) => format!(" (linked from {})", link),
+                None => String::new(),
+            }
+        );
+
+        Ok(PtySink {
+            master,
+            path,
+            link: args.pty_link.as_ref().map(PathBuf::from),
+            format: args.pty_format,
+            template,
+            timestamps: args.pty_timestamp.clone(),
+            pending: vec![],
+        })
+    }
+
+    fn line(&self, reading: &SensorOutput) -> String {
+        match self.format {
+            PtyFormat::Nmea => nmea_sentence(reading),
+            PtyFormat::KeyValue => key_value_line(reading),
+            PtyFormat::Template => {
+                self.template
+                    .as_ref()
+                    .unwrap()
+                    .render(reading, &self.timestamps, &Timezone::Utc)
+            }
+        }
+    }
+
+    /// writes as much of the pending line as the terminal will take, keeping the rest for the next reading
+    fn write_pending(&mut self) -> Result<()> {
+        while !self.pending.is_empty() {
+            match self.master.write(&self.pending) {
+                Ok(0) => break,
+                Ok(written) => {
+                    self.pending.drain(..written);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                // nobody is reading, or they have fallen behind - the same as a serial line with nothing on the
+                // other end
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                // the reader has closed the terminal, so whoever opens it next starts on a fresh line
+                Err(e) if e.raw_os_error() == Some(libc::EIO) => self.pending.clear(),
+                Err(e) => {
+                    return Err(format!("could not write to {}: {}", self.path.display(), e).into());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Sink for PtySink {
+    fn name(&self) -> &str {
+        "pty"
+    }
+
+    fn send(&mut self, reading: &SensorOutput) -> Result<()> {
+        // finish a line cut short last time first. if even that doesn't fit, this reading is dropped
+        self.write_pending()?;
+        if !self.pending.is_empty() {
+            return Ok(());
+        }
+
+        self.pending = format!("{}\r\n", self.line(reading)).into_bytes();
+        self.write_pending()
+    }
+
+    fn close(&mut self) -> Result<()> {
+        if let Some(link) = &self.link {
+            std::fs::remove_file(link)?;
+        }
+        Ok(())
+    }
+}
+
+/// opens a new pseudo-terminal in raw mode, returning its master side and the path of the device to read from
+fn open_pty() -> Result<(File, PathBuf)> {
+    // non blocking so a full terminal buffer drops readings instead of stopping the sensor
+    let fd = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_NONBLOCK) };
+    if fd < 0 {
+        return Err(format!(
+            "could not create a pty: {}",
+            std::io::Error::last_os_error()
+        )
+        .into());
+    }
+    // owned from here on, so the descriptor is closed on every early return
+    let master = unsafe { File::from_raw_fd(fd) };
+
+    if unsafe { libc::grantpt(fd) } != 0 || unsafe { libc::unlockpt(fd) } != 0 {
+        return Err(format!(
+            "could not unlock the pty: {}",
+            std::io::Error::last_os_error()
+        )
+        .into());
+    }
+
+    // ptsname isn't thread safe, but sinks are set up before any sensor thread starts
+    let name = unsafe { libc::ptsname(fd) };
+    if name.is_null() {
+        return Err("could not find the name of the pty".into());
+    }
+    let path = PathBuf::from(
+        unsafe { CStr::from_ptr(name) }
+            .to_string_lossy()
+            .into_owned(),
+    );
+
+    // raw mode, so the line discipline doesn't echo, translate line endings or hold lines back. programs that open
+    // the port usually set their own mode anyway, but this makes `cat /dev/pts/N` show exactly what was sent
+    let slave = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(&path)?;
+    unsafe {
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(slave.as_raw_fd(), &mut termios) == 0 {
+            libc::cfmakeraw(&mut termios);
+            libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios);
+        }
+    }
+
+    Ok((master, path))
+}
+
+/// an nmea 0183 XDR transducer measurement, e.g. `$WIXDR,C,23.41,C,TMP1ab*1C`. the type and unit letters are
+/// the standard ones where there is one (C/K for temperature, P/B for pressure in pascal/bar, H/P for percent humidity)
+fn nmea_sentence(reading: &SensorOutput) -> String {
+    let (kind, unit) = match &reading.unit {
+        Unit::TemperatureUnit(TemperatureUnit::Celsius) => ("C", "C"),
+        Unit::TemperatureUnit(TemperatureUnit::Kelvin) => ("C", "K"),
+        Unit::PressureUnit(PressureUnit::Pascal) => ("P", "P"),
+        Unit::PressureUnit(PressureUnit::Bar) => ("P", "B"),
+        Unit::HumidityUnit(HumidityUnit::Relative) => ("H", "P"),
+        // there isn't a letter for absolute humidity, so this one is our own
+        Unit::HumidityUnit(HumidityUnit::Absolute) => ("H", "G"),
+    };
+
+    let body: String = format!(
+        "WIXDR,{},{:.2},{},{}",
+        kind, reading.value, unit, reading.id
+    );
+    // the checksum is the xor of every byte between the $ and the *
+    let checksum: u8 = body.bytes().fold(0, |checksum, byte| checksum ^ byte);
+
+    format!("${}*{:02X}", body, checksum)
+}
+
+/// the kind of line a simple sensor board prints, e.g. `T=23.4C`, `P=1.013bar` or `H=45.2%`
+fn key_value_line(reading: &SensorOutput) -> String {
+    let (key, suffix, precision) = match &reading.unit {
+        Unit::TemperatureUnit(TemperatureUnit::Celsius) => ("T", "C", 1),
+        Unit::TemperatureUnit(TemperatureUnit::Kelvin) => ("T", "K", 1),
+        Unit::PressureUnit(PressureUnit::Pascal) => ("P", "Pa", 0),
+        Unit::PressureUnit(PressureUnit::Bar) => ("P", "bar", 3),
+        Unit::HumidityUnit(HumidityUnit::Relative) => ("H", "%", 1),
+        Unit::HumidityUnit(HumidityUnit::Absolute) => ("H", "g/m3", 1),
+    };
+
+    format!("{}={:.*}{}", key, precision, reading.value, suffix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::UtcDateTime;
+
+    fn reading(value: f32, unit: Unit) -> SensorOutput {
+        SensorOutput {
+            id: "TMPabc".to_string(),
+            timestamp: UtcDateTime::from_unix_timestamp(1_738_315_800).unwrap(),
+            value,
+            unit,
+            symbol: String::new(),
+        }
+    }
+
+    #[test]
+    fn nmea_checksum() {
+        let sentence = nmea_sentence(&reading(
+            23.41,
+            Unit::TemperatureUnit(TemperatureUnit::Celsius),
+        ));
+        assert!(sentence.starts_with("$WIXDR,C,23.41,C,TMPabc*"));
+
+        let (body, checksum) = sentence[1..].split_once('*').unwrap();
+        let expected: u8 = body.bytes().fold(0, |checksum, byte| checksum ^ byte);
+        assert_eq!(checksum, format!("{:02X}", expected));
+        assert_eq!(checksum.len(), 2);
+
+        assert!(
+            nmea_sentence(&reading(1.01325, Unit::PressureUnit(PressureUnit::Bar)))
+                .starts_with("$WIXDR,P,1.01,B,TMPabc*")
+        );
+    }
+
+    #[test]
+    fn key_value_lines() {
+        let cases = [
+            (
+                Unit::TemperatureUnit(TemperatureUnit::Celsius),
+                23.44,
+                "T=23.4C",
+            ),
+            (
+                Unit::TemperatureUnit(TemperatureUnit::Kelvin),
+                296.59,
+                "T=296.6K",
+            ),
+            (
+                Unit::PressureUnit(PressureUnit::Pascal),
+                101325.4,
+                "P=101325Pa",
+            ),
+            (Unit::PressureUnit(PressureUnit::Bar), 1.01325, "P=1.013bar"),
+            (Unit::HumidityUnit(HumidityUnit::Relative), 45.23, "H=45.2%"),
+            (
+                Unit::HumidityUnit(HumidityUnit::Absolute),
+                9.87,
+                "H=9.9g/m3",
+            ),
+        ];
+        for (unit, value, line) in cases {
+            assert_eq!(key_value_line(&reading(value, unit)), line);
+        }
+    }
+
+    #[test]
+    fn full_buffer_drops_whole_lines() {
+        let (master, path) = open_pty().unwrap();
+        let mut slave = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NOCTTY | libc::O_NONBLOCK)
+            .open(&path)
+            .unwrap();
+        let mut sink = PtySink {
+            master,
+            path,
+            link: None,
+            format: PtyFormat::Nmea,
+            template: None,
+            timestamps: TimestampFormat::Plain,
+            pending: vec![],
+        };
+
+        // fill the terminal until a line is cut short
+        let mut sent = 0;
+        while sink.pending.is_empty() {
+            sent += 1;
+            assert!(sent < 100_000, "the pty never filled up");
+            sink.send(&reading(
+                sent as f32,
+                Unit::TemperatureUnit(TemperatureUnit::Celsius),
+            ))
+            .unwrap();
+        }
+
+        let read_all = |slave: &mut File, output: &mut Vec<u8>| {
+            let mut buffer = [0u8; 4096];
+            while let Ok(read) = slave.read(&mut buffer) {
+                if read == 0 {
+                    break;
+                }
+                output.extend_from_slice(&buffer[..read]);
+            }
+        };
+
+        let mut output: Vec<u8> = vec![];
+        read_all(&mut slave, &mut output);
+        assert!(!output.ends_with(b"\r\n"));
+
+        // the next reading finishes the cut line before its own
+        sink.send(&reading(
+            0.5,
+            Unit::TemperatureUnit(TemperatureUnit::Celsius),
+        ))
+        .unwrap();
+        assert!(sink.pending.is_empty());
+        read_all(&mut slave, &mut output);
+
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.strip_suffix("\r\n").unwrap().split("\r\n").collect();
+        assert_eq!(lines.len(), sent + 1);
+        for line in lines {
+            assert!(line.starts_with("$WIXDR,C,"), "torn line {:?}", line);
+            let (body, checksum) = line[1..].split_once('*').unwrap();
+            let expected: u8 = body.bytes().fold(0, |checksum, byte| checksum ^ byte);
+            assert_eq!(checksum, format!("{:02X}", expected));
+        }
+    }
+}
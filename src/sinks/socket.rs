@@ -0,0 +1,490 @@
+use crate::args::PayloadFormat;
+use crate::buffer::RingBuffer;
+use crate::encoding::{encode, encode_frame};
+use crate::sensor::SensorOutput;
+use crate::sinks::Sink;
+use crate::timestamp::TimestampFormat;
+use std::collections::BTreeMap;
+use std::io::prelude::*;
+use std::net::{TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+// a server that can't take a reading in this long is treated as gone, so it can't hold up the sensor
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// somewhere a stream socket can be opened to or listened on
+#[derive(Debug, Clone)]
+pub enum Address {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Address::Tcp(address) => write!(f, "tcp://{}", address),
+            Address::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn connect(address: &Address) -> Result<Stream> {
+        let stream = match address {
+            Address::Tcp(address) => {
+                let mut error: Option<std::io::Error> = None;
+                let mut connected: Option<TcpStream> = None;
+
+                // try every address the name resolves to, e.g. both the ipv6 and ipv4 localhost
+                for socket_address in address.to_socket_addrs()? {
+                    match TcpStream::connect_timeout(&socket_address, CONNECT_TIMEOUT) {
+                        Ok(stream) => {
+                            connected = Some(stream);
+                            break;
+                        }
+                        Err(e) => error = Some(e),
+                    }
+                }
+
+                match (connected, error) {
+                    (Some(stream), _) => Stream::Tcp(stream),
+                    (None, Some(e)) => return Err(e.into()),
+                    (None, None) => return Err(format!("{} did not resolve", address).into()),
+                }
+            }
+            #[cfg(unix)]
+            Address::Unix(path) => Stream::Unix(UnixStream::connect(path)?),
+            #[cfg(not(unix))]
+            Address::Unix(..) => return Err("unix sockets are only available on unix".into()),
+        };
+
+        stream.set_write_timeout(WRITE_TIMEOUT)?;
+
+        Ok(stream)
+    }
+
+    fn set_write_timeout(&self, timeout: Duration) -> Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_write_timeout(Some(timeout))?,
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_write_timeout(Some(timeout))?,
+        }
+        Ok(())
+    }
+
+    fn set_nonblocking(&self) -> Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nonblocking(true)?,
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_nonblocking(true)?,
+        }
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> std::io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.write_all(line),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write_all(line),
+        }
+    }
+}
+
+/// connects to a tcp or unix socket server and streams readings to it, one per line.
+///
+/// when the connection drops, readings are held in a buffer and the sink keeps trying to reconnect, backing
+/// off up to 30s between attempts. the buffer is sent first once it is back. the reading written just as the
+/// other end went away can still be lost, since the os accepts it before anyone notices the connection is gone
+#[derive(Debug)]
+pub struct StreamClientSink {
+    name: &'static str,
+    address: Address,
+    format: PayloadFormat,
+    timestamps: TimestampFormat,
+    stream: Option<Stream>,
+    pending: RingBuffer<Vec<u8>>,
+    next_attempt: Instant,
+    reconnect_delay: Duration,
+}
+
+impl StreamClientSink {
+    pub fn new(
+        name: &'static str,
+        address: Address,
+        format: PayloadFormat,
+        timestamps: TimestampFormat,
+        buffer: usize,
+    ) -> StreamClientSink {
+        StreamClientSink {
+            name,
+            address,
+            format,
+            timestamps,
+            stream: None,
+            pending: RingBuffer::with_capacity(buffer.max(1)),
+            next_attempt: Instant::now(),
+            reconnect_delay: Duration::from_secs(1),
+        }
+    }
+
+    fn reconnect(&mut self) {
+        if Instant::now() < self.next_attempt {
+            return;
+        }
+
+        match Stream::connect(&self.address) {
+            Ok(stream) => {
+                note!("{}: connected to {}", self.name, self.address);
+                self.stream = Some(stream);
+                self.reconnect_delay = Duration::from_secs(1);
+            }
+            Err(..) => {
+                self.next_attempt = Instant::now() + self.reconnect_delay;
+                self.reconnect_delay = (self.reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+            }
+        }
+    }
+
+    /// sends as much of the buffer as the connection will take, oldest first
+    fn drain(&mut self) {
+        while let Some(stream) = &mut self.stream {
+            let line = match self.pending.iter().next() {
+                Some(line) => line,
+                None => return,
+            };
+
+            match stream.write_line(line) {
+                Ok(..) => {
+                    self.pending.pop_front();
+                }
+                Err(e) => {
+                    eprintln!(
+                        "{}: lost connection to {} ({}), reconnecting",
+                        self.name, self.address, e
+                    );
+                    self.stream = None;
+                    self.next_attempt = Instant::now();
+                }
+            }
+        }
+    }
+}
+
+impl Sink for StreamClientSink {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn send(&mut self, reading: &SensorOutput) -> Result<()> {
+        let dropped = self
+            .pending
+            .push(encode_frame(reading, &self.format, &self.timestamps)?);
+
+        if self.stream.is_none() {
+            self.reconnect();
+        }
+        self.drain();
+
+        match dropped {
+            Some(..) => Err(format!(
+                "{} has been unreachable for too long, the oldest buffered reading was dropped",
+                self.address
+            )
+            .into()),
+            None => Ok(()),
+        }
+    }
+
+    fn close(&mut self) -> Result<()> {
+        // one last go at sending what is buffered, without waiting out the backoff
+        if self.stream.is_none() {
+            self.next_attempt = Instant::now();
+            self.reconnect();
+        }
+        self.drain();
+
+        if !self.pending.is_empty() {
+            return Err(format!(
+                "{} readings could not be sent to {}",
+                self.pending.len(),
+                self.address
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+}
+
+/// a listening socket and the sinks sending to its clients
+#[derive(Debug)]
+struct Listener {
+    clients: Arc<Mutex<Vec<Stream>>>,
+    sinks: usize,
+}
+
+// one listener per address, so with `serve` every sensor's readings go to the same clients. a second bind to
+// the address would fail with it already in use
+static LISTENERS: Mutex<BTreeMap<String, Arc<Mutex<Listener>>>> = Mutex::new(BTreeMap::new());
+
+/// listens on a tcp or unix socket and streams readings to every client that connects, one per line.
+/// clients only get readings from after they connect, and are dropped as soon as a write to them fails.
+///
+/// writes to clients never wait: one that has stopped reading is dropped once its socket buffer is full, rather
+/// than holding up the sensor and every other client
+#[derive(Debug)]
+pub struct StreamServerSink {
+    name: &'static str,
+    address: Address,
+    format: PayloadFormat,
+    timestamps: TimestampFormat,
+    listener: Arc<Mutex<Listener>>,
+}
+
+impl StreamServerSink {
+    pub fn new(
+        name: &'static str,
+        address: Address,
+        format: PayloadFormat,
+        timestamps: TimestampFormat,
+    ) -> Result<StreamServerSink> {
+        let listener: Arc<Mutex<Listener>> = {
+            let mut listeners = LISTENERS.lock().unwrap();
+            match listeners.get(&address.to_string()) {
+                Some(listener) => listener.clone(),
+                None => {
+                    let listener = Arc::new(Mutex::new(listen(name, &address)?));
+                    listeners.insert(address.to_string(), listener.clone());
+                    listener
+                }
+            }
+        };
+        listener.lock().unwrap().sinks += 1;
+
+        Ok(StreamServerSink {
+            name,
+            address,
+            format,
+            timestamps,
+            listener,
+        })
+    }
+}
+
+fn listen(name: &str, address: &Address) -> Result<Listener> {
+    let clients: Arc<Mutex<Vec<Stream>>> = Arc::new(Mutex::new(vec![]));
+    let accepted = clients.clone();
+
+    match address {
+        Address::Tcp(bind) => {
+            let listener = TcpListener::bind(bind)
+                .map_err(|e| format!("could not listen on {}: {}", bind, e))?;
+            std::thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    add_client(&accepted, Stream::Tcp(stream));
+                }
+            });
+        }
+        #[cfg(unix)]
+        Address::Unix(path) => {
+            // a socket file left behind by an earlier run would stop the bind, and nothing can be listening on it
+            if UnixStream::connect(path).is_err() && path.exists() {
+                std::fs::remove_file(path)?;
+            }
+            let listener = UnixListener::bind(path)
+                .map_err(|e| format!("could not listen on {}: {}", path.display(), e))?;
+            std::thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    add_client(&accepted, Stream::Unix(stream));
+                }
+            });
+        }
+        #[cfg(not(unix))]
+        Address::Unix(..) => return Err("unix sockets are only available on unix".into()),
+    }
+
+    note!("{}: listening on {}", name, address);
+
+    Ok(Listener { clients, sinks: 0 })
+}
+
+fn add_client(clients: &Arc<Mutex<Vec<Stream>>>, stream: Stream) {
+    if stream.set_nonblocking().is_ok() {
+        clients.lock().unwrap().push(stream);
+    }
+}
+
+impl Sink for StreamServerSink {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn send(&mut self, reading: &SensorOutput) -> Result<()> {
+        let line = encode_frame(reading, &self.format, &self.timestamps)?;
+
+        self.listener
+            .lock()
+            .unwrap()
+            .clients
+            .lock()
+            .unwrap()
+            // a full buffer fails with WouldBlock, possibly partway through the line, so the client is dropped
+            // rather than sent the rest of it later
+            .retain_mut(|client| client.write_line(&line).is_ok());
+
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        let mut listener = self.listener.lock().unwrap();
+        listener.sinks -= 1;
+
+        // the clients stay connected until the last sensor sharing the socket has finished
+        if listener.sinks > 0 {
+            return Ok(());
+        }
+        LISTENERS.lock().unwrap().remove(&self.address.to_string());
+
+        listener.clients.lock().unwrap().clear();
+
+        if let Address::Unix(path) = &self.address {
+            std::fs::remove_file(path)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// sends each reading as a single udp datagram, without a trailing newline. there is no connection to lose,
+/// so nothing is buffered - a datagram that doesn't arrive is simply gone, as it would be from a real device
+#[derive(Debug)]
+pub struct UdpSink {
+    socket: UdpSocket,
+    address: String,
+    format: PayloadFormat,
+    timestamps: TimestampFormat,
+}
+
+impl UdpSink {
+    pub fn new(
+        address: &str,
+        format: PayloadFormat,
+        timestamps: TimestampFormat,
+    ) -> Result<UdpSink> {
+        let target = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| format!("{} did not resolve", address))?;
+
+        let bind = if target.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(bind)?;
+        socket.connect(target)?;
+
+        Ok(UdpSink {
+            socket,
+            address: address.to_string(),
+            format,
+            timestamps,
+        })
+    }
+}
+
+impl Sink for UdpSink {
+    fn name(&self) -> &str {
+        "udp"
+    }
+
+    fn send(&mut self, reading: &SensorOutput) -> Result<()> {
+        // connection refused only means nothing is listening yet, which is normal for udp
+        match self
+            .socket
+            .send(&encode(reading, &self.format, &self.timestamps)?)
+        {
+            Ok(..) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => Ok(()),
+            Err(e) => Err(format!("could not send to {}: {}", self.address, e).into()),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::args::TemperatureUnit;
+    use crate::sensor::Unit;
+    use std::io::BufReader;
+    use time::UtcDateTime;
+
+    fn reading(value: f32) -> SensorOutput {
+        SensorOutput {
+            id: "TMPabc".to_string(),
+            timestamp: UtcDateTime::from_unix_timestamp(1_738_315_800).unwrap(),
+            value,
+            unit: Unit::TemperatureUnit(TemperatureUnit::Celsius),
+            symbol: "°C".to_string(),
+        }
+    }
+
+    #[test]
+    fn a_stalled_client_is_dropped_without_holding_up_the_others() {
+        let path = std::env::temp_dir().join(format!(
+            "sensor_simulator_socket_stalled_{}.sock",
+            std::process::id()
+        ));
+        let mut sink = StreamServerSink::new(
+            "unix",
+            Address::Unix(path.clone()),
+            PayloadFormat::Json,
+            TimestampFormat::Plain,
+        )
+        .unwrap();
+
+        // connected, but never reads
+        let _stalled = UnixStream::connect(&path).unwrap();
+        let reader = UnixStream::connect(&path).unwrap();
+        let received = std::thread::spawn(move || BufReader::new(reader).lines().count());
+
+        let clients = sink.listener.lock().unwrap().clients.clone();
+        while clients.lock().unwrap().len() < 2 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        let started = Instant::now();
+        let mut sent = 0;
+        while clients.lock().unwrap().len() == 2 {
+            sent += 1;
+            assert!(sent < 1_000_000, "the stalled client was never dropped");
+            sink.send(&reading(sent as f32)).unwrap();
+            // give the reading client a chance to keep up
+            if sent % 100 == 0 {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+        // every write either went straight into a buffer or failed at once
+        assert!(started.elapsed() < WRITE_TIMEOUT);
+
+        sink.send(&reading(0.0)).unwrap();
+        sink.close().unwrap();
+        assert_eq!(received.join().unwrap(), sent + 1);
+        assert!(!path.exists());
+    }
+}
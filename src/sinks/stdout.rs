@@ -0,0 +1,135 @@
+use crate::args::{OutputArgs, PayloadFormat, StdoutFormat};
+use crate::encoding::{FormattedReading, encode_local};
+use crate::sensor::SensorOutput;
+use crate::sinks::Sink;
+use crate::template::Template;
+use crate::timestamp::{TimestampFormat, Timezone};
+use std::io::prelude::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// in serve mode every sensor has its own stdout sink, but the csv header should still only be printed once
+static CSV_HEADER_PRINTED: AtomicBool = AtomicBool::new(false);
+
+/// prints each reading to stdout as one line, in the format picked with `--stdout-format`. timestamps are shown
+/// in --timezone, which only the console uses.
+///
+/// only readings go to stdout, so it can be piped into another tool. when that tool exits (e.g. `| head`) the
+/// sink says so once and stops printing, rather than failing on every reading for the rest of the run
+#[derive(Debug)]
+pub struct StdoutSink {
+    format: StdoutFormat,
+    template: Option<Template>,
+    timestamps: Option<TimestampFormat>,
+    timezone: Timezone,
+    closed: bool,
+}
+
+impl StdoutSink {
+    pub fn new(args: &OutputArgs) -> Result<StdoutSink> {
+        let template: Option<Template> = match &args.stdout_template {
+            Some(template) => Some(Template::parse(template)?),
+            None => None,
+        };
+
+        Ok(StdoutSink {
+            format: args.stdout_format,
+            template,
+            timestamps: args.stdout_timestamp.clone(),
+            timezone: args.timezone,
+            closed: false,
+        })
+    }
+
+    fn line(&self, reading: &SensorOutput) -> Result<Vec<u8>> {
+        let timestamps: &TimestampFormat =
+            self.timestamps.as_ref().unwrap_or(&TimestampFormat::Plain);
+
+        let mut line: Vec<u8> = match self.format {
+            StdoutFormat::Human => self.human(reading).into_bytes(),
+            StdoutFormat::Csv if !CSV_HEADER_PRINTED.swap(true, Ordering::Relaxed) => {
+                let mut writer = csv::Writer::from_writer(vec![]);
+                writer.serialize(FormattedReading::new(reading, timestamps, &self.timezone))?;
+                let mut lines = writer.into_inner()?;
+                lines.pop();
+                lines
+            }
+            StdoutFormat::Csv => {
+                encode_local(reading, &PayloadFormat::Csv, timestamps, &self.timezone)?
+            }
+            StdoutFormat::Json => {
+                encode_local(reading, &PayloadFormat::Json, timestamps, &self.timezone)?
+            }
+            StdoutFormat::Influx => {
+                encode_local(reading, &PayloadFormat::Influx, timestamps, &self.timezone)?
+            }
+            StdoutFormat::Template => self
+                .template
+                .as_ref()
+                .unwrap()
+                .render(reading, timestamps, &self.timezone)
+                .into_bytes(),
+        };
+        line.push(b'\n');
+
+        Ok(line)
+    }
+
+    /// `[HH:MM:SS] Sensor ID: 12.34°C`, or with the whole timestamp when --stdout-timestamp is given
+    fn human(&self, reading: &SensorOutput) -> String {
+        let time: String = match &self.timestamps {
+            Some(timestamps) => timestamps
+                .format(&reading.timestamp, &self.timezone)
+                .to_string(),
+            None => {
+                let local = self.timezone.local(&reading.timestamp);
+                format!(
+                    "{:02}:{:02}:{:02}",
+                    local.hour(),
+                    local.minute(),
+                    local.second()
+                )
+            }
+        };
+
+        format!(
+            "[{}] Sensor {}: {:.2}{}",
+            time, reading.id, reading.value, reading.symbol
+        )
+    }
+}
+
+impl Sink for StdoutSink {
+    fn name(&self) -> &str {
+        "stdout"
+    }
+
+    fn send(&mut self, reading: &SensorOutput) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+
+        let line = self.line(reading)?;
+
+        // one write under the lock, so lines from sensors on other threads never interleave
+        match std::io::stdout().lock().write_all(&line) {
+            Ok(..) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {
+                self.closed = true;
+                Err(
+                    "whatever was reading stdout has closed it, readings will no longer be printed"
+                        .into(),
+                )
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn close(&mut self) -> Result<()> {
+        if !self.closed {
+            std::io::stdout().flush()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,181 @@
+use crate::args::{SyslogArgs, SyslogFacility};
+use crate::sensor::SensorOutput;
+use crate::sinks::Sink;
+use crate::utils::unit_parts;
+use std::net::{ToSocketAddrs, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use time::format_description::BorrowedFormatItem;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// every reading is logged as informational
+const SEVERITY: u8 = 6;
+// structured data ids that aren't registered with iana need an enterprise number. 32473 is the one set aside
+// for documentation and examples (rfc 5612), which a simulator fits
+const SD_ID: &str = "sensor@32473";
+// rfc 3339, but with at most microseconds
+const TIMESTAMP: &[BorrowedFormatItem] = time::macros::format_description!(
+    "[year]-[month]-[day]T[hour]:[minute]:[second].[subsecond digits:6]Z"
+);
+
+#[derive(Debug)]
+enum Transport {
+    Udp(UdpSocket),
+    #[cfg(unix)]
+    Unix(std::path::PathBuf, Option<UnixDatagram>),
+}
+
+/// logs each reading as an rfc 5424 syslog message, e.g.
+///
+/// `<134>1 2025-01-01T12:00:00.123Z host sensor_simulator 4242 temperature [sensor@32473 id="TMP1ab"
+/// value="23.41" unit="celsius" symbol="°C"] TMP1ab 23.41°C`
+///
+/// over udp (rfc 5426, one message per datagram) or to a local datagram socket like `/dev/log`, which is where
+/// syslog daemons and journald listen. the structured data has everything a log pipeline needs, so the message
+/// at the end is only for people reading the log
+#[derive(Debug)]
+pub struct SyslogSink {
+    transport: Transport,
+    facility: SyslogFacility,
+    hostname: String,
+    app_name: String,
+    category: String,
+}
+
+impl SyslogSink {
+    pub fn new(args: &SyslogArgs, category: &str) -> Result<SyslogSink> {
+        let transport = match (&args.syslog_udp, &args.syslog_socket) {
+            (Some(address), _) => {
+                let target = address
+                    .to_socket_addrs()?
+                    .next()
+                    .ok_or_else(|| format!("{} did not resolve", address))?;
+                let socket = UdpSocket::bind(if target.is_ipv4() {
+                    "0.0.0.0:0"
+                } else {
+                    "[::]:0"
+                })?;
+                socket.connect(target)?;
+                Transport::Udp(socket)
+            }
+            #[cfg(unix)]
+            (None, Some(path)) => Transport::Unix(path.into(), None),
+            #[cfg(not(unix))]
+            (None, Some(..)) => return Err("--syslog-socket is only available on unix".into()),
+            (None, None) => {
+                return Err("the syslog sink needs --syslog-udp or --syslog-socket".into());
+            }
+        };
+
+        Ok(SyslogSink {
+            transport,
+            facility: args.syslog_facility,
+            hostname: hostname(),
+            app_name: header_field(&args.syslog_app_name, 48),
+            category: category.to_string(),
+        })
+    }
+
+    fn message(&self, reading: &SensorOutput) -> Result<String> {
+        let (_, unit) = unit_parts(&reading.unit);
+
+        Ok(format!(
+            "<{}>1 {} {} {} {} {} [{} id=\"{}\" value=\"{}\" unit=\"{}\" symbol=\"{}\"] {} {:.2}{}",
+            self.facility as u8 * 8 + SEVERITY,
+            reading.timestamp.format(TIMESTAMP)?,
+            self.hostname,
+            self.app_name,
+            std::process::id(),
+            header_field(&self.category, 32),
+            SD_ID,
+            param_value(&reading.id),
+            reading.value,
+            unit,
+            param_value(&reading.symbol),
+            reading.id,
+            reading.value,
+            reading.symbol
+        ))
+    }
+}
+
+impl Sink for SyslogSink {
+    fn name(&self) -> &str {
+        "syslog"
+    }
+
+    fn send(&mut self, reading: &SensorOutput) -> Result<()> {
+        let message = self.message(reading)?;
+
+        match &mut self.transport {
+            // connection refused only means nothing is listening yet, which is normal for udp
+            Transport::Udp(socket) => match socket.send(message.as_bytes()) {
+                Ok(..) => Ok(()),
+                Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => Ok(()),
+                Err(e) => Err(e.into()),
+            },
+            #[cfg(unix)]
+            Transport::Unix(path, socket) => {
+                // the socket is made again after a failure, since the daemon may have restarted and made a new one
+                if socket.is_none() {
+                    let connected = UnixDatagram::unbound()?;
+                    connected
+                        .connect(&*path)
+                        .map_err(|e| format!("could not connect to {}: {}", path.display(), e))?;
+                    *socket = Some(connected);
+                }
+
+                if let Err(e) = socket.as_ref().unwrap().send(message.as_bytes()) {
+                    *socket = None;
+                    return Err(format!("could not log to {}: {}", path.display(), e).into());
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// header fields are printable ascii with no spaces, up to a maximum length, and `-` when there is nothing
+fn header_field(value: &str, max_length: usize) -> String {
+    let field: String = value
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(max_length)
+        .collect();
+
+    match field.is_empty() {
+        true => "-".to_string(),
+        false => field,
+    }
+}
+
+/// structured data values need `"`, `\` and `]` escaping
+fn param_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace(']', "\\]")
+}
+
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut buffer = [0u8; 256];
+    // the name may be cut short without a nul if it doesn't fit, so the buffer is only read up to one
+    let result =
+        unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) };
+    if result != 0 {
+        return "-".to_string();
+    }
+
+    let length = buffer
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(buffer.len());
+    header_field(&String::from_utf8_lossy(&buffer[..length]), 255)
+}
+
+#[cfg(not(unix))]
+fn hostname() -> String {
+    header_field(&std::env::var("COMPUTERNAME").unwrap_or_default(), 255)
+}
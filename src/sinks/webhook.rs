@@ -0,0 +1,328 @@
+use crate::args::{WebhookArgs, WebhookFormat};
+use crate::encoding::{FormattedReading, line_protocol};
+use crate::senml;
+use crate::sensor::SensorOutput;
+use crate::sinks::Sink;
+use crate::timestamp::{TimestampFormat, Timezone, plain};
+use std::fs::OpenOptions;
+use std::io::prelude::*;
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError, sync_channel};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+// batches waiting for the sender thread. past this the endpoint is too far behind and batches go to the dead letter file
+const QUEUED_BATCHES: usize = 64;
+
+/// where and how to post, shared with the sender thread
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub retries: u32,
+    pub backoff: Duration,
+    pub timeout: Duration,
+    pub dead_letter: Option<String>,
+}
+
+/// how readings are written into a request body
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BodyFormat {
+    /// a single json object, or an array of them for a batch
+    Json,
+    /// influxdb line protocol, one reading per line
+    LineProtocol,
+    /// a senml pack, always an array even for a batch of one
+    Senml,
+}
+
+impl BodyFormat {
+    fn content_type(&self) -> &'static str {
+        match self {
+            BodyFormat::Json => "application/json",
+            BodyFormat::LineProtocol => "text/plain; charset=utf-8",
+            BodyFormat::Senml => "application/senml+json",
+        }
+    }
+}
+
+/// posts readings as json to an http endpoint, one at a time or in batches.
+///
+/// the body uses the same serde representation as `output.json`, with the timestamp in --webhook-timestamp's
+/// format - a single reading is sent as one object, a batch as an array of them. with `--webhook-format senml`
+/// each batch is a senml pack instead. requests are made from a background thread so a slow or failing endpoint
+/// never holds up the sensor. failed requests are retried with exponential backoff, and batches that still fail
+/// are appended to the dead letter file (one json line per batch) so they can be replayed later.
+///
+/// other sinks that post batches over http, like influxdb, are this sink with their own endpoint and body format
+#[derive(Debug)]
+pub struct WebhookSink {
+    name: &'static str,
+    format: BodyFormat,
+    timestamps: TimestampFormat,
+    // readings are kept as the json text serde produced for them, so the body is byte for byte what the file
+    // outputs contain (going through serde_json::Value would reorder the fields and widen the f32 value)
+    batch: Vec<String>,
+    // the timestamp of the batch's first reading, which a senml pack's later records are timed from
+    senml_base: Option<time::UtcDateTime>,
+    batch_size: usize,
+    sender: Option<SyncSender<Vec<String>>>,
+    worker: Option<JoinHandle<()>>,
+    endpoint: Endpoint,
+}
+
+impl WebhookSink {
+    pub fn new(args: &WebhookArgs) -> Result<WebhookSink> {
+        let url: String = args
+            .webhook_url
+            .clone()
+            .ok_or("the webhook sink needs --webhook-url")?;
+
+        let mut headers: Vec<(String, String)> = vec![];
+
+        for header in &args.webhook_header {
+            let (name, value) = header.split_once(':').ok_or_else(|| {
+                format!("webhook header `{}` should look like `Name: value`", header)
+            })?;
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+
+        // the token is read from the environment so it doesn't end up in shell history or process listings
+        if let Some(var) = &args.webhook_token_env {
+            let token = std::env::var(var)
+                .map_err(|_| format!("--webhook-token-env names {}, but it is not set", var))?;
+            headers.push(("Authorization".to_string(), format!("Bearer {}", token)));
+        }
+
+        let endpoint = Endpoint {
+            url,
+            headers,
+            retries: args.webhook_retries,
+            backoff: args.webhook_backoff,
+            timeout: args.webhook_timeout,
+            dead_letter: args.webhook_dead_letter.clone(),
+        };
+
+        let format: BodyFormat = match args.webhook_format {
+            WebhookFormat::Json => BodyFormat::Json,
+            WebhookFormat::Senml => BodyFormat::Senml,
+        };
+
+        let mut sink = WebhookSink::start(
+            "webhook",
+            endpoint,
+            format,
+            args.webhook_batch_size as usize,
+        );
+        sink.timestamps = args.webhook_timestamp.clone();
+
+        Ok(sink)
+    }
+
+    /// starts the sender thread for any endpoint readings are posted to in batches, not just a webhook
+    pub fn start(
+        name: &'static str,
+        endpoint: Endpoint,
+        format: BodyFormat,
+        batch_size: usize,
+    ) -> WebhookSink {
+        let agent: ureq::Agent = ureq::Agent::config_builder()
+            .timeout_global(Some(endpoint.timeout))
+            .http_status_as_error(false)
+            .build()
+            .into();
+
+        let (sender, receiver) = sync_channel::<Vec<String>>(QUEUED_BATCHES);
+        let worker_endpoint = endpoint.clone();
+        let worker =
+            std::thread::spawn(move || run_worker(name, agent, worker_endpoint, format, receiver));
+
+        WebhookSink {
+            name,
+            format,
+            timestamps: TimestampFormat::Plain,
+            batch: Vec::with_capacity(batch_size),
+            senml_base: None,
+            batch_size,
+            sender: Some(sender),
+            worker: Some(worker),
+            endpoint,
+        }
+    }
+
+    fn dispatch(&mut self) -> Result<()> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+
+        let batch = std::mem::replace(&mut self.batch, Vec::with_capacity(self.batch_size));
+        self.senml_base = None;
+
+        match self.sender.as_ref().unwrap().try_send(batch) {
+            Ok(..) => Ok(()),
+            Err(TrySendError::Full(batch)) | Err(TrySendError::Disconnected(batch)) => {
+                let error = "too many batches waiting to be sent";
+                write_dead_letter(
+                    &self.endpoint,
+                    self.format,
+                    &body(batch, self.format),
+                    error,
+                )?;
+                Err(format!("{}, batch written to the dead letter file instead", error).into())
+            }
+        }
+    }
+}
+
+impl Sink for WebhookSink {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn send(&mut self, reading: &SensorOutput) -> Result<()> {
+        self.batch.push(match self.format {
+            BodyFormat::Json => serde_json::to_string(&FormattedReading::new(
+                reading,
+                &self.timestamps,
+                &Timezone::Utc,
+            ))?,
+            BodyFormat::LineProtocol => line_protocol(reading),
+            BodyFormat::Senml => {
+                let record = senml::Record::new(reading, self.senml_base.as_ref());
+                self.senml_base.get_or_insert(reading.timestamp);
+                serde_json::to_string(&record)?
+            }
+        });
+
+        if self.batch.len() >= self.batch_size {
+            self.dispatch()?;
+        }
+
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        let result = self.dispatch();
+
+        // dropping the sender lets the worker finish what is queued and exit
+        self.sender.take();
+        if let Some(worker) = self.worker.take() {
+            _ = worker.join();
+        }
+
+        result
+    }
+}
+
+/// a json batch of one is sent as a bare object, so `--webhook-batch-size 1` posts exactly what a single reading
+/// looks like
+fn body(mut batch: Vec<String>, format: BodyFormat) -> String {
+    match format {
+        BodyFormat::Json if batch.len() == 1 => batch.pop().unwrap(),
+        BodyFormat::Json | BodyFormat::Senml => format!("[{}]", batch.join(",")),
+        BodyFormat::LineProtocol => batch.join("\n"),
+    }
+}
+
+fn run_worker(
+    name: &str,
+    agent: ureq::Agent,
+    endpoint: Endpoint,
+    format: BodyFormat,
+    receiver: Receiver<Vec<String>>,
+) {
+    for batch in receiver {
+        let body = body(batch, format);
+
+        if let Err(e) = post_with_retries(name, &agent, &endpoint, format, &body) {
+            eprintln!("{}: giving up on a batch: {}", name, e);
+            if let Err(e) = write_dead_letter(&endpoint, format, &body, &e.to_string()) {
+                eprintln!(
+                    "{}: could not write to the dead letter file either, batch lost: {}",
+                    name, e
+                );
+            }
+        }
+    }
+}
+
+fn post_with_retries(
+    name: &str,
+    agent: &ureq::Agent,
+    endpoint: &Endpoint,
+    format: BodyFormat,
+    body: &str,
+) -> Result<()> {
+    let payload: &[u8] = body.as_bytes();
+    let mut wait: Duration = endpoint.backoff;
+    let mut attempt: u32 = 0;
+
+    loop {
+        let mut request = agent.post(&endpoint.url);
+        for (name, value) in &endpoint.headers {
+            request = request.header(name, value);
+        }
+
+        let error: String = match request.content_type(format.content_type()).send(payload) {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            // other client errors mean the request itself is wrong, and sending it again won't help
+            Ok(response) if response.status().is_client_error() && response.status() != 429 => {
+                return Err(format!(
+                    "{} rejected the batch with {}",
+                    endpoint.url,
+                    response.status()
+                )
+                .into());
+            }
+            Ok(response) => format!("{} responded with {}", endpoint.url, response.status()),
+            Err(e) => format!("could not reach {}: {}", endpoint.url, e),
+        };
+
+        if attempt >= endpoint.retries {
+            return Err(format!("{} (after {} retries)", error, attempt).into());
+        }
+
+        eprintln!(
+            "{}: {}, retrying in {:.1}s",
+            name,
+            error,
+            wait.as_secs_f32()
+        );
+        std::thread::sleep(wait);
+
+        wait = (wait * 2).min(MAX_BACKOFF);
+        attempt += 1;
+    }
+}
+
+fn write_dead_letter(
+    endpoint: &Endpoint,
+    format: BodyFormat,
+    body: &str,
+    error: &str,
+) -> Result<()> {
+    let path = match &endpoint.dead_letter {
+        Some(path) => path,
+        None => return Err("no dead letter file was given, so the batch was dropped".into()),
+    };
+
+    // a json body is spliced in as it is rather than being escaped into a string
+    let body: String = match format {
+        BodyFormat::Json | BodyFormat::Senml => body.to_string(),
+        BodyFormat::LineProtocol => serde_json::to_string(body)?,
+    };
+    let line = format!(
+        "{{\"failed_at\":{},\"url\":{},\"error\":{},\"body\":{}}}",
+        serde_json::to_string(&plain(&time::UtcDateTime::now()))?,
+        serde_json::to_string(&endpoint.url)?,
+        serde_json::to_string(error)?,
+        body
+    );
+
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(format!("{}\n", line).as_bytes())?;
+
+    Ok(())
+}
@@ -0,0 +1,258 @@
+use crate::sensor::SensorOutput;
+use crate::timestamp::{TimestampFormat, Timezone};
+use crate::utils::unit_parts;
+
+/// a piece of a reading that can be filled into a template
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Id,
+    Value,
+    Symbol,
+    Unit,
+    Category,
+    Timestamp,
+    TimestampRfc3339,
+}
+
+#[derive(Debug, Clone)]
+enum Part {
+    Text(String),
+    Field {
+        field: Field,
+        precision: Option<usize>,
+    },
+}
+
+/// a user supplied line format such as `T={value:.1}{symbol}`.
+///
+/// fields are written in braces - `{id}`, `{value}`, `{symbol}`, `{unit}`, `{category}`, `{timestamp}` in
+/// whichever format the sink uses and `{timestamp_rfc3339}` always in rfc 3339 - and the value can be given a
+/// number of decimal places the same way as printf, e.g. `{value:.3}`. `{{` and `}}` are literal braces, and
+/// `\r`, `\n` and `\t` are turned into the characters they stand for
+#[derive(Debug, Clone)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Template, String> {
+        let mut parts: Vec<Part> = vec![];
+        let mut text = String::new();
+        let mut chars = template.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let mut placeholder = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => placeholder.push(c),
+                            None => return Err(format!("`{{{}` is never closed", placeholder)),
+                        }
+                    }
+
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(parse_placeholder(&placeholder)?);
+                }
+                '}' => {
+                    return Err(
+                        "a `}` has no `{` before it - use `}}` for a literal one".to_string()
+                    );
+                }
+                '\\' => match chars.next() {
+                    Some('r') => text.push('\r'),
+                    Some('n') => text.push('\n'),
+                    Some('t') => text.push('\t'),
+                    Some('\\') => text.push('\\'),
+                    Some(c) => {
+                        text.push('\\');
+                        text.push(c);
+                    }
+                    None => text.push('\\'),
+                },
+                c => text.push(c),
+            }
+        }
+
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+
+        Ok(Template { parts })
+    }
+
+    pub fn render(
+        &self,
+        reading: &SensorOutput,
+        timestamps: &TimestampFormat,
+        timezone: &Timezone,
+    ) -> String {
+        let mut rendered = String::new();
+
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => rendered.push_str(text),
+                Part::Field { field, precision } => {
+                    let (category, unit) = unit_parts(&reading.unit);
+                    match field {
+                        Field::Id => rendered.push_str(&reading.id),
+                        Field::Value => match precision {
+                            Some(precision) => {
+                                rendered.push_str(&format!("{:.*}", precision, reading.value))
+                            }
+                            None => rendered.push_str(&reading.value.to_string()),
+                        },
+                        Field::Symbol => rendered.push_str(&reading.symbol),
+                        Field::Unit => rendered.push_str(unit),
+                        Field::Category => rendered.push_str(category),
+                        Field::Timestamp => rendered
+                            .push_str(&timestamps.format(&reading.timestamp, timezone).to_string()),
+                        Field::TimestampRfc3339 => rendered.push_str(
+                            &TimestampFormat::Rfc3339
+                                .format(&reading.timestamp, timezone)
+                                .to_string(),
+                        ),
+                    }
+                }
+            }
+        }
+
+        rendered
+    }
+}
+
+fn parse_placeholder(placeholder: &str) -> Result<Part, String> {
+    let (name, spec) = match placeholder.split_once(':') {
+        Some((name, spec)) => (name, Some(spec)),
+        None => (placeholder, None),
+    };
+
+    let field = match name {
+        "id" => Field::Id,
+        "value" => Field::Value,
+        "symbol" => Field::Symbol,
+        "unit" => Field::Unit,
+        "category" => Field::Category,
+        "timestamp" => Field::Timestamp,
+        "timestamp_rfc3339" => Field::TimestampRfc3339,
+        _ => {
+            return Err(format!(
+                "`{{{}}}` is not a field - use id, value, symbol, unit, category, timestamp or timestamp_rfc3339",
+                name
+            ));
+        }
+    };
+
+    let precision: Option<usize> = match spec {
+        None => None,
+        Some(spec) if field == Field::Value => Some(
+            spec.strip_prefix('.')
+                .and_then(|digits| digits.parse().ok())
+                .ok_or_else(|| {
+                    format!(
+                        "`{{{}}}` should give the decimal places like `{{value:.2}}`",
+                        placeholder
+                    )
+                })?,
+        ),
+        Some(..) => {
+            return Err(format!(
+                "only {{value}} can be given decimal places, not {{{}}}",
+                name
+            ));
+        }
+    };
+
+    Ok(Part::Field { field, precision })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::TemperatureUnit;
+    use crate::sensor::Unit;
+    use time::UtcDateTime;
+
+    fn reading() -> SensorOutput {
+        SensorOutput {
+            id: "TMPabc".to_string(),
+            // 2025-01-31 09:30:00
+            timestamp: UtcDateTime::from_unix_timestamp(1_738_315_800).unwrap(),
+            value: 21.46,
+            unit: Unit::TemperatureUnit(TemperatureUnit::Celsius),
+            symbol: "°C".to_string(),
+        }
+    }
+
+    fn render(template: &str) -> String {
+        Template::parse(template).unwrap().render(
+            &reading(),
+            &TimestampFormat::Plain,
+            &Timezone::Utc,
+        )
+    }
+
+    #[test]
+    fn fields_are_filled_in() {
+        assert_eq!(
+            render("{id} {category} {unit} {value}{symbol}"),
+            "TMPabc temperature celsius 21.46°C"
+        );
+    }
+
+    #[test]
+    fn value_takes_decimal_places() {
+        assert_eq!(render("T={value:.1}{symbol}"), "T=21.5°C");
+        assert_eq!(render("{value:.3}"), "21.460");
+        assert_eq!(render("{value:.0}"), "21");
+    }
+
+    #[test]
+    fn timestamp_uses_the_sinks_format_and_rfc3339_is_fixed() {
+        let template = Template::parse("{timestamp} {timestamp_rfc3339}").unwrap();
+
+        assert_eq!(
+            template.render(&reading(), &TimestampFormat::Plain, &Timezone::Utc),
+            "2025-01-31 09:30:00 2025-01-31T09:30:00Z"
+        );
+        assert_eq!(
+            template.render(&reading(), &TimestampFormat::EpochSeconds, &Timezone::Utc),
+            "1738315800 2025-01-31T09:30:00Z"
+        );
+    }
+
+    #[test]
+    fn braces_and_escapes_are_literal() {
+        assert_eq!(render("{{{id}}}\\r\\n"), "{TMPabc}\r\n");
+        assert_eq!(render("a\\tb\\\\c\\q"), "a\tb\\c\\q");
+    }
+
+    #[test]
+    fn bad_templates_are_rejected() {
+        for template in [
+            "{value",
+            "value}",
+            "{nope}",
+            "{id:.2}",
+            "{value:2}",
+            "{value:.x}",
+        ] {
+            assert!(
+                Template::parse(template).is_err(),
+                "{} was accepted",
+                template
+            );
+        }
+    }
+}
@@ -0,0 +1,330 @@
+use clap::builder::{PossibleValue, TypedValueParser, ValueParserFactory};
+use serde::Serialize;
+use std::fmt;
+use time::format_description::well_known::Rfc3339;
+use time::format_description::{BorrowedFormatItem, OwnedFormatItem};
+use time::{OffsetDateTime, UtcDateTime, UtcOffset};
+use time_tz::{Offset, TimeZone, Tz};
+
+const PLAIN: &[BorrowedFormatItem] =
+    time::macros::format_description!("[year]-[month]-[day] [hour]:[minute]:[second]");
+const ISO_MILLIS: &[BorrowedFormatItem] = time::macros::format_description!(
+    "[year]-[month]-[day]T[hour]:[minute]:[second].[subsecond digits:3][offset_hour sign:mandatory]:[offset_minute]"
+);
+
+/// how a reading's timestamp is written out. picked per sink with the `--*-timestamp` options, e.g.
+/// `--mqtt-timestamp epoch-ms`.
+///
+/// those options all take the same formats, which clap lists in each one's help from [`FORMATS`] rather than
+/// every option describing them itself
+#[derive(Debug, Clone)]
+pub enum TimestampFormat {
+    /// `2025-01-31 09:30:00`, the format the partitions and, by default, the output files use. whole seconds
+    /// and no zone
+    Plain,
+    /// `2025-01-31T09:30:00.123456789Z`, or with the offset of the timezone it is shown in
+    Rfc3339,
+    /// `2025-01-31T09:30:00.123Z`
+    IsoMillis,
+    EpochSeconds,
+    EpochMillis,
+    EpochNanos,
+    /// a format description in the `time` crate's syntax, e.g. `[day]/[month]/[year] [hour]:[minute]`
+    Custom {
+        description: String,
+        items: OwnedFormatItem,
+    },
+}
+
+/// a formatted timestamp. the epoch formats stay numbers, so they are written to json unquoted
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum Timestamp {
+    Text(String),
+    Number(i64),
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Timestamp::Text(text) => write!(f, "{}", text),
+            Timestamp::Number(number) => write!(f, "{}", number),
+        }
+    }
+}
+
+impl TimestampFormat {
+    /// reads a format from the command line: `plain`, `rfc3339`, `iso-millis`, `epoch-s`, `epoch-ms`, `epoch-ns`,
+    /// or anything with a `[component]` in it as a custom format
+    pub fn parse(format: &str) -> Result<TimestampFormat, String> {
+        let parsed = match format.to_ascii_lowercase().as_str() {
+            "plain" => TimestampFormat::Plain,
+            "rfc3339" => TimestampFormat::Rfc3339,
+            "iso-millis" => TimestampFormat::IsoMillis,
+            "epoch-s" | "epoch" => TimestampFormat::EpochSeconds,
+            "epoch-ms" => TimestampFormat::EpochMillis,
+            "epoch-ns" => TimestampFormat::EpochNanos,
+            _ if format.contains('[') => {
+                let items = time::format_description::parse_owned::<1>(format)
+                    .map_err(|e| format!("`{}` is not a valid format: {}", format, e))?;
+                TimestampFormat::Custom {
+                    description: format.to_string(),
+                    items,
+                }
+            }
+            _ => {
+                return Err(format!(
+                    "`{}` is not a timestamp format - use plain, rfc3339, iso-millis, epoch-s, epoch-ms, epoch-ns \
+                    or a custom format such as `[hour]:[minute]:[second]`",
+                    format
+                ));
+            }
+        };
+
+        // the components are only checked against a real date when formatting, so try one now rather than
+        // failing on every reading
+        if let Err(e) = parsed.format_text(OffsetDateTime::UNIX_EPOCH) {
+            return Err(format!("`{}` can't be used for timestamps: {}", format, e));
+        }
+
+        Ok(parsed)
+    }
+
+    /// formats a timestamp as it is in the given timezone. epoch timestamps are the same in every timezone
+    pub fn format(&self, timestamp: &UtcDateTime, timezone: &Timezone) -> Timestamp {
+        match self {
+            TimestampFormat::EpochSeconds => Timestamp::Number(timestamp.unix_timestamp()),
+            TimestampFormat::EpochMillis => {
+                Timestamp::Number((timestamp.unix_timestamp_nanos() / 1_000_000) as i64)
+            }
+            TimestampFormat::EpochNanos => {
+                Timestamp::Number(timestamp.unix_timestamp_nanos() as i64)
+            }
+            _ => Timestamp::Text(
+                self.format_text(timezone.local(timestamp))
+                    .unwrap_or_default(),
+            ),
+        }
+    }
+
+    fn format_text(&self, local: OffsetDateTime) -> Result<String, time::error::Format> {
+        match self {
+            TimestampFormat::Plain => local.format(PLAIN),
+            TimestampFormat::Rfc3339 => local.format(&Rfc3339),
+            TimestampFormat::IsoMillis if local.offset().is_utc() => {
+                let formatted = local.format(ISO_MILLIS)?;
+                Ok(format!("{}Z", formatted.trim_end_matches("+00:00")))
+            }
+            TimestampFormat::IsoMillis => local.format(ISO_MILLIS),
+            TimestampFormat::EpochSeconds => Ok(local.unix_timestamp().to_string()),
+            TimestampFormat::EpochMillis => {
+                Ok((local.unix_timestamp_nanos() / 1_000_000).to_string())
+            }
+            TimestampFormat::EpochNanos => Ok(local.unix_timestamp_nanos().to_string()),
+            TimestampFormat::Custom { items, .. } => local.format(items),
+        }
+    }
+}
+
+impl fmt::Display for TimestampFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimestampFormat::Plain => write!(f, "plain"),
+            TimestampFormat::Rfc3339 => write!(f, "rfc3339"),
+            TimestampFormat::IsoMillis => write!(f, "iso-millis"),
+            TimestampFormat::EpochSeconds => write!(f, "epoch-s"),
+            TimestampFormat::EpochMillis => write!(f, "epoch-ms"),
+            TimestampFormat::EpochNanos => write!(f, "epoch-ns"),
+            TimestampFormat::Custom { description, .. } => write!(f, "{}", description),
+        }
+    }
+}
+
+impl Serialize for TimestampFormat {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// every named format, with the example shown for it in the `--*-timestamp` help. the last one stands for any
+/// custom format
+const FORMATS: [(&str, &str); 7] = [
+    ("plain", "2025-01-31 09:30:00"),
+    ("rfc3339", "2025-01-31T09:30:00.123456789Z"),
+    ("iso-millis", "2025-01-31T09:30:00.123Z"),
+    ("epoch-s", "seconds since 1970, e.g. 1738315800"),
+    ("epoch-ms", "milliseconds since 1970"),
+    ("epoch-ns", "nanoseconds since 1970"),
+    (
+        "[hour]:[minute]:[second]",
+        "or any other format description in the time crate's syntax",
+    ),
+];
+
+/// lets clap parse a `--*-timestamp` option and list the formats it takes in its help
+#[derive(Debug, Clone, Copy)]
+pub struct TimestampFormatParser;
+
+impl TypedValueParser for TimestampFormatParser {
+    type Value = TimestampFormat;
+
+    fn parse_ref(
+        &self,
+        cmd: &clap::Command,
+        arg: Option<&clap::Arg>,
+        value: &std::ffi::OsStr,
+    ) -> Result<TimestampFormat, clap::Error> {
+        TimestampFormat::parse.parse_ref(cmd, arg, value)
+    }
+
+    fn possible_values(&self) -> Option<Box<dyn Iterator<Item = PossibleValue> + '_>> {
+        Some(Box::new(FORMATS.iter().map(|(name, example)| {
+            PossibleValue::new(*name).help(*example)
+        })))
+    }
+}
+
+impl ValueParserFactory for TimestampFormat {
+    type Parser = TimestampFormatParser;
+
+    fn value_parser() -> TimestampFormatParser {
+        TimestampFormatParser
+    }
+}
+
+/// the timezone timestamps are shown in on the console. everything else is written in utc
+#[derive(Debug, Clone, Copy)]
+pub enum Timezone {
+    Utc,
+    Fixed(UtcOffset),
+    /// an iana timezone, which follows its daylight saving changes
+    Named(&'static Tz),
+}
+
+impl Timezone {
+    /// reads `utc`, an iana name such as `Europe/London`, or a fixed offset such as `+05:30` or `-08`
+    pub fn parse(timezone: &str) -> Result<Timezone, String> {
+        if timezone.eq_ignore_ascii_case("utc") || timezone.eq_ignore_ascii_case("z") {
+            return Ok(Timezone::Utc);
+        }
+
+        if let Some(tz) = time_tz::timezones::get_by_name(timezone) {
+            return Ok(Timezone::Named(tz));
+        }
+
+        parse_offset(timezone).map(Timezone::Fixed).ok_or_else(|| {
+            format!(
+                "`{}` is not a timezone - use an iana name like Europe/London, or an offset like +05:30",
+                timezone
+            )
+        })
+    }
+
+    /// the offset from utc in force at the given moment
+    pub fn offset_at(&self, timestamp: &UtcDateTime) -> UtcOffset {
+        match self {
+            Timezone::Utc => UtcOffset::UTC,
+            Timezone::Fixed(offset) => *offset,
+            Timezone::Named(tz) => tz
+                .get_offset_utc(&timestamp.to_offset(UtcOffset::UTC))
+                .to_utc(),
+        }
+    }
+
+    /// the timestamp as the clock on the wall would show it in this timezone
+    pub fn local(&self, timestamp: &UtcDateTime) -> OffsetDateTime {
+        timestamp.to_offset(self.offset_at(timestamp))
+    }
+}
+
+impl fmt::Display for Timezone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Timezone::Utc => write!(f, "UTC"),
+            Timezone::Fixed(offset) => {
+                let (hours, minutes, _) = offset.as_hms();
+                let sign = if offset.is_negative() { '-' } else { '+' };
+                write!(f, "{}{:02}:{:02}", sign, hours.abs(), minutes.abs())
+            }
+            Timezone::Named(tz) => write!(f, "{}", tz.name()),
+        }
+    }
+}
+
+impl Serialize for Timezone {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// `+05:30`, `+0530` or `-08`
+fn parse_offset(offset: &str) -> Option<UtcOffset> {
+    let (sign, digits): (i8, &str) = match offset.split_at_checked(1)? {
+        ("+", digits) => (1, digits),
+        ("-", digits) => (-1, digits),
+        _ => return None,
+    };
+
+    let digits: String = digits.replace(':', "");
+    if !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let (hours, minutes): (i8, i8) = match digits.len() {
+        2 => (digits.parse().ok()?, 0),
+        4 => (digits[..2].parse().ok()?, digits[2..].parse().ok()?),
+        _ => return None,
+    };
+
+    UtcOffset::from_hms(sign * hours, sign * minutes, 0).ok()
+}
+
+/// writes a timestamp the way the partitions always have, `2025-01-31 09:30:00` in utc. these are read back
+/// when resuming, so the partitions don't get a choice of format
+pub fn plain(timestamp: &UtcDateTime) -> String {
+    timestamp.format(PLAIN).unwrap_or_default()
+}
+
+/// reads back a timestamp written by `plain`
+pub fn parse_plain(timestamp: &str) -> Result<UtcDateTime, time::error::Parse> {
+    Ok(time::PrimitiveDateTime::parse(timestamp, PLAIN)?.as_utc())
+}
+
+/// `serialize_with` for readings, so the csv partitions get the plain format
+pub fn serialize_plain<S: serde::Serializer>(
+    timestamp: &UtcDateTime,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&plain(timestamp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_offset_reads_each_form() {
+        assert_eq!(parse_offset("+05:30"), UtcOffset::from_hms(5, 30, 0).ok());
+        assert_eq!(parse_offset("+0530"), UtcOffset::from_hms(5, 30, 0).ok());
+        assert_eq!(parse_offset("-08"), UtcOffset::from_hms(-8, 0, 0).ok());
+        assert_eq!(parse_offset("-03:30"), UtcOffset::from_hms(-3, -30, 0).ok());
+        assert_eq!(parse_offset("+00:00"), Some(UtcOffset::UTC));
+    }
+
+    #[test]
+    fn parse_offset_rejects_anything_else() {
+        for offset in [
+            "",
+            "+",
+            "05:30",
+            "+5",
+            "+053",
+            "+05:3",
+            "+ab:cd",
+            "+05:60",
+            "+05:30:00",
+        ] {
+            assert_eq!(parse_offset(offset), None, "{} was accepted", offset);
+        }
+    }
+}
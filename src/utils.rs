@@ -1,24 +1,33 @@
 use crate::args::{HumidityUnit, PressureUnit, TemperatureUnit};
 use crate::sensor::Unit;
 use rand::{self, Rng};
-use rusqlite;
-use std::io::prelude::*;
-use time::UtcDateTime;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// switches off the progress notes printed with `note!`, for `--quiet`
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
 pub fn setup_db() -> Result<rusqlite::Connection> {
     let conn = rusqlite::Connection::open_in_memory()?;
 
     let _result = conn.execute(
-        "create or replace table
+        "create table if not exists
             readings
         (
             id varchar,
-            timestamp timestamp primary key,
+            timestamp timestamp,
             value float,
             unit varchar,
-            symbol varchar,
+            symbol varchar
         )",
         (),
     )?;
@@ -26,36 +35,20 @@ pub fn setup_db() -> Result<rusqlite::Connection> {
     Ok(conn)
 }
 
-pub fn serialize_unit(unit: &Unit) -> &str {
-    let unit_str = match &unit {
+pub fn serialize_unit(unit: &Unit) -> &'static str {
+    match &unit {
         Unit::TemperatureUnit(TemperatureUnit::Celsius) => "temperature_celsius",
         Unit::TemperatureUnit(TemperatureUnit::Kelvin) => "temperature_kelvin",
         Unit::PressureUnit(PressureUnit::Pascal) => "pressure_pascal",
         Unit::PressureUnit(PressureUnit::Bar) => "pressure_bar",
         Unit::HumidityUnit(HumidityUnit::Absolute) => "humidity_absolute",
         Unit::HumidityUnit(HumidityUnit::Relative) => "humidity_relative",
-    };
-
-    unit_str
+    }
 }
 
-pub fn serialize_timestamp(datetime: &UtcDateTime) -> Result<String> {
-    let mut w: Vec<u8> = Vec::new();
-
-    write!(
-        w,
-        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
-        datetime.year(),
-        datetime.month() as u8,
-        datetime.day(),
-        datetime.hour(),
-        datetime.minute(),
-        datetime.second(),
-    )?;
-
-    let s = String::from_utf8(w)?;
-
-    Ok(s)
+/// splits a unit into its category and the unit itself, e.g. `("temperature", "celsius")`
+pub fn unit_parts(unit: &Unit) -> (&'static str, &'static str) {
+    serialize_unit(unit).split_once('_').unwrap()
 }
 
 pub fn create_id() -> String {